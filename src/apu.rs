@@ -0,0 +1,743 @@
+use mmu::Bus;
+use irq::Irq;
+use scheduler::{Clock, Scheduled};
+use savestate::{State, Writer, Reader};
+use error::Result;
+
+/// Rate at which `Apu::drain` produces stereo sample pairs.
+pub const SAMPLE_RATE: u32 = 48000;
+
+/// M-cycles per second.
+const CLOCK: u32 = 1 << 20;
+
+/// The frame sequencer runs at 512 Hz.
+const SEQUENCER_PERIOD: u32 = CLOCK / 512;
+
+/// At most one second of samples is kept if nobody drains them.
+const MAX_BUFFERED: usize = SAMPLE_RATE as usize * 2;
+
+//12.5%, 25%, 50% and 75%, first step in the top bit
+const DUTY: [u8; 4] = [0b00000001, 0b10000001, 0b10000111, 0b01111110];
+
+const NOISE_DIVISORS: [i32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+#[derive(Default, Clone)]
+struct Length {
+    counter: u16,
+    enabled: bool,
+}
+
+impl Length {
+    /// Returns true when the counter just ran out.
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    fn reload(&mut self, max: u16) {
+        if self.counter == 0 {
+            self.counter = max;
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+struct Envelope {
+    initial: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn read(&self) -> u8 {
+        (self.initial << 4) | if self.increase { 0x08 } else { 0 } | self.period
+    }
+
+    fn write(&mut self, value: u8) {
+        self.initial = value >> 4;
+        self.increase = value & 0x08 != 0;
+        self.period = value & 0x07;
+    }
+
+    /// The DAC is powered as long as the upper five bits aren't all zero.
+    fn dac_enabled(&self) -> bool {
+        self.initial != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+/// Channels 1 and 2. Only channel 1 has a working frequency sweep.
+#[derive(Default, Clone)]
+struct Square {
+    enabled: bool,
+    duty: u8,
+    length: Length,
+    envelope: Envelope,
+    frequency: u16,
+    timer: i32,
+    position: u8,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_timer: u8,
+    sweep_enabled: bool,
+    shadow: u16,
+}
+
+impl Square {
+    fn read(&self, register: u16) -> u8 {
+        match register {
+            0 => 0x80 | (self.sweep_period << 4) | if self.sweep_negate { 0x08 } else { 0 } | self.sweep_shift,
+            1 => (self.duty << 6) | 0x3F,
+            2 => self.envelope.read(),
+            3 => 0xFF,
+            _ => 0xBF | if self.length.enabled { 0x40 } else { 0 },
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.sweep_period = (value >> 4) & 0x07;
+                self.sweep_negate = value & 0x08 != 0;
+                self.sweep_shift = value & 0x07;
+            },
+            1 => {
+                self.duty = value >> 6;
+                self.length.counter = 64 - (value & 0x3F) as u16;
+            },
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            },
+            3 => self.frequency = (self.frequency & 0x0700) | value as u16,
+            _ => {
+                self.frequency = (self.frequency & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.trigger();
+                }
+            },
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.reload(64);
+        self.timer = (2048 - self.frequency as i32) * 4;
+        self.envelope.trigger();
+
+        self.shadow = self.frequency;
+        self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+        self.sweep_enabled = self.sweep_period != 0 || self.sweep_shift != 0;
+        if self.sweep_shift != 0 {
+            self.sweep_frequency();
+        }
+    }
+
+    /// Next frequency of the sweep. Overflowing it silences the channel.
+    fn sweep_frequency(&mut self) -> u16 {
+        let delta = self.shadow >> self.sweep_shift;
+        let frequency = if self.sweep_negate {
+            self.shadow.wrapping_sub(delta)
+        } else {
+            self.shadow + delta
+        };
+        if frequency > 2047 {
+            self.enabled = false;
+        }
+        frequency
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep_timer > 0 {
+            self.sweep_timer -= 1;
+        }
+        if self.sweep_timer != 0 {
+            return;
+        }
+        self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+        if self.sweep_enabled && self.sweep_period != 0 {
+            let frequency = self.sweep_frequency();
+            if frequency <= 2047 && self.sweep_shift != 0 {
+                self.frequency = frequency;
+                self.shadow = frequency;
+                self.sweep_frequency();
+            }
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn run(&mut self, cycles: u32) {
+        self.timer -= 4 * cycles as i32;
+        while self.timer <= 0 {
+            self.timer += (2048 - self.frequency as i32) * 4;
+            self.position = (self.position + 1) & 0x07;
+        }
+    }
+
+    fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        let high = (DUTY[self.duty as usize] >> (7 - self.position)) & 0x01 != 0;
+        Some(if self.enabled && high { self.envelope.volume } else { 0 })
+    }
+}
+
+#[derive(Default, Clone)]
+struct Wave {
+    enabled: bool,
+    dac: bool,
+    length: Length,
+    volume: u8,
+    frequency: u16,
+    timer: i32,
+    position: u8,
+    ram: [u8; 16],
+}
+
+impl Wave {
+    fn read(&self, register: u16) -> u8 {
+        match register {
+            0 => 0x7F | if self.dac { 0x80 } else { 0 },
+            1 => 0xFF,
+            2 => 0x9F | (self.volume << 5),
+            3 => 0xFF,
+            _ => 0xBF | if self.length.enabled { 0x40 } else { 0 },
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.dac = value & 0x80 != 0;
+                if !self.dac {
+                    self.enabled = false;
+                }
+            },
+            1 => self.length.counter = 256 - value as u16,
+            2 => self.volume = (value >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x0700) | value as u16,
+            _ => {
+                self.frequency = (self.frequency & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.enabled = self.dac;
+                    self.length.reload(256);
+                    self.timer = (2048 - self.frequency as i32) * 2;
+                    self.position = 0;
+                }
+            },
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn run(&mut self, cycles: u32) {
+        self.timer -= 4 * cycles as i32;
+        while self.timer <= 0 {
+            self.timer += (2048 - self.frequency as i32) * 2;
+            self.position = (self.position + 1) & 0x1F;
+        }
+    }
+
+    fn output(&self) -> Option<u8> {
+        if !self.dac {
+            return None;
+        }
+        if !self.enabled || self.volume == 0 {
+            return Some(0);
+        }
+        let byte = self.ram[(self.position / 2) as usize];
+        let sample = if self.position & 0x01 == 0 { byte >> 4 } else { byte & 0x0F };
+        Some(sample >> (self.volume - 1))
+    }
+}
+
+#[derive(Default, Clone)]
+struct Noise {
+    enabled: bool,
+    length: Length,
+    envelope: Envelope,
+    shift: u8,
+    short: bool,
+    divisor: u8,
+    lfsr: u16,
+    timer: i32,
+}
+
+impl Noise {
+    fn read(&self, register: u16) -> u8 {
+        match register {
+            1 => 0xFF,
+            2 => self.envelope.read(),
+            3 => (self.shift << 4) | if self.short { 0x08 } else { 0 } | self.divisor,
+            _ => 0xBF | if self.length.enabled { 0x40 } else { 0 },
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            1 => self.length.counter = 64 - (value & 0x3F) as u16,
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            },
+            3 => {
+                self.shift = value >> 4;
+                self.short = value & 0x08 != 0;
+                self.divisor = value & 0x07;
+            },
+            _ => {
+                self.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.enabled = self.envelope.dac_enabled();
+                    self.length.reload(64);
+                    self.timer = self.period();
+                    self.envelope.trigger();
+                    self.lfsr = 0x7FFF;
+                }
+            },
+        }
+    }
+
+    fn period(&self) -> i32 {
+        NOISE_DIVISORS[self.divisor as usize] << self.shift
+    }
+
+    fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn run(&mut self, cycles: u32) {
+        self.timer -= 4 * cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            let bit = (self.lfsr ^ (self.lfsr >> 1)) & 0x01;
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            if self.short {
+                self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+            }
+        }
+    }
+
+    fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_enabled() {
+            return None;
+        }
+        Some(if self.enabled && self.lfsr & 0x01 == 0 { self.envelope.volume } else { 0 })
+    }
+}
+
+/// Runs lazily, up to the current time whenever its registers are written,
+/// its samples drained or its frame sequencer steps. Nothing a read sees
+/// changes in between.
+pub struct Apu {
+    clock: Clock,
+    last: u64,
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    power: bool,
+    volume: u8,
+    panning: u8,
+    sequencer_timer: u32,
+    sequencer_step: u8,
+    sample_timer: u32,
+    capacitor: [f32; 2],
+    samples: Vec<i16>,
+}
+
+impl Apu {
+    pub fn new(clock: Clock) -> Apu {
+        let mut apu = Apu {
+            last: clock.now(),
+            clock,
+            square1: Square::default(),
+            square2: Square::default(),
+            wave: Wave::default(),
+            noise: Noise::default(),
+            power: false,
+            volume: 0,
+            panning: 0,
+            sequencer_timer: 0,
+            sequencer_step: 0,
+            sample_timer: 0,
+            capacitor: [0.0; 2],
+            samples: vec!(),
+        };
+        // Values left behind by the boot ROM, without its sound still playing
+        for &(addr, value) in &[(0xFF26, 0x80), (0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3),
+                                (0xFF24, 0x77), (0xFF25, 0xF3)] {
+            apu.write(addr, value);
+        }
+        apu
+    }
+
+    /// Takes the samples produced so far, interleaved left and right.
+    pub fn drain(&mut self) -> Vec<i16> {
+        self.run();
+        std::mem::take(&mut self.samples)
+    }
+
+    /// Runs everything up to the current time.
+    pub fn run(&mut self) {
+        let now = self.clock.now();
+        while self.last < now {
+            // The channels only have to be looked at on samples and
+            // sequencer steps, so they move in one go up to the next one
+            let mut cycles = (now - self.last).min(self.until_sample() as u64) as u32;
+            if self.power {
+                cycles = cycles.min(SEQUENCER_PERIOD - self.sequencer_timer);
+            }
+            self.advance(cycles);
+            self.last += cycles as u64;
+        }
+    }
+
+    fn until_sample(&self) -> u32 {
+        (CLOCK - self.sample_timer).div_ceil(SAMPLE_RATE)
+    }
+
+    /// Runs `cycles` M-cycles, at most up to the next sample or sequencer
+    /// step.
+    fn advance(&mut self, cycles: u32) {
+        if self.power {
+            self.square1.run(cycles);
+            self.square2.run(cycles);
+            self.wave.run(cycles);
+            self.noise.run(cycles);
+
+            self.sequencer_timer += cycles;
+            if self.sequencer_timer >= SEQUENCER_PERIOD {
+                self.sequencer_timer = 0;
+                self.clock_sequencer();
+            }
+        }
+
+        self.sample_timer += SAMPLE_RATE * cycles;
+        if self.sample_timer >= CLOCK {
+            self.sample_timer -= CLOCK;
+            let (left, right) = self.mix();
+            if self.samples.len() < MAX_BUFFERED {
+                self.samples.push(left);
+                self.samples.push(right);
+            }
+        }
+    }
+
+    fn clock_sequencer(&mut self) {
+        if self.sequencer_step & 0x01 == 0 {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if self.sequencer_step == 2 || self.sequencer_step == 6 {
+            self.square1.clock_sweep();
+        }
+        if self.sequencer_step == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+        self.sequencer_step = (self.sequencer_step + 1) & 0x07;
+    }
+
+    fn mix(&mut self) -> (i16, i16) {
+        let outputs = [self.square1.output(), self.square2.output(), self.wave.output(), self.noise.output()];
+        let mut mixed = [0.0f32; 2];
+        for (channel, output) in outputs.iter().enumerate() {
+            // DACs map 0..15 onto 1.0..-1.0; a disabled one outputs nothing
+            let analog = match *output {
+                Some(digital) => 1.0 - digital as f32 / 7.5,
+                None => continue,
+            };
+            if self.panning & (0x10 << channel) != 0 {
+                mixed[0] += analog;
+            }
+            if self.panning & (0x01 << channel) != 0 {
+                mixed[1] += analog;
+            }
+        }
+        let volumes = [(self.volume >> 4) & 0x07, self.volume & 0x07];
+        // High-pass filter standing in for the output capacitor
+        let charge = 0.999958f32.powi((4 * CLOCK / SAMPLE_RATE) as i32);
+        let mut out = [0i16; 2];
+        for side in 0..2 {
+            let input = mixed[side] / 4.0 * (volumes[side] + 1) as f32 / 8.0;
+            let filtered = input - self.capacitor[side];
+            self.capacitor[side] = input - filtered * charge;
+            out[side] = (filtered * i16::MAX as f32) as i16;
+        }
+        (out[0], out[1])
+    }
+
+    fn status(&self) -> u8 {
+        let channels = [self.square1.enabled, self.square2.enabled, self.wave.enabled, self.noise.enabled];
+        channels.iter().enumerate()
+            .filter(|&(_, &enabled)| enabled)
+            .fold(0x70 | if self.power { 0x80 } else { 0 }, |bits, (i, _)| bits | (1 << i))
+    }
+
+    fn set_power(&mut self, power: bool) {
+        if self.power && !power {
+            // Powering off clears every register but keeps wave RAM
+            let ram = self.wave.ram;
+            self.square1 = Square::default();
+            self.square2 = Square::default();
+            self.wave = Wave { ram, ..Wave::default() };
+            self.noise = Noise::default();
+            self.volume = 0;
+            self.panning = 0;
+        } else if !self.power && power {
+            self.sequencer_step = 0;
+        }
+        self.power = power;
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Apu {
+        Apu::new(Clock::new())
+    }
+}
+
+impl Scheduled for Apu {
+    fn catch_up(&mut self, _irq: &mut Irq) {
+        self.run();
+    }
+
+    /// The next frame sequencer step, which can silence channels.
+    fn next_event(&self) -> Option<u64> {
+        if self.power {
+            Some(self.last + (SEQUENCER_PERIOD - self.sequencer_timer) as u64)
+        } else {
+            None
+        }
+    }
+
+    fn restart(&mut self) {
+        self.last = self.clock.now();
+    }
+}
+
+impl Bus for Apu {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF10 ..= 0xFF14 => self.square1.read(addr - 0xFF10),
+            0xFF16 ..= 0xFF19 => self.square2.read(addr - 0xFF15),
+            0xFF1A ..= 0xFF1E => self.wave.read(addr - 0xFF1A),
+            0xFF20 ..= 0xFF23 => self.noise.read(addr - 0xFF1F),
+            0xFF24 => self.volume,
+            0xFF25 => self.panning,
+            0xFF26 => self.status(),
+            0xFF30 ..= 0xFF3F => self.wave.ram[(addr & 0x0F) as usize],
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF26 => self.set_power(value & 0x80 != 0),
+            0xFF30 ..= 0xFF3F => self.wave.ram[(addr & 0x0F) as usize] = value,
+            _ if !self.power => (), // Read-only while powered off
+            0xFF10 ..= 0xFF14 => self.square1.write(addr - 0xFF10, value),
+            0xFF16 ..= 0xFF19 => self.square2.write(addr - 0xFF15, value),
+            0xFF1A ..= 0xFF1E => self.wave.write(addr - 0xFF1A, value),
+            0xFF20 ..= 0xFF23 => self.noise.write(addr - 0xFF1F, value),
+            0xFF24 => self.volume = value,
+            0xFF25 => self.panning = value,
+            _ => (),
+        }
+    }
+}
+
+impl State for Length {
+    fn save(&self, out: &mut Writer) {
+        out.u16(self.counter);
+        out.bool(self.enabled);
+    }
+
+    fn load(&mut self, input: &mut Reader) -> Result<()> {
+        self.counter = input.u16()?;
+        self.enabled = input.bool()?;
+        Ok(())
+    }
+}
+
+impl State for Envelope {
+    fn save(&self, out: &mut Writer) {
+        out.u8(self.read());
+        out.u8(self.volume);
+        out.u8(self.timer);
+    }
+
+    fn load(&mut self, input: &mut Reader) -> Result<()> {
+        let value = input.u8()?;
+        self.write(value);
+        self.volume = input.u8()? & 0x0F;
+        self.timer = input.u8()?;
+        Ok(())
+    }
+}
+
+impl State for Square {
+    fn save(&self, out: &mut Writer) {
+        out.bool(self.enabled);
+        out.u8(self.duty);
+        self.length.save(out);
+        self.envelope.save(out);
+        out.u16(self.frequency);
+        out.u32(self.timer as u32);
+        out.u8(self.position);
+        out.u8(self.sweep_period);
+        out.bool(self.sweep_negate);
+        out.u8(self.sweep_shift);
+        out.u8(self.sweep_timer);
+        out.bool(self.sweep_enabled);
+        out.u16(self.shadow);
+    }
+
+    fn load(&mut self, input: &mut Reader) -> Result<()> {
+        self.enabled = input.bool()?;
+        self.duty = input.u8()? & 0x03;
+        self.length.load(input)?;
+        self.envelope.load(input)?;
+        self.frequency = input.u16()? & 0x07FF;
+        self.timer = input.u32()? as i32;
+        self.position = input.u8()? & 0x07;
+        self.sweep_period = input.u8()? & 0x07;
+        self.sweep_negate = input.bool()?;
+        self.sweep_shift = input.u8()? & 0x07;
+        self.sweep_timer = input.u8()?;
+        self.sweep_enabled = input.bool()?;
+        self.shadow = input.u16()?;
+        Ok(())
+    }
+}
+
+impl State for Wave {
+    fn save(&self, out: &mut Writer) {
+        out.bool(self.enabled);
+        out.bool(self.dac);
+        self.length.save(out);
+        out.u8(self.volume);
+        out.u16(self.frequency);
+        out.u32(self.timer as u32);
+        out.u8(self.position);
+        out.bytes(&self.ram);
+    }
+
+    fn load(&mut self, input: &mut Reader) -> Result<()> {
+        self.enabled = input.bool()?;
+        self.dac = input.bool()?;
+        self.length.load(input)?;
+        self.volume = input.u8()? & 0x03;
+        self.frequency = input.u16()? & 0x07FF;
+        self.timer = input.u32()? as i32;
+        self.position = input.u8()? & 0x1F;
+        input.bytes(&mut self.ram)
+    }
+}
+
+impl State for Noise {
+    fn save(&self, out: &mut Writer) {
+        out.bool(self.enabled);
+        self.length.save(out);
+        self.envelope.save(out);
+        out.u8(self.shift);
+        out.bool(self.short);
+        out.u8(self.divisor);
+        out.u16(self.lfsr);
+        out.u32(self.timer as u32);
+    }
+
+    fn load(&mut self, input: &mut Reader) -> Result<()> {
+        self.enabled = input.bool()?;
+        self.length.load(input)?;
+        self.envelope.load(input)?;
+        self.shift = input.u8()? & 0x0F;
+        self.short = input.bool()?;
+        self.divisor = input.u8()? & 0x07;
+        self.lfsr = input.u16()? & 0x7FFF;
+        self.timer = input.u32()? as i32;
+        Ok(())
+    }
+}
+
+/// Samples waiting to be drained belong to the host and are left alone.
+impl State for Apu {
+    fn save(&self, out: &mut Writer) {
+        self.square1.save(out);
+        self.square2.save(out);
+        self.wave.save(out);
+        self.noise.save(out);
+        out.bool(self.power);
+        out.u8(self.volume);
+        out.u8(self.panning);
+        out.u32(self.sequencer_timer);
+        out.u8(self.sequencer_step);
+        out.u32(self.sample_timer);
+        out.u32(self.capacitor[0].to_bits());
+        out.u32(self.capacitor[1].to_bits());
+    }
+
+    fn load(&mut self, input: &mut Reader) -> Result<()> {
+        self.square1.load(input)?;
+        self.square2.load(input)?;
+        self.wave.load(input)?;
+        self.noise.load(input)?;
+        self.power = input.bool()?;
+        self.volume = input.u8()?;
+        self.panning = input.u8()?;
+        self.sequencer_timer = input.u32()? % SEQUENCER_PERIOD;
+        self.sequencer_step = input.u8()? & 0x07;
+        self.sample_timer = input.u32()? % CLOCK;
+        self.capacitor = [f32::from_bits(input.u32()?), f32::from_bits(input.u32()?)];
+        Ok(())
+    }
+}
@@ -1,59 +1,163 @@
 use mmu::Bus;
 use rom::Rom;
+use header::{Header, MemoryBankController};
+use error::{Error, Result};
+use savestate::{self, State, Writer, Reader};
+use cheats::GameGenie;
 
-//TODO Support banking
-
-#[derive(Debug, Copy, Clone)]
-pub enum MemoryBankController
-{
-    RomOnly,
-    Mbc1,
-}
-
-impl MemoryBankController {
-    fn from_u8(value: u8) -> MemoryBankController {
-        match value {
-            0 => MemoryBankController::RomOnly,
-            1 => MemoryBankController::Mbc1,
-            _ => panic!("Unknown Memory Bank Controller {}", value),
-        }
-    }
-}
+//TODO Support MBC2, MBC3 and MBC5
 
 pub struct Cartridge {
     rom: Rom,
-    mbc: MemoryBankController
+    ram: Vec<u8>,
+    header: Header,
+    checksum: u32,
+    ram_enabled: bool,
+    rom_bank: u8,
+    ram_bank: u8,
+    advanced_banking: bool,
+    patches: Vec<GameGenie>,
 }
 
 impl Cartridge {
-    pub fn new(filename : &String) -> Result<Cartridge,String> {
-        let rom = Rom::new(filename)?;
+    pub fn new(filename : &str) -> Result<Cartridge> {
+        Cartridge::from_rom(Rom::new(filename)?)
+    }
+
+    pub fn from_rom(rom: Rom) -> Result<Cartridge> {
+        let header = Header::parse(rom.as_slice())?;
+        match header.cartridge_type.mbc {
+            Some(MemoryBankController::RomOnly) | Some(MemoryBankController::Mbc1) => (),
+            _ => return Err(Error::UnsupportedCartridgeType(header.cartridge_type.code)),
+        }
         Ok(Cartridge {
-            mbc: MemoryBankController::from_u8(rom.as_slice()[0x147]),
+            checksum: savestate::crc32(rom.as_slice()),
             rom,
+            ram: vec!(0; header.ram_size),
+            header,
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            advanced_banking: false,
+            patches: vec!(),
         })
     }
 
+    /// Resets the banking registers. RAM is left alone.
+    pub fn reset(&mut self) {
+        self.ram_enabled = false;
+        self.rom_bank = 1;
+        self.ram_bank = 0;
+        self.advanced_banking = false;
+    }
+
+    /// Wipes cartridge RAM, as if its battery had been pulled.
+    pub fn clear_ram(&mut self) {
+        for byte in self.ram.iter_mut() {
+            *byte = 0;
+        }
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
     pub fn memory_bank_controller(&self) -> MemoryBankController {
-        self.mbc
+        self.header.cartridge_type.mbc.unwrap_or(MemoryBankController::RomOnly)
+    }
+
+    pub fn title(&self) -> &str {
+        &self.header.title
     }
 
-    pub fn title(&self) -> String {
-        use std::str;
-        let slice = &self.rom.as_slice()[0x134 .. 0x143];
-        str::from_utf8(slice).map_err(|_| { "".to_string() }).unwrap().trim_right_matches('\0').to_string()
+    pub fn rom(&self) -> &[u8] {
+        self.rom.as_slice()
+    }
+
+    /// Game Genie codes to apply to ROM reads from now on. The first one
+    /// that matches wins.
+    pub fn set_patches(&mut self, patches: Vec<GameGenie>) {
+        self.patches = patches;
+    }
+
+    /// CRC-32 of the whole ROM image.
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    /// ROM bank currently mapped at 0x0000-0x3FFF or 0x4000-0x7FFF.
+    pub fn rom_bank(&self, addr: u16) -> usize {
+        let bank = match (self.memory_bank_controller(), addr) {
+            (MemoryBankController::Mbc1, 0x0000 ..= 0x3FFF) if self.advanced_banking => (self.ram_bank as usize) << 5,
+            (MemoryBankController::Mbc1, 0x0000 ..= 0x3FFF) => 0,
+            (MemoryBankController::Mbc1, _) => ((self.ram_bank as usize) << 5) | (self.rom_bank as usize),
+            (_, 0x0000 ..= 0x3FFF) => 0,
+            (_, _) => 1,
+        };
+        let banks = self.rom.as_slice().len().div_ceil(0x4000).max(1);
+        bank % banks
+    }
+
+    fn ram_offset(&self, addr: u16) -> usize {
+        let bank = match self.memory_bank_controller() {
+            MemoryBankController::Mbc1 if self.advanced_banking => self.ram_bank as usize,
+            _ => 0,
+        };
+        ((bank << 13) | (addr as usize & 0x1FFF)) % self.ram.len()
+    }
+
+    fn ram_accessible(&self) -> bool {
+        !self.ram.is_empty() && match self.memory_bank_controller() {
+            MemoryBankController::RomOnly => true,
+            _ => self.ram_enabled,
+        }
     }
 }
 
 impl Bus for Cartridge {
     fn read(&self, addr: u16) -> u8 {
-        self.rom.read(addr)
+        match addr {
+            0x0000 ..= 0x7FFF => {
+                let offset = (self.rom_bank(addr) << 14) | (addr as usize & 0x3FFF);
+                let value = self.rom.as_slice().get(offset).cloned().unwrap_or(0xFF);
+                self.patches.iter()
+                    .find(|patch| patch.addr == addr && patch.compare.map_or(true, |compare| compare == value))
+                    .map_or(value, |patch| patch.value)
+            },
+            0xA000 ..= 0xBFFF if self.ram_accessible() => self.ram[self.ram_offset(addr)],
+            _ => 0xFF,
+        }
     }
 
-    fn write(&mut self, _addr: u16, _value: u8) {
-        match self.memory_bank_controller() {
-            MemoryBankController::RomOnly => (),
-            MemoryBankController::Mbc1 => panic!("Not yet implemented"),
+    fn write(&mut self, addr: u16, value: u8) {
+        match (self.memory_bank_controller(), addr) {
+            (MemoryBankController::Mbc1, 0x0000 ..= 0x1FFF) => self.ram_enabled = value & 0x0F == 0x0A,
+            (MemoryBankController::Mbc1, 0x2000 ..= 0x3FFF) => self.rom_bank = (value & 0x1F).max(1),
+            (MemoryBankController::Mbc1, 0x4000 ..= 0x5FFF) => self.ram_bank = value & 0x03,
+            (MemoryBankController::Mbc1, 0x6000 ..= 0x7FFF) => self.advanced_banking = value & 0x01 != 0,
+            (_, 0xA000 ..= 0xBFFF) if self.ram_accessible() => {
+                let offset = self.ram_offset(addr);
+                self.ram[offset] = value;
+            },
+            _ => (),
         }
     }
 }
+
+impl State for Cartridge {
+    fn save(&self, out: &mut Writer) {
+        out.bool(self.ram_enabled);
+        out.u8(self.rom_bank);
+        out.u8(self.ram_bank);
+        out.bool(self.advanced_banking);
+        out.bytes(&self.ram);
+    }
+
+    fn load(&mut self, input: &mut Reader) -> Result<()> {
+        self.ram_enabled = input.bool()?;
+        self.rom_bank = (input.u8()? & 0x1F).max(1);
+        self.ram_bank = input.u8()? & 0x03;
+        self.advanced_banking = input.bool()?;
+        input.bytes(&mut self.ram)
+    }
+}
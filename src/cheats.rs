@@ -0,0 +1,238 @@
+//! Game Genie and GameShark codes.
+//!
+//! Game Genie codes (`ABC-DEF` or `ABC-DEF-GHI`) patch what the cartridge
+//! returns for a ROM address, optionally only while the byte there matches
+//! a compare value, so they follow bank switches. GameShark codes
+//! (`BBVVAAAA`) store a value in RAM once every frame, at VBlank.
+//!
+//! Cheats files hold codes for any number of games, in sections named
+//! after a ROM's title or its CRC-32 as eight hex digits:
+//!
+//! ```text
+//! ; Comments start with a semicolon
+//! [TETRIS]
+//! 00A-17B-C49 Name of the cheat
+//! !01FF3CC0 Starts with ! so it's there, but disabled
+//! [0123abcd]
+//! ...
+//! ```
+
+use std::fmt;
+use std::fs;
+
+use cartridge::Cartridge;
+use error::{Error, Result};
+
+/// Replaces a ROM byte as the cartridge returns it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GameGenie {
+    pub addr: u16,
+    pub value: u8,
+    /// Only patch while the ROM holds this byte, to hit a single bank
+    pub compare: Option<u8>,
+}
+
+/// Stores a byte in RAM every frame.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GameShark {
+    /// RAM bank for the Game Boy Color. Writes go wherever `addr` is
+    /// mapped at the time.
+    pub bank: u8,
+    pub addr: u16,
+    pub value: u8,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Code {
+    GameGenie(GameGenie),
+    GameShark(GameShark),
+}
+
+impl Code {
+    /// Decodes a code, telling the kind by its shape: eight digits for a
+    /// GameShark, six or nine with optional dashes for a Game Genie.
+    pub fn parse(text: &str) -> Result<Code> {
+        let digits: Vec<u8> = text.chars()
+            .filter(|&c| c != '-')
+            .map(|c| c.to_digit(16).map(|digit| digit as u8))
+            .collect::<Option<_>>()
+            .ok_or_else(|| Error::BadCheat(format!("{} isn't hexadecimal", text)))?;
+        let code = match digits.len() {
+            8 if !text.contains('-') => Code::GameShark(GameShark {
+                bank: digits[0] << 4 | digits[1],
+                value: digits[2] << 4 | digits[3],
+                addr: u16::from_le_bytes([digits[4] << 4 | digits[5], digits[6] << 4 | digits[7]]),
+            }),
+            6 | 9 => Code::GameGenie(GameGenie {
+                value: digits[0] << 4 | digits[1],
+                addr: ((digits[5] ^ 0xF) as u16) << 12 | (digits[2] as u16) << 8 |
+                      (digits[3] as u16) << 4 | digits[4] as u16,
+                // The digit in between is a checksum nobody agrees on
+                compare: match digits.len() {
+                    9 => Some((digits[6] << 4 | digits[8]).rotate_right(2) ^ 0xBA),
+                    _ => None,
+                },
+            }),
+            _ => return Err(Error::BadCheat(format!("{} is neither a Game Genie nor a GameShark code", text))),
+        };
+        match code {
+            Code::GameGenie(ref patch) if patch.addr >= 0x8000 =>
+                Err(Error::BadCheat(format!("{} patches 0x{:04x}, outside of ROM", text, patch.addr))),
+            Code::GameShark(ref write) if write.addr < 0x8000 =>
+                Err(Error::BadCheat(format!("{} writes 0x{:04x}, outside of RAM", text, write.addr))),
+            _ => Ok(code),
+        }
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Code::GameGenie(ref patch) => {
+                write!(f, "Game Genie: 0x{:02x} at 0x{:04x}", patch.value, patch.addr)?;
+                match patch.compare {
+                    Some(compare) => write!(f, " over 0x{:02x}", compare),
+                    None => Ok(()),
+                }
+            },
+            Code::GameShark(ref write) =>
+                write!(f, "GameShark: 0x{:02x} to 0x{:04x} every frame", write.value, write.addr),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cheat {
+    /// The code as it was entered
+    pub text: String,
+    pub code: Code,
+    pub name: String,
+    pub enabled: bool,
+}
+
+impl Cheat {
+    /// An enabled cheat, without a name.
+    pub fn new(text: &str) -> Result<Cheat> {
+        Ok(Cheat {
+            text: text.to_uppercase(),
+            code: Code::parse(text)?,
+            name: String::new(),
+            enabled: true,
+        })
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+}
+
+impl Cheats {
+    pub fn new() -> Cheats {
+        Cheats::default()
+    }
+
+    /// Reads the codes for `cart` from a cheats file.
+    pub fn open(filename: &str, cart: &Cartridge) -> Result<Cheats> {
+        Cheats::parse(&fs::read_to_string(filename)?, cart)
+    }
+
+    /// Parses the codes for `cart` out of a cheats file, see above. Other
+    /// games' sections are skipped without a look.
+    pub fn parse(text: &str, cart: &Cartridge) -> Result<Cheats> {
+        let checksum = format!("{:08x}", cart.checksum());
+        let mut cheats = Cheats::new();
+        let mut wanted = false;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                let key = line[1..line.len() - 1].trim();
+                wanted = key == cart.title() || key.eq_ignore_ascii_case(&checksum);
+                continue;
+            }
+            if !wanted {
+                continue;
+            }
+            let (enabled, line) = match line.strip_prefix('!') {
+                Some(rest) => (false, rest.trim_start()),
+                None => (true, line),
+            };
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let text = parts.next().unwrap_or("");
+            let mut cheat = Cheat::new(text).map_err(|e| match e {
+                Error::BadCheat(reason) => Error::BadCheat(format!("line {}: {}", number + 1, reason)),
+                e => e,
+            })?;
+            cheat.name = parts.next().unwrap_or("").trim().to_string();
+            cheat.enabled = enabled;
+            cheats.add(cheat);
+        }
+        Ok(cheats)
+    }
+
+    /// Returns the index of the new cheat.
+    pub fn add(&mut self, cheat: Cheat) -> usize {
+        self.cheats.push(cheat);
+        self.cheats.len() - 1
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Cheat> {
+        if index < self.cheats.len() {
+            Some(self.cheats.remove(index))
+        } else {
+            None
+        }
+    }
+
+    /// Returns whether there is such a cheat.
+    pub fn set_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        match self.cheats.get_mut(index) {
+            Some(cheat) => {
+                cheat.enabled = enabled;
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Cheat> {
+        self.cheats.get(index)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Cheat> {
+        self.cheats.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cheats.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cheats.is_empty()
+    }
+
+    /// Enabled Game Genie codes, for the cartridge.
+    pub(crate) fn patches(&self) -> Vec<GameGenie> {
+        self.cheats.iter()
+            .filter(|cheat| cheat.enabled)
+            .filter_map(|cheat| match cheat.code {
+                Code::GameGenie(patch) => Some(patch),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Enabled GameShark codes, to apply every frame.
+    pub(crate) fn writes(&self) -> Vec<GameShark> {
+        self.cheats.iter()
+            .filter(|cheat| cheat.enabled)
+            .filter_map(|cheat| match cheat.code {
+                Code::GameShark(write) => Some(write),
+                _ => None,
+            })
+            .collect()
+    }
+}
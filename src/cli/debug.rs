@@ -0,0 +1,60 @@
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+use gbm_rust::{Cartridge, GameBoy, Result};
+use gbm_rust::debugger::{Debugger, Output};
+use super::usage_error;
+
+pub fn main(args: &[String]) -> i32 {
+    let mut state = None;
+    let mut filename = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--load-state" => match args.next() {
+                Some(file) => state = Some(file.clone()),
+                None => return usage_error("--load-state needs a file name"),
+            },
+            _ if arg.starts_with('-') => return usage_error(&format!("Unknown option {}", arg)),
+            _ if filename.is_none() => filename = Some(arg.clone()),
+            _ => return usage_error("debug takes exactly one ROM"),
+        }
+    }
+    let filename = match filename {
+        Some(filename) => filename,
+        None => return usage_error("debug needs a ROM"),
+    };
+    match run(&filename, state.as_deref()) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        },
+    }
+}
+
+fn run(filename: &str, state: Option<&str>) -> Result<()> {
+    let mut gameboy = GameBoy::new(Cartridge::new(filename)?);
+    if let Some(state) = state {
+        gameboy.load_state(&fs::read(state)?)?;
+    }
+    let mut debugger = Debugger::new();
+    if let Output::Text(text) = debugger.execute(&mut gameboy, "list") {
+        println!("{}", text);
+    }
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("(gbm) ");
+        io::stdout().flush()?;
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => return Ok(()),
+        };
+        match debugger.execute(&mut gameboy, &line) {
+            Output::Text(ref text) if text.is_empty() => (),
+            Output::Text(text) => println!("{}", text),
+            Output::Quit => return Ok(()),
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use std::fs;
+
+use gbm_rust::Result;
+use gbm_rust::disasm::{Disassembler, Symbols};
+use super::usage_error;
+
+pub fn main(args: &[String]) -> i32 {
+    let mut symbols = None;
+    let mut filename = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sym" => match args.next() {
+                Some(file) => symbols = Some(file.clone()),
+                None => return usage_error("--sym needs a file name"),
+            },
+            _ if arg.starts_with('-') => return usage_error(&format!("Unknown option {}", arg)),
+            _ if filename.is_none() => filename = Some(arg.clone()),
+            _ => return usage_error("disasm takes exactly one ROM"),
+        }
+    }
+    let filename = match filename {
+        Some(filename) => filename,
+        None => return usage_error("disasm needs a ROM"),
+    };
+    match run(&filename, symbols.as_deref()) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        },
+    }
+}
+
+fn run(filename: &str, symbols: Option<&str>) -> Result<()> {
+    let rom = fs::read(filename)?;
+    let symbols = match symbols {
+        Some(symbols) => Symbols::open(symbols)?,
+        None => Symbols::new(),
+    };
+    print!("{}", Disassembler::new(&rom, &symbols).source());
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use std::fs;
+
+use gbm_rust::{Cartridge, GameBoy, Result};
+use gbm_rust::gdb::Stub;
+use super::{usage_error, parse_number};
+
+pub fn main(args: &[String]) -> i32 {
+    let mut port = 1234;
+    let mut state = None;
+    let mut filename = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => match args.next().and_then(|v| parse_number(v)) {
+                Some(value) if value <= 0xFFFF => port = value as u16,
+                _ => return usage_error("--port needs a port number"),
+            },
+            "--load-state" => match args.next() {
+                Some(file) => state = Some(file.clone()),
+                None => return usage_error("--load-state needs a file name"),
+            },
+            _ if arg.starts_with('-') => return usage_error(&format!("Unknown option {}", arg)),
+            _ if filename.is_none() => filename = Some(arg.clone()),
+            _ => return usage_error("gdb takes exactly one ROM"),
+        }
+    }
+    let filename = match filename {
+        Some(filename) => filename,
+        None => return usage_error("gdb needs a ROM"),
+    };
+    match run(&filename, port, state.as_deref()) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        },
+    }
+}
+
+fn run(filename: &str, port: u16, state: Option<&str>) -> Result<()> {
+    let mut gameboy = GameBoy::new(Cartridge::new(filename)?);
+    if let Some(state) = state {
+        gameboy.load_state(&fs::read(state)?)?;
+    }
+    let addr = format!("127.0.0.1:{}", port);
+    eprintln!("Waiting for a debugger on {}", addr);
+    Stub::new().listen(&mut gameboy, &addr)
+}
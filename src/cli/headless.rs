@@ -0,0 +1,244 @@
+use std::fs;
+
+use gbm_rust::{Cartridge, GameBoy, Result};
+use gbm_rust::screenshot;
+use gbm_rust::cpu::trace::{self, Trace, Filter};
+use gbm_rust::cheats::{Cheat, Cheats};
+use gbm_rust::disasm::Symbols;
+use gbm_rust::profile::Profile;
+use gbm_rust::headless::{self, Limits, Exit, Protocol};
+use super::{usage_error, parse_number, parse_address};
+
+pub fn main(args: &[String]) -> i32 {
+    let mut limits = Limits::default();
+    let mut output = Output::default();
+    let mut states = States::default();
+    let mut tracing = Tracing::default();
+    let mut profiling = Profiling::default();
+    let mut cheating = Cheating::default();
+    let mut filename = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => match args.next().and_then(|v| parse_number(v)) {
+                Some(frames) => limits.frames = Some(frames),
+                None => return usage_error("--frames needs a number"),
+            },
+            "--cycles" => match args.next().and_then(|v| parse_number(v)) {
+                Some(cycles) => limits.cycles = Some(cycles),
+                None => return usage_error("--cycles needs a number"),
+            },
+            "--break" => match args.next().and_then(|v| parse_address(v)) {
+                Some(addr) => limits.breakpoints.push(addr),
+                None => return usage_error("--break needs an address"),
+            },
+            "--ld-b-b" => limits.ld_b_b = true,
+            "--check" => match args.next().map(|v| v.as_str()) {
+                Some("blargg") => limits.check = Some(Protocol::Blargg),
+                Some("mooneye") => limits.check = Some(Protocol::Mooneye),
+                _ => return usage_error("--check needs blargg or mooneye"),
+            },
+            "--serial" => output.serial = true,
+            "--dump" => match args.next() {
+                Some(file) => output.dump = Some(file.clone()),
+                None => return usage_error("--dump needs a file name"),
+            },
+            "--load-state" => match args.next() {
+                Some(file) => states.load = Some(file.clone()),
+                None => return usage_error("--load-state needs a file name"),
+            },
+            "--save-state" => match args.next() {
+                Some(file) => states.save = Some(file.clone()),
+                None => return usage_error("--save-state needs a file name"),
+            },
+            "--trace" => match args.next() {
+                Some(file) => tracing.file = Some(file.clone()),
+                None => return usage_error("--trace needs a file name"),
+            },
+            "--trace-pc" => match args.next().and_then(|v| parse_range(v)) {
+                Some(range) => tracing.filter.pc = Some(range),
+                None => return usage_error("--trace-pc needs a range like 0150-01ff"),
+            },
+            "--trace-bank" => match args.next().and_then(|v| parse_number(v)) {
+                Some(bank) => tracing.filter.bank = Some(bank as usize),
+                None => return usage_error("--trace-bank needs a number"),
+            },
+            "--trace-doctor" => tracing.doctor = true,
+            "--profile" => match args.next() {
+                Some(file) => profiling.flat = Some(file.clone()),
+                None => return usage_error("--profile needs a file name"),
+            },
+            "--profile-report" => match args.next() {
+                Some(file) => profiling.report = Some(file.clone()),
+                None => return usage_error("--profile-report needs a file name"),
+            },
+            "--coverage" => match args.next() {
+                Some(file) => profiling.coverage = Some(file.clone()),
+                None => return usage_error("--coverage needs a file name"),
+            },
+            "--sym" => match args.next() {
+                Some(file) => profiling.symbols = Some(file.clone()),
+                None => return usage_error("--sym needs a file name"),
+            },
+            "--cheat" => match args.next().map(|v| Cheat::new(v)) {
+                Some(Ok(cheat)) => cheating.codes.push(cheat),
+                Some(Err(e)) => return usage_error(&e.to_string()),
+                None => return usage_error("--cheat needs a code"),
+            },
+            "--cheats" => match args.next() {
+                Some(file) => cheating.file = Some(file.clone()),
+                None => return usage_error("--cheats needs a file name"),
+            },
+            _ if arg.starts_with('-') => return usage_error(&format!("Unknown option {}", arg)),
+            _ if filename.is_none() => filename = Some(arg.clone()),
+            _ => return usage_error("headless takes exactly one ROM"),
+        }
+    }
+    let filename = match filename {
+        Some(filename) => filename,
+        None => return usage_error("headless needs a ROM"),
+    };
+
+    match run(&filename, &limits, &states, &cheating, &tracing, &profiling, &output) {
+        Ok(Exit::Lockup { .. }) => 3,
+        Ok(Exit::Passed) => 0,
+        // Stopping for any other reason means the test didn't pass
+        Ok(_) if limits.check.is_some() => 4,
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        },
+    }
+}
+
+/// Save states to start from and to write once the run stops.
+#[derive(Default)]
+struct States {
+    load: Option<String>,
+    save: Option<String>,
+}
+
+/// Cheats to play with, from a file and the command line.
+#[derive(Default)]
+struct Cheating {
+    file: Option<String>,
+    codes: Vec<Cheat>,
+}
+
+impl Cheating {
+    fn cheats(&self, cart: &Cartridge) -> Result<Cheats> {
+        let mut cheats = match self.file {
+            Some(ref file) => Cheats::open(file, cart)?,
+            None => Cheats::new(),
+        };
+        for cheat in &self.codes {
+            cheats.add(cheat.clone());
+        }
+        Ok(cheats)
+    }
+}
+
+/// Where to write an execution trace, and of what.
+#[derive(Default)]
+struct Tracing {
+    file: Option<String>,
+    filter: Filter,
+    /// Read LY the way Gameboy Doctor's reference traces do
+    doctor: bool,
+}
+
+/// Where to write profiling results, if anywhere.
+#[derive(Default)]
+struct Profiling {
+    flat: Option<String>,
+    report: Option<String>,
+    coverage: Option<String>,
+    symbols: Option<String>,
+}
+
+impl Profiling {
+    fn enabled(&self) -> bool {
+        self.flat.is_some() || self.report.is_some() || self.coverage.is_some()
+    }
+
+    fn write(&self, profile: &Profile, rom: &[u8]) -> Result<()> {
+        let symbols = match self.symbols {
+            Some(ref file) => Symbols::open(file)?,
+            None => Symbols::new(),
+        };
+        if let Some(ref file) = self.flat {
+            fs::write(file, profile.flat(&symbols))?;
+        }
+        if let Some(ref file) = self.report {
+            fs::write(file, profile.report(&symbols))?;
+        }
+        if let Some(ref file) = self.coverage {
+            fs::write(file, profile.coverage(rom))?;
+        }
+        Ok(())
+    }
+}
+
+/// What to show once the run stops, besides registers and the reason.
+#[derive(Default)]
+struct Output {
+    dump: Option<String>,
+    serial: bool,
+}
+
+fn parse_range(value: &str) -> Option<(u16, u16)> {
+    let mut parts = value.splitn(2, '-');
+    let start = parse_address(parts.next()?)?;
+    let end = parse_address(parts.next()?)?;
+    if start <= end {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+fn run(filename: &str, limits: &Limits, states: &States, cheating: &Cheating, tracing: &Tracing,
+       profiling: &Profiling, output: &Output) -> Result<Exit> {
+    let cart = Cartridge::new(filename)?;
+    let cheats = cheating.cheats(&cart)?;
+    let mut gameboy = GameBoy::new(cart);
+    gameboy.set_cheats(cheats);
+    if let Some(ref load) = states.load {
+        gameboy.load_state(&fs::read(load)?)?;
+    }
+    if let Some(ref file) = tracing.file {
+        gameboy.cpu_mut().set_trace(Trace::create(file, tracing.filter.clone())?);
+    }
+    if tracing.doctor {
+        trace::stub_ly(gameboy.mmu_mut());
+    }
+    if profiling.enabled() {
+        gameboy.set_profile(Profile::new());
+    }
+    let exit = headless::run(&mut gameboy, limits)?;
+    if let Some(profile) = gameboy.take_profile() {
+        profiling.write(&profile, gameboy.mmu().cartridge().rom())?;
+    }
+    if let Some(trace) = gameboy.cpu_mut().take_trace() {
+        trace.finish()?;
+    }
+    if let Some(ref save) = states.save {
+        fs::write(save, gameboy.save_state())?;
+    }
+    println!("{}", gameboy.cpu().registers());
+    if output.serial || limits.check == Some(Protocol::Blargg) {
+        let serial = gameboy.mmu().serial().output();
+        if !serial.is_empty() {
+            println!("{}", String::from_utf8_lossy(serial));
+        }
+    }
+    if let Some(text) = headless::blargg_text(gameboy.mmu()) {
+        println!("{}", text);
+    }
+    println!("{}", exit);
+    if let Some(ref dump) = output.dump {
+        screenshot::write(dump, gameboy.framebuffer())?;
+    }
+    Ok(exit)
+}
@@ -0,0 +1,159 @@
+use gbm_rust::rom::Rom;
+use gbm_rust::header::{Header, CgbSupport, Destination, Checksum};
+use gbm_rust::error::Result;
+use super::usage_error;
+
+pub fn main(args: &[String]) -> i32 {
+    let mut json = false;
+    let mut filenames = vec!();
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json = true,
+            _ if arg.starts_with('-') => return usage_error(&format!("Unknown option {}", arg)),
+            _ => filenames.push(arg.as_str()),
+        }
+    }
+    if filenames.is_empty() {
+        return usage_error("info needs at least one ROM");
+    }
+
+    let mut status = 0;
+    let mut entries = vec!();
+    for filename in filenames {
+        let info = load(filename);
+        if let Err(ref e) = info {
+            if !json {
+                eprintln!("{}: {}", filename, e);
+            }
+            status = 1;
+        }
+        if json {
+            entries.push(to_json(filename, &info));
+        } else if let Ok((ref header, file_size)) = info {
+            print_human(filename, header, file_size);
+        }
+    }
+
+    if json {
+        println!("[\n{}\n]", entries.join(",\n"));
+    }
+    status
+}
+
+fn load(filename: &str) -> Result<(Header, usize)> {
+    let rom = Rom::new(filename)?;
+    let header = Header::parse(rom.as_slice())?;
+    Ok((header, rom.as_slice().len()))
+}
+
+fn cgb_name(cgb: CgbSupport) -> &'static str {
+    match cgb {
+        CgbSupport::None => "none",
+        CgbSupport::Enhanced => "enhanced",
+        CgbSupport::Only => "only",
+    }
+}
+
+fn destination_name(destination: Destination) -> String {
+    match destination {
+        Destination::Japan => "japan".to_string(),
+        Destination::Overseas => "overseas".to_string(),
+        Destination::Unknown(code) => format!("unknown (0x{:02x})", code),
+    }
+}
+
+fn features(header: &Header) -> Vec<&'static str> {
+    let kind = &header.cartridge_type;
+    let mut features = vec!();
+    if kind.ram { features.push("ram"); }
+    if kind.battery { features.push("battery"); }
+    if kind.timer { features.push("timer"); }
+    if kind.rumble { features.push("rumble"); }
+    if kind.sensor { features.push("sensor"); }
+    features
+}
+
+fn checksum_status<T: PartialEq>(checksum: &Checksum<T>) -> &'static str {
+    if checksum.is_valid() { "ok" } else { "BAD" }
+}
+
+fn print_human(filename: &str, header: &Header, file_size: usize) {
+    let features = features(header);
+    println!("{}", filename);
+    println!("  Title:             {}", header.title);
+    println!("  Manufacturer code: {}", header.manufacturer_code.as_deref().unwrap_or("-"));
+    println!("  CGB support:       {}", cgb_name(header.cgb));
+    println!("  SGB support:       {}", if header.sgb { "yes" } else { "no" });
+    println!("  Old licensee code: 0x{:02x}", header.old_licensee_code);
+    println!("  New licensee code: {}", header.new_licensee_code.as_deref().unwrap_or("-"));
+    println!("  Cartridge type:    0x{:02x} {}", header.cartridge_type.code, header.cartridge_type);
+    println!("  Features:          {}", if features.is_empty() { "-".to_string() } else { features.join(", ") });
+    println!("  ROM size:          {} KiB ({} banks, file has {})", header.rom_size / 1024, header.rom_banks(), file_size.div_ceil(0x4000));
+    println!("  RAM size:          {} KiB ({} banks)", header.ram_size / 1024, header.ram_banks());
+    println!("  Destination:       {}", destination_name(header.destination));
+    println!("  Version:           {}", header.version);
+    println!("  Header checksum:   0x{:02x} (computed 0x{:02x}, {})",
+             header.header_checksum.expected, header.header_checksum.computed, checksum_status(&header.header_checksum));
+    println!("  Global checksum:   0x{:04x} (computed 0x{:04x}, {})",
+             header.global_checksum.expected, header.global_checksum.computed, checksum_status(&header.global_checksum));
+}
+
+fn to_json(filename: &str, info: &Result<(Header, usize)>) -> String {
+    let (header, file_size) = match *info {
+        Ok((ref header, file_size)) => (header, file_size),
+        Err(ref e) => return format!("  {{ \"file\": {}, \"error\": {} }}", json_string(filename), json_string(&e.to_string())),
+    };
+    let kind = &header.cartridge_type;
+    let fields = vec!(
+        ("file", json_string(filename)),
+        ("title", json_string(&header.title)),
+        ("manufacturer_code", json_option(&header.manufacturer_code)),
+        ("cgb", json_string(cgb_name(header.cgb))),
+        ("sgb", header.sgb.to_string()),
+        ("old_licensee_code", header.old_licensee_code.to_string()),
+        ("new_licensee_code", json_option(&header.new_licensee_code)),
+        ("cartridge_type", format!("{{ \"code\": {}, \"mbc\": {}, \"features\": [{}] }}",
+                                   kind.code,
+                                   kind.mbc.map(|mbc| json_string(&format!("{:?}", mbc))).unwrap_or_else(|| "null".to_string()),
+                                   features(header).iter().map(|f| json_string(f)).collect::<Vec<_>>().join(", "))),
+        ("rom_size", header.rom_size.to_string()),
+        ("rom_banks", header.rom_banks().to_string()),
+        ("file_size", file_size.to_string()),
+        ("ram_size", header.ram_size.to_string()),
+        ("ram_banks", header.ram_banks().to_string()),
+        ("destination", json_string(&destination_name(header.destination))),
+        ("version", header.version.to_string()),
+        ("header_checksum", format!("{{ \"expected\": {}, \"computed\": {}, \"valid\": {} }}",
+                                    header.header_checksum.expected, header.header_checksum.computed, header.header_checksum.is_valid())),
+        ("global_checksum", format!("{{ \"expected\": {}, \"computed\": {}, \"valid\": {} }}",
+                                    header.global_checksum.expected, header.global_checksum.computed, header.global_checksum.is_valid())),
+    );
+    let fields: Vec<String> = fields.into_iter()
+        .map(|(key, value)| format!("    \"{}\": {}", key, value))
+        .collect();
+    format!("  {{\n{}\n  }}", fields.join(",\n"))
+}
+
+fn json_option(value: &Option<String>) -> String {
+    match *value {
+        Some(ref s) => json_string(s),
+        None => "null".to_string(),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
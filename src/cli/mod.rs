@@ -0,0 +1,113 @@
+mod run;
+mod info;
+mod headless;
+mod movie;
+mod debug;
+mod disasm;
+mod gdb;
+
+use gbm_rust::debugger::parse_address;
+
+const USAGE: &str = "\
+Usage: gbm-rust <command> [options]
+
+Commands:
+    run [options] <rom>         Run a ROM at the speed of the real thing
+        --speed <x>             Run x times as fast, e.g. 4 to fast-forward or
+                                0.5 for slow motion, or max for no limit
+        --frame-skip <n>        Leave up to n frames in a row undrawn when
+                                running ahead of the display or falling behind,
+                                8 by default
+        --frames <n>            Stop after n frames
+        --benchmark             Run as fast as possible, drawing every frame,
+                                and print emulated frames per second
+    info [--json] <rom>...      Print the cartridge header of one or more ROMs
+    headless [options] <rom>    Run a ROM without a display until a limit is reached
+        --frames <n>            Stop after n frames
+        --cycles <n>            Stop after n M-cycles
+        --break <addr>          Stop when PC reaches addr (may be repeated)
+        --ld-b-b                Stop when about to execute LD B,B
+        --dump <file>           Write the final framebuffer as a PNG image, or
+                                PGM unless the file name ends in .png
+        --serial                Print everything sent over the serial port
+        --check <protocol>      Stop when a test ROM reports its result,
+                                protocol is blargg or mooneye
+        --load-state <file>     Start from a save state
+        --save-state <file>     Write a save state once stopped
+        --cheat <code>          Apply a Game Genie or GameShark code (may be
+                                repeated)
+        --cheats <file>         Apply the codes for this ROM from a cheats file
+        --trace <file>          Log every instruction in the Gameboy Doctor format
+        --trace-pc <start-end>  Only log instructions within an address range
+        --trace-bank <n>        Only log instructions running from ROM bank n
+        --trace-doctor          Read LY as 0x90 like Gameboy Doctor does, so
+                                traces compare with its reference logs; the
+                                ROM runs differently for it
+        --profile <file>        Write where the cycles went, added up by symbol
+        --profile-report <file> Write executions and cycles for every address
+        --coverage <file>       Write a map with a byte for every ROM byte: bit 0
+                                set if an instruction started there, bit 1 if
+                                it was part of an executed instruction
+        --sym <file>            Name addresses in profiles after an RGBDS symbol file
+      Exits with 0 when stopped by a limit or a test passed, 1 on an
+      emulation error, 3 when the CPU locked up and 4 when a test failed
+      or didn't report a result.
+    movie record [options] <rom> <movie>
+                                Record a movie from power-on
+        --frames <n>            Number of frames to record, 600 by default
+        --load-state <file>     Start from a save state instead
+        --input <file>          Hold buttons as an input script says: lines
+                                of a frame number and the buttons held from
+                                then on, e.g. 120 start, with none to let
+                                go; without it nothing is pressed
+    movie play <rom> <movie>    Replay a movie, checking every frame
+      Exits with 4 when the replay doesn't match the recording.
+    debug [options] <rom>       Debug a ROM interactively, type help for commands
+        --load-state <file>     Start from a save state
+    disasm [options] <rom>      Print the ROM as source for rgbasm, telling code
+                                from data by following it from the entry points
+        --sym <file>            Name addresses after an RGBDS symbol file
+    gdb [options] <rom>         Wait for GDB to attach over TCP on localhost
+        --port <n>              Port to listen on, 1234 by default
+        --load-state <file>     Start from a save state
+    help                        Print this message
+
+Running gbm-rust <rom> is the same as gbm-rust run <rom>, options included.";
+
+/// Runs the command line front-end and returns the process exit code.
+pub fn main(args: Vec<String>) -> i32 {
+    let mut args = args.into_iter();
+    let command = match args.next() {
+        Some(command) => command,
+        None => {
+            eprintln!("{}", USAGE);
+            return 2;
+        },
+    };
+    let args: Vec<String> = args.collect();
+    match command.as_str() {
+        "run" => run::main(&args),
+        "info" => info::main(&args),
+        "headless" => headless::main(&args),
+        "movie" => movie::main(&args),
+        "debug" => debug::main(&args),
+        "disasm" => disasm::main(&args),
+        "gdb" => gdb::main(&args),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            0
+        },
+        _ if command.starts_with('-') => usage_error(&format!("Unknown option {}", command)),
+        _ => run::main(&[vec!(command), args].concat()),
+    }
+}
+
+fn usage_error(message: &str) -> i32 {
+    eprintln!("{}\n\n{}", message, USAGE);
+    2
+}
+
+fn parse_number(value: &str) -> Option<u64> {
+    value.parse().ok()
+}
+
@@ -0,0 +1,101 @@
+use std::fs;
+
+use gbm_rust::{Cartridge, GameBoy, Result};
+use gbm_rust::movie::{Movie, Recorder, Script};
+use super::{usage_error, parse_number};
+
+/// Length of a recording when no --frames is given: ten seconds.
+const DEFAULT_FRAMES: u64 = 600;
+
+pub fn main(args: &[String]) -> i32 {
+    match args.split_first() {
+        Some((command, args)) if command == "record" => record_main(args),
+        Some((command, args)) if command == "play" => play_main(args),
+        _ => usage_error("movie needs record or play"),
+    }
+}
+
+fn record_main(args: &[String]) -> i32 {
+    let mut frames = DEFAULT_FRAMES;
+    let mut state = None;
+    let mut input = None;
+    let mut filenames = vec!();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => match args.next().and_then(|v| parse_number(v)) {
+                Some(n) => frames = n,
+                None => return usage_error("--frames needs a number"),
+            },
+            "--load-state" => match args.next() {
+                Some(file) => state = Some(file.clone()),
+                None => return usage_error("--load-state needs a file name"),
+            },
+            "--input" => match args.next() {
+                Some(file) => input = Some(file.clone()),
+                None => return usage_error("--input needs a file name"),
+            },
+            _ if arg.starts_with('-') => return usage_error(&format!("Unknown option {}", arg)),
+            _ => filenames.push(arg.as_str()),
+        }
+    }
+    let (rom, movie) = match filenames[..] {
+        [rom, movie] => (rom, movie),
+        _ => return usage_error("movie record takes a ROM and a movie file"),
+    };
+    exit_code(record(rom, movie, frames, state.as_deref(), input.as_deref()).map(|_| 0))
+}
+
+fn record(rom: &str, movie: &str, frames: u64, state: Option<&str>, input: Option<&str>) -> Result<()> {
+    let script = match input {
+        Some(input) => Script::open(input)?,
+        None => Script::parse("")?,
+    };
+    let mut gameboy = GameBoy::new(Cartridge::new(rom)?);
+    let mut recorder = match state {
+        Some(state) => {
+            gameboy.load_state(&fs::read(state)?)?;
+            Recorder::from_here(&gameboy)
+        },
+        None => Recorder::power_on(&mut gameboy),
+    };
+    for frame in 0..frames {
+        recorder.frame(&mut gameboy, script.input(frame))?;
+    }
+    recorder.finish().save(movie)?;
+    println!("Recorded {} frames", frames);
+    Ok(())
+}
+
+fn play_main(args: &[String]) -> i32 {
+    let (rom, movie) = match args {
+        [rom, movie] => (rom, movie),
+        _ => return usage_error("movie play takes a ROM and a movie file"),
+    };
+    exit_code(play(rom, movie))
+}
+
+fn play(rom: &str, movie: &str) -> Result<i32> {
+    let mut gameboy = GameBoy::new(Cartridge::new(rom)?);
+    let movie = Movie::open(movie)?;
+    match movie.play(&mut gameboy)? {
+        Some(desync) => {
+            println!("{}", desync);
+            Ok(4)
+        },
+        None => {
+            println!("Replayed {} frames", movie.frames().len());
+            Ok(0)
+        },
+    }
+}
+
+fn exit_code(result: Result<i32>) -> i32 {
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        },
+    }
+}
@@ -0,0 +1,101 @@
+use std::thread;
+use std::time::Instant;
+
+use gbm_rust::{Cartridge, GameBoy, Result};
+use gbm_rust::pacing::{Pacer, Speed};
+use super::{usage_error, parse_number};
+
+/// Frames left unrendered in a row at most, unless --frame-skip says otherwise.
+const DEFAULT_FRAME_SKIP: u32 = 8;
+
+struct Options {
+    speed: Speed,
+    frame_skip: u32,
+    frames: Option<u64>,
+    benchmark: bool,
+}
+
+pub fn main(args: &[String]) -> i32 {
+    let mut options = Options {
+        speed: Speed::Times(1.0),
+        frame_skip: DEFAULT_FRAME_SKIP,
+        frames: None,
+        benchmark: false,
+    };
+    let mut filenames = vec!();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--speed" => match args.next().and_then(|v| parse_speed(v)) {
+                Some(speed) => options.speed = speed,
+                None => return usage_error("--speed needs a positive number or max"),
+            },
+            "--frame-skip" => match args.next().and_then(|v| parse_number(v)) {
+                Some(n) => options.frame_skip = n.min(u32::MAX as u64) as u32,
+                None => return usage_error("--frame-skip needs a number"),
+            },
+            "--frames" => match args.next().and_then(|v| parse_number(v)) {
+                Some(n) => options.frames = Some(n),
+                None => return usage_error("--frames needs a number"),
+            },
+            "--benchmark" => options.benchmark = true,
+            _ if arg.starts_with('-') => return usage_error(&format!("Unknown option {}", arg)),
+            _ => filenames.push(arg.as_str()),
+        }
+    }
+    let filename = match filenames[..] {
+        [filename] => filename,
+        _ => return usage_error("run takes exactly one ROM"),
+    };
+    if options.benchmark {
+        // Everything is emulated and drawn, as fast as it goes
+        options.speed = Speed::Uncapped;
+        options.frame_skip = 0;
+    }
+    match run(filename, &options) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        },
+    }
+}
+
+fn parse_speed(value: &str) -> Option<Speed> {
+    if value == "max" {
+        return Some(Speed::Uncapped);
+    }
+    match value.parse::<f64>() {
+        Ok(speed) if speed > 0.0 && speed.is_finite() => Some(Speed::Times(speed)),
+        _ => None,
+    }
+}
+
+fn run(filename: &str, options: &Options) -> Result<()> {
+    println!("{}", filename);
+    let cart = Cartridge::new(filename)?;
+    println!("{}", cart.title());
+    println!("Cartridge: {}", cart.header().cartridge_type);
+    let mut gameboy = GameBoy::new(cart);
+    let mut pacer = Pacer::new(options.speed, options.frame_skip, Instant::now());
+    loop {
+        gameboy.set_rendering(pacer.render());
+        if let Some(event) = gameboy.run_frame()? {
+            println!("{}", event);
+        }
+        let now = Instant::now();
+        let wait = pacer.frame_done(now);
+        if options.benchmark {
+            if let Some(report) = pacer.take_report(now) {
+                println!("{}", report);
+            }
+        }
+        if options.frames.is_some_and(|limit| gameboy.frames() >= limit) {
+            if options.benchmark {
+                println!("Total: {}", pacer.total(now));
+            }
+            return Ok(());
+        }
+        thread::sleep(wait);
+    }
+}
@@ -1,11 +1,19 @@
-mod registers;
-mod opcodes;
+pub mod registers;
+pub mod opcodes;
+pub mod trace;
+#[cfg(test)]
+mod tests;
+
+use std::fmt;
 
 use self::opcodes::{Opcode, Op8, Op16, Addr};
-use mmu::{MMU, Bus, Master};
+use mmu::{Bus, Master};
 use cpu::registers::{Registers, Reg8, Reg16, Flags};
+use cpu::trace::Trace;
+use savestate::{self, State, Writer, Reader};
+use error::{Error, Result};
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Cond {
     NZ,
     NC,
@@ -14,41 +22,53 @@ pub enum Cond {
     Always,
 }
 
+impl fmt::Display for Cond {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Cond::NZ => write!(f, "nz"),
+            Cond::NC => write!(f, "nc"),
+            Cond::Z => write!(f, "z"),
+            Cond::C => write!(f, "c"),
+            Cond::Always => Ok(()),
+        }
+    }
+}
+
 impl Cond {
     fn check(&self, flags: Flags) -> bool {
         match *self {
             Cond::Always => true,
-            Cond::Z => flags.contains(registers::Z),
-            Cond::C => flags.contains(registers::C),
-            Cond::NZ => !flags.contains(registers::Z),
-            Cond::NC => !flags.contains(registers::C),
+            Cond::Z => flags.contains(Flags::Z),
+            Cond::C => flags.contains(Flags::C),
+            Cond::NZ => !flags.contains(Flags::Z),
+            Cond::NC => !flags.contains(Flags::C),
         }
     }
 }
 
 pub trait DecInc {
-    fn dec(&mut self, cpu: &mut CPU);
-    fn inc(&mut self, cpu: &mut CPU);
+    fn dec<M: Bus + Master>(&mut self, cpu: &mut CPU<M>);
+    fn inc<M: Bus + Master>(&mut self, cpu: &mut CPU<M>);
 }
 
 pub trait In16 {
-    fn read(&self, &mut CPU) -> u16;
+    fn read<M: Bus + Master>(&self, cpu: &mut CPU<M>) -> u16;
 }
 
 pub trait Out16 {
-    fn write(&self, &mut CPU, u16);
+    fn write<M: Bus + Master>(&self, cpu: &mut CPU<M>, value: u16);
 }
 
 pub trait In8 {
-    fn read(&self, &mut CPU) -> u8;
+    fn read<M: Bus + Master>(&self, cpu: &mut CPU<M>) -> u8;
 }
 
 pub trait Out8 {
-    fn write(&self, &mut CPU, u8);
+    fn write<M: Bus + Master>(&self, cpu: &mut CPU<M>, value: u8);
 }
 
 impl In16 for Reg16 {
-    fn read(&self, cpu: &mut CPU) -> u16 {
+    fn read<M: Bus + Master>(&self, cpu: &mut CPU<M>) -> u16 {
         use cpu::registers::Reg16::*;
         match *self {
             HL => ((cpu.regs.h as u16) << 8) | (cpu.regs.l as u16),
@@ -62,7 +82,7 @@ impl In16 for Reg16 {
 }
 
 impl Out16 for Reg16 {
-    fn write(&self, cpu: &mut CPU, value: u16) {
+    fn write<M: Bus + Master>(&self, cpu: &mut CPU<M>, value: u16) {
         use cpu::registers::Reg16::*;
         match *self {
             HL => {
@@ -88,31 +108,19 @@ impl Out16 for Reg16 {
 }
 
 impl DecInc for Reg16 {
-    fn dec(&mut self, cpu: &mut CPU) {
+    fn dec<M: Bus + Master>(&mut self, cpu: &mut CPU<M>) {
         let value = self.read(cpu).wrapping_sub(1);
         self.write(cpu, value);
     }
 
-    fn inc(&mut self, cpu: &mut CPU) {
-        let value = self.read(cpu).wrapping_add(1);
-        self.write(cpu, value);
-    }
-}
-
-impl DecInc for Reg8 {
-    fn dec(&mut self, cpu: &mut CPU) {
-        let value = self.read(cpu).wrapping_sub(1);
-        self.write(cpu, value);
-    }
-
-    fn inc(&mut self, cpu: &mut CPU) {
+    fn inc<M: Bus + Master>(&mut self, cpu: &mut CPU<M>) {
         let value = self.read(cpu).wrapping_add(1);
         self.write(cpu, value);
     }
 }
 
 impl In8 for Reg8 {
-    fn read(&self, cpu: &mut CPU) -> u8 {
+    fn read<M: Bus + Master>(&self, cpu: &mut CPU<M>) -> u8 {
         use cpu::registers::Reg8::*;
         match *self {
             A => cpu.regs.a,
@@ -127,7 +135,7 @@ impl In8 for Reg8 {
 }
 
 impl Out8 for Reg8 {
-    fn write(&self, cpu: &mut CPU, value: u8) {
+    fn write<M: Bus + Master>(&self, cpu: &mut CPU<M>, value: u8) {
         use cpu::registers::Reg8::*;
         match *self {
             A => cpu.regs.a = value,
@@ -141,146 +149,232 @@ impl Out8 for Reg8 {
     }
 }
 
-pub struct Immediate;
-impl In16 for Immediate {
-    fn read(&self, cpu: &mut CPU) -> u16 {
-        cpu.next_u16()
+impl Addr {
+    /// The address this operand points at. (HL+) and (HL-) adjust HL as a
+    /// side effect.
+    fn resolve<M: Bus + Master>(&self, cpu: &mut CPU<M>) -> u16 {
+        match *self {
+            Addr::HL => Reg16::HL.read(cpu),
+            Addr::HLI => {
+                let addr = Reg16::HL.read(cpu);
+                Reg16::HL.inc(cpu);
+                addr
+            },
+            Addr::HLD => {
+                let addr = Reg16::HL.read(cpu);
+                Reg16::HL.dec(cpu);
+                addr
+            },
+            Addr::BC => Reg16::BC.read(cpu),
+            Addr::DE => Reg16::DE.read(cpu),
+            Addr::ZeroPage(addr) => 0xFF00 | (addr as u16),
+            Addr::ZeroPageC => 0xFF00 | (cpu.regs.c as u16),
+            Addr::Immediate(addr) => addr,
+        }
     }
 }
 
-impl In8 for Immediate {
-    fn read(&self, cpu: &mut CPU) -> u8 {
-        cpu.next_u8()
-    }
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Ime {
+    Disabled,
+    Enabled,
+    Enabling,
 }
 
-#[derive(Copy, Clone)]
-pub enum Memory {
-    HL,
-    HLI,
-    HLD,
-    DE,
+/// Something the host may want to know about, returned from `CPU::step`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Event {
+    /// An undefined opcode was executed and the CPU hard-locked.
+    Lockup { opcode: u8, pc: u16 },
 }
 
-impl Out8 for Memory {
-    fn write(&self, cpu: &mut CPU, value: u8) {
-        let addr = match self {
-            &Memory::HL | &Memory::HLI | &Memory::HLD => Reg16::HL.read(cpu),
-            &Memory::DE => Reg16::DE.read(cpu),
-        };
-        cpu.write_u8(addr, value);
-        match self {
-            &Memory::HLI => Reg16::HL.inc(cpu),
-            &Memory::HLD => Reg16::HL.dec(cpu),
-            _ => (),
-        };
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Event::Lockup { opcode, pc } => write!(f, "CPU locked up on opcode 0x{:02x} at 0x{:04x}", opcode, pc),
+        }
     }
 }
 
-impl In8 for Memory {
-    fn read(&self, cpu: &mut CPU) -> u8 {
-        let addr = match self {
-            &Memory::HL | &Memory::HLI | &Memory::HLD => Reg16::HL.read(cpu),
-            &Memory::DE => Reg16::DE.read(cpu),
-        };
-        let value = cpu.read_u8(addr);
-        match self {
-            &Memory::HLI => Reg16::HL.inc(cpu),
-            &Memory::HLD => Reg16::HL.dec(cpu),
-            _ => (),
-        };
-        value
+/// For hosts that would rather treat a lock-up as a failure.
+impl From<Event> for Error {
+    fn from(event: Event) -> Error {
+        match event {
+            Event::Lockup { opcode, pc } => Error::IllegalOpcode { opcode, pc },
+        }
     }
 }
 
-enum Ime {
-    Disabled,
-    Enabled,
-    Enabling,
-}
-
-//TODO Use Bus+Master instead of MMU
-//TODO Don't save a reference
-pub struct CPU<'a> {
+pub struct CPU<M> {
     regs: Registers,
     ime: Ime,
-    mmu: &'a mut MMU
+    halted: bool,
+    halt_bug: bool,
+    locked: bool,
+    trace: Option<Trace>,
+    bus: M,
 }
 
-impl<'a> CPU<'a> {
-    pub fn new(mmu: &'a mut MMU) -> CPU<'a> {
+impl<M: Bus + Master> CPU<M> {
+    pub fn new(bus: M) -> CPU<M> {
         CPU {
             regs: Registers::new(),
             ime: Ime::Disabled,
-            mmu: mmu
+            halted: false,
+            halt_bug: false,
+            locked: false,
+            trace: None,
+            bus,
         }
     }
 
-    pub fn step(&mut self) {
+    /// Puts the CPU back in its post-boot state. The bus is left alone.
+    pub fn reset(&mut self) {
+        self.regs = Registers::new();
+        self.ime = Ime::Disabled;
+        self.halted = false;
+        self.halt_bug = false;
+        self.locked = false;
+    }
+
+    pub fn registers(&self) -> &Registers {
+        &self.regs
+    }
+
+    pub fn registers_mut(&mut self) -> &mut Registers {
+        &mut self.regs
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Whether an illegal opcode has hung the CPU for good.
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Starts writing a line for every instruction executed, replacing any
+    /// trace already running.
+    pub fn set_trace(&mut self, trace: Trace) {
+        self.trace = Some(trace);
+    }
+
+    /// Stops tracing and hands back the trace, to be finished.
+    pub fn take_trace(&mut self) -> Option<Trace> {
+        self.trace.take()
+    }
+
+    pub fn bus(&self) -> &M {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut M {
+        &mut self.bus
+    }
+
+    /// Executes one instruction or services one interrupt. While halted or
+    /// locked up it idles for a single M-cycle instead.
+    pub fn step(&mut self) -> Result<Option<Event>> {
+        if self.locked {
+            // The rest of the system keeps running
+            self.bus.cycle();
+            return Ok(None);
+        }
+
+        if self.halted {
+            self.bus.cycle();
+            // Any pending interrupt wakes the CPU up, even with IME off
+            if self.bus.has_interrupt() {
+                self.halted = false;
+            }
+            return Ok(None);
+        }
+
         let pc = self.regs.pc;
-        //println!("Regs   : {}", self.regs);
         let opcode = self.read_u8(pc);
         let interrupt = match self.ime {
             Ime::Disabled | Ime::Enabling => false,
-            Ime::Enabled => self.mmu.has_interrupt()
+            Ime::Enabled => self.bus.has_interrupt()
         };
-        match self.ime {
-            Ime::Enabling => self.ime = Ime::Enabled,
-            _ => (),
+        if let Ime::Enabling = self.ime {
+            self.ime = Ime::Enabled;
         }
-        
+
         if interrupt {
-            //println!("INTERRUPT");
             self.dispatch_interrupt();
+            Ok(None)
         } else {
-            self.regs.pc += 1;
-            let (opcode, instruction) = Opcode::decode(self, opcode);
-            //if pc >= 0x0293 && pc <= 0x029e {
-            //    println!("Regs   : {}", self.regs);
-            //    println!("[0x{:04x}] 0x{:02x} ({:?})", pc, opcode, instruction);
-            //}
-            self.decode(instruction);
+            if self.trace.is_some() {
+                self.trace_instruction();
+            }
+            if self.halt_bug {
+                // The byte after HALT gets read twice
+                self.halt_bug = false;
+            } else {
+                self.regs.pc = pc.wrapping_add(1);
+            }
+            let opcode = self.bus.execute(pc, opcode);
+            let instruction = Opcode::decode(self, opcode);
+            Ok(self.execute(pc, instruction))
+        }
+    }
+
+    fn trace_instruction(&mut self) {
+        let pc = self.regs.pc;
+        let bank = self.bus.rom_bank(pc);
+        if let Some(ref mut trace) = self.trace {
+            if trace.wants(pc, bank) {
+                let bus = &self.bus;
+                let memory = [0, 1, 2, 3].map(|i| bus.peek(pc.wrapping_add(i)));
+                trace.record(&self.regs, memory);
+            }
         }
     }
 
+    /// Pushes PC and jumps to the interrupt vector. Along with the opcode
+    /// fetch it replaces, this takes five M-cycles: fetch, idle, push the
+    /// high byte, push the low byte, jump. The interrupt is only picked
+    /// between the two pushes, so pushing onto IE can cancel it, and PC
+    /// ends up at 0x0000.
     fn dispatch_interrupt(&mut self) {
-        //self.halt = false;
         self.ime = Ime::Disabled;
-        self.mmu.cycle();
-        self.mmu.cycle();
+        self.bus.cycle();
         let pc = self.regs.pc;
-        self.push_u16(pc);
-        if let Some(interrupt) = self.mmu.ack_interrupt() {
-            self.regs.pc = interrupt.addr();
-        } else {
-            self.regs.pc = 0x0000;
-        }
+        self.push_u8((pc >> 8) as u8);
+        let vector = self.bus.ack_interrupt().map_or(0x0000, |interrupt| interrupt.addr());
+        self.push_u8(pc as u8);
+        self.regs.pc = vector;
+        self.bus.cycle();
     }
 
+    /// Takes an M-cycle, stepping the rest of the system before the write
+    /// lands.
     pub fn write_u8(&mut self, addr: u16, value: u8) {
-        self.mmu.cycle();
-        self.mmu.write(addr, value);
+        self.bus.cycle();
+        self.bus.write(addr, value);
     }
 
     pub fn read_u16(&mut self, addr: u16) -> u16 {
         let l = self.read_u8(addr);
-        let h = self.read_u8(addr+1);
+        let h = self.read_u8(addr.wrapping_add(1));
         ((h as u16) << 8) | (l as u16)
     }
 
     pub fn write_u16(&mut self, addr: u16, value: u16) {
         self.write_u8(addr, value as u8);
-        self.write_u8(addr+1, (value >> 8) as u8);
+        self.write_u8(addr.wrapping_add(1), (value >> 8) as u8);
     }
 
+    /// Takes an M-cycle, stepping the rest of the system before the read.
     pub fn read_u8(&mut self, addr: u16) -> u8 {
-        self.mmu.cycle();
-        self.mmu.read(addr)
+        self.bus.cycle();
+        self.bus.read(addr)
     }
 
     pub fn next_u8(&mut self) -> u8 {
         let addr = self.regs.pc;
-        self.regs.pc += 1;
+        self.regs.pc = addr.wrapping_add(1);
         self.read_u8(addr)
     }
 
@@ -314,121 +408,187 @@ impl<'a> CPU<'a> {
         ((h as u16) << 8) | (l as u16)
     }
 
-    fn decode(&mut self, opcode: Opcode) {
+    fn execute(&mut self, pc: u16, opcode: Opcode) -> Option<Event> {
         match opcode {
             Opcode::Nop => (),
             Opcode::Jr(cond, addr) => self.jr(cond, addr),
+            Opcode::Jp(Cond::Always, Op16::Register(Reg16::HL)) => self.regs.pc = Reg16::HL.read(self),
             Opcode::Jp(cond, to) => self.jp(cond, to),
-            Opcode::Xor(Op8::Register(reg)) => self.xor(reg),
-            Opcode::Ld16(to, from) => self.load16(from, to), //Ld16(SP,HL) needs an internal cycle ?!?
+            Opcode::Ld16(Op16::Register(Reg16::SP), Op16::Register(Reg16::HL)) => {
+                self.load16(Reg16::HL, Reg16::SP);
+                self.bus.cycle();
+            },
+            Opcode::Ld16(to, from) => self.load16(from, to),
+            Opcode::LdHlSp(offset) => {
+                let value = self.add_sp(offset);
+                self.bus.cycle();
+                Reg16::HL.write(self, value);
+            },
             Opcode::Ld(to, from) => self.load8(from, to),
             Opcode::Dec(op) => self.dec8(op),
             Opcode::Dec16(Op16::Register(reg)) => self.dec16(reg),
             Opcode::Inc16(Op16::Register(reg)) => self.inc16(reg),
-            Opcode::Inc(reg) => self.inc8(reg),
+            Opcode::Inc(op) => self.inc8(op),
+            Opcode::Add(to, from) => self.add8(from, to),
             Opcode::Adc(to, from) => self.adc(from, to),
-            Opcode::Rra => self.rra(),
-            Opcode::Di => self.ime = Ime::Disabled,
+            Opcode::Sub(op) => self.sub(op),
+            Opcode::Sbc(to, from) => self.sbc(from, to),
+            Opcode::And(from) => self.and(from),
+            Opcode::Xor(op) => self.xor(op),
+            Opcode::Or(op) => self.or(op),
             Opcode::Cp(op) => self.cp(op),
-            Opcode::Call(Cond::Always, addr) => self.call(addr),
+            Opcode::Add16(to, from) => self.add16(from, to),
+            Opcode::AddSp(offset) => {
+                let value = self.add_sp(offset);
+                self.bus.cycle();
+                self.bus.cycle();
+                Reg16::SP.write(self, value);
+            },
+            Opcode::Rlca => self.rotate_a(Self::rlc),
+            Opcode::Rrca => self.rotate_a(Self::rrc),
+            Opcode::Rla => self.rotate_a(Self::rl),
+            Opcode::Rra => self.rotate_a(Self::rr),
+            Opcode::Daa => self.daa(),
+            Opcode::Cpl => self.cpl(),
+            Opcode::Scf => self.scf(),
+            Opcode::Ccf => self.ccf(),
+            Opcode::Halt => self.halt(),
+            //TODO Enter low-power mode until a button is pressed
+            Opcode::Stop => (),
+            Opcode::Di => self.ime = Ime::Disabled,
+            Opcode::Ei => self.ime = Ime::Enabling,
+            Opcode::Call(cond, addr) => self.call(cond, addr),
             Opcode::Ret(Cond::Always) => self.ret(),
             Opcode::Ret(cond) => self.ret_cond(cond),
-            Opcode::Or(Op8::Register(reg)) => self.or(reg),
-            Opcode::Ei => self.ime = Ime::Enabling,
-            Opcode::Cpl => self.cpl(),
-            Opcode::And(from) => self.and(from),
-            Opcode::Swap(op) => self.swap(op),
+            Opcode::Reti => {
+                self.ret();
+                self.ime = Ime::Enabled;
+            },
             Opcode::Rst(addr) => self.rst(addr),
-            Opcode::Add(to, from) => self.add8(from, to),
-            Opcode::Add16(to, from) => self.add16(from, to),
-            Opcode::Unknown(opcode) => panic!("Unknown opcode 0x{:04X}", opcode),
             Opcode::Pop(Op16::Register(reg)) => self.pop(reg),
             Opcode::Push(Op16::Register(reg)) => self.push(reg),
+            Opcode::Rlc(op) => self.rlc(op),
+            Opcode::Rrc(op) => self.rrc(op),
+            Opcode::Rl(op) => self.rl(op),
+            Opcode::Rr(op) => self.rr(op),
+            Opcode::Sla(op) => self.sla(op),
+            Opcode::Sra(op) => self.sra(op),
+            Opcode::Swap(op) => self.swap(op),
+            Opcode::Srl(op) => self.srl(op),
+            Opcode::Bit(bit, op) => self.bit(bit, op),
             Opcode::Res(bit, op) => self.res(bit, op),
-            Opcode::Scf => self.scf(),
-            _ => panic!("Unhandled opcode ({:?})", opcode),
+            Opcode::Set(bit, op) => self.set(bit, op),
+            Opcode::Illegal(opcode) => {
+                self.locked = true;
+                return Some(Event::Lockup { opcode, pc });
+            },
+            _ => unreachable!("decode only gives register operands to INC, DEC, PUSH and POP"),
         }
+        None
     }
 }
 
 impl In16 for Op16 {
-    fn read(&self, cpu: &mut CPU) -> u16 {
+    fn read<M: Bus + Master>(&self, cpu: &mut CPU<M>) -> u16 {
         match *self {
             Op16::Register(ref r) => r.read(cpu),
             Op16::Immediate(value) => value,
-            _ => panic!("Not yet implemented (Op16+In16) ({:?})", self),
+            Op16::Memory(ref addr) => {
+                let addr = addr.resolve(cpu);
+                cpu.read_u16(addr)
+            },
         }
     }
 }
 
 impl Out16 for Op16 {
-    fn write(&self, cpu: &mut CPU, value: u16) {
+    fn write<M: Bus + Master>(&self, cpu: &mut CPU<M>, value: u16) {
         match *self {
             Op16::Register(ref r) => r.write(cpu, value),
             Op16::Immediate(_) => panic!("You cannot write to an immediate"),
-            _ => panic!("Not yet implemented (Op16+Out16) ({:?})", self),
+            Op16::Memory(ref addr) => {
+                let addr = addr.resolve(cpu);
+                cpu.write_u16(addr, value);
+            },
         }
     }
 }
 
 impl In8 for Op8 {
-    fn read(&self, cpu: &mut CPU) -> u8 {
+    fn read<M: Bus + Master>(&self, cpu: &mut CPU<M>) -> u8 {
         match *self {
             Op8::Register(ref r) => r.read(cpu),
             Op8::Immediate(value) => value,
-            Op8::Memory(Addr::ZeroPage(addr)) => cpu.read_u8(0xFF00|(addr as u16)),
-            Op8::Memory(Addr::HLI) => Memory::HLI.read(cpu),
-            Op8::Memory(Addr::HL) => Memory::HL.read(cpu),
-            Op8::Memory(Addr::DE) => Memory::DE.read(cpu),
-            Op8::Memory(Addr::Immediate(addr)) => cpu.read_u8(addr),
-            _ => panic!("Not yet implemented (Op8+In8) ({:?})", self),
+            Op8::Memory(ref addr) => {
+                let addr = addr.resolve(cpu);
+                cpu.read_u8(addr)
+            },
         }
     }
 }
 
 impl Out8 for Op8 {
-    fn write(&self, cpu: &mut CPU, value: u8) {
+    fn write<M: Bus + Master>(&self, cpu: &mut CPU<M>, value: u8) {
         match *self {
             Op8::Register(ref r) => r.write(cpu, value),
             Op8::Immediate(_) => panic!("You cannot write to an immediate"),
-            Op8::Memory(Addr::HL) => Memory::HL.write(cpu, value),
-            Op8::Memory(Addr::HLD) => Memory::HLD.write(cpu, value),
-            Op8::Memory(Addr::HLI) => Memory::HLI.write(cpu, value),
-            Op8::Memory(Addr::ZeroPage(addr)) => {cpu.write_u8(0xFF00|(addr as u16), value);},
-            Op8::Memory(Addr::ZeroPageC) => { let c = Reg8::C.read(cpu) as u16; cpu.write_u8(0xFF00|c, value)},
-            Op8::Memory(Addr::Immediate(addr)) => {cpu.write_u8(addr, value);},
-            Op8::Memory(Addr::DE) => {let addr = Reg16::DE.read(cpu); cpu.write_u8(addr, value); },
-            _ => panic!("Not yet implemented (Op8+Out8) ({:?})", self),
+            Op8::Memory(ref addr) => {
+                let addr = addr.resolve(cpu);
+                cpu.write_u8(addr, value);
+            },
         }
     }
 }
 
-impl DecInc for Op8 {
-    fn dec(&mut self, cpu: &mut CPU) {
-        match *self {
-            Op8::Register(ref mut r) => r.dec(cpu),
-            Op8::Memory(Addr::HL) => {
-                let value = Memory::HL.read(cpu).wrapping_sub(1);
-                Memory::HL.write(cpu, value);
-            },
-            _ => panic!("Not yet implemented (Op8+DecInc+dec) ({:?})", self),
+impl<M: Bus + Master> CPU<M> {
+    fn halt(&mut self) {
+        if !self.bus.has_interrupt() {
+            self.halted = true;
+        } else if self.ime == Ime::Disabled {
+            // HALT falls straight through and the next byte is read twice
+            self.halt_bug = true;
         }
+        // With IME set the pending interrupt is serviced right away
     }
-    fn inc(&mut self, cpu: &mut CPU) {
-        match *self {
-            Op8::Register(ref mut r) => r.inc(cpu),
-            Op8::Memory(Addr::HL) => {
-                let value = Memory::HL.read(cpu).wrapping_add(1);
-                Memory::HL.write(cpu, value);
-            },
-            _ => panic!("Not yet implemented (Op8+DecInc+inc) ({:?})", self),
+
+    fn scf(&mut self) {
+        self.regs.f = (self.regs.f & Flags::Z) | Flags::C;
+    }
+
+    fn ccf(&mut self) {
+        self.regs.f = (self.regs.f & Flags::Z) | Flags::C.test(!self.regs.f.contains(Flags::C));
+    }
+
+    fn daa(&mut self) {
+        let mut a = self.regs.a;
+        let mut carry = self.regs.f.contains(Flags::C);
+        if self.regs.f.contains(Flags::N) {
+            if carry {
+                a = a.wrapping_sub(0x60);
+            }
+            if self.regs.f.contains(Flags::H) {
+                a = a.wrapping_sub(0x06);
+            }
+        } else {
+            if carry || a > 0x99 {
+                a = a.wrapping_add(0x60);
+                carry = true;
+            }
+            if self.regs.f.contains(Flags::H) || (a & 0x0F) > 0x09 {
+                a = a.wrapping_add(0x06);
+            }
         }
+        self.regs.a = a;
+        self.regs.f = Flags::Z.test(a == 0) |
+            (self.regs.f & Flags::N) |
+            Flags::C.test(carry); // (Z - 0 C)
     }
-}
 
-impl<'a> CPU<'a> {
-    fn scf(&mut self) {
-        self.regs.f = (self.regs.f & registers::Z) | registers::C;
+    fn bit<I: In8>(&mut self, bit: u8, in8: I) {
+        let value = in8.read(self);
+        self.regs.f = Flags::Z.test(value & (1 << bit) == 0) |
+            Flags::H |
+            (self.regs.f & Flags::C); // (Z 0 1 -)
     }
 
     fn res<IO: In8+Out8>(&mut self, bit: u8, io8: IO) {
@@ -438,9 +598,14 @@ impl<'a> CPU<'a> {
         io8.write(self, value);
     }
 
+    fn set<IO: In8+Out8>(&mut self, bit: u8, io8: IO) {
+        let value = io8.read(self) | (1 << bit);
+        io8.write(self, value);
+    }
+
     fn push(&mut self, reg: Reg16) {
         let value = reg.read(self);
-        self.mmu.cycle();
+        self.bus.cycle();
         self.push_u16(value);
     }
 
@@ -448,13 +613,23 @@ impl<'a> CPU<'a> {
         let rhs = in16.read(self);
         let lhs = out16.read(self);
         let (value, carry) = lhs.overflowing_add(rhs);
-        let half_carry = (((lhs >> 4) & 0xf)+((rhs >> 4) & 0xf)) & 0x10 == 0x10;
-        self.regs.f = (self.regs.f & registers::Z) |
-            registers::H.test(half_carry) |
-            registers::C.test(carry);
+        let half_carry = (lhs & 0x0FFF) + (rhs & 0x0FFF) > 0x0FFF;
+        self.regs.f = (self.regs.f & Flags::Z) |
+            Flags::H.test(half_carry) |
+            Flags::C.test(carry);
+        self.bus.cycle();
         out16.write(self, value);
     }
 
+    /// SP plus a signed offset, for `ADD SP,e` and `LD HL,SP+e`.
+    fn add_sp(&mut self, offset: u8) -> u16 {
+        let sp = self.regs.sp;
+        // The flags come from an unsigned add on the low byte
+        self.regs.f = Flags::H.test((sp & 0x0F) + (offset as u16 & 0x0F) > 0x0F) |
+            Flags::C.test((sp & 0xFF) + (offset as u16) > 0xFF); // (0 0 H C)
+        sp.wrapping_add((offset as i8) as u16)
+    }
+
     fn pop(&mut self, reg: Reg16) {
         let value = self.pop_u16();
         reg.write(self, value);
@@ -465,148 +640,211 @@ impl<'a> CPU<'a> {
         let lhs = out8.read(self);
         let (value, carry) = lhs.overflowing_add(rhs);
         let half_carry = ((lhs & 0xf)+(rhs & 0xf)) & 0x10 == 0x10;
-        self.regs.f = registers::Z.test(value == 0) |
-            registers::H.test(half_carry) |
-            registers::C.test(carry);
+        self.regs.f = Flags::Z.test(value == 0) |
+            Flags::H.test(half_carry) |
+            Flags::C.test(carry);
         out8.write(self, value);
     }
 
     fn rst(&mut self, addr: u8) {
         let pc = Reg16::PC.read(self);
-        self.mmu.cycle();
+        self.bus.cycle();
         self.push_u16(pc);
         Reg16::PC.write(self, addr as u16);
     }
 
+    /// RLCA, RRCA, RLA and RRA behave like their CB counterparts on A,
+    /// except that Z is always cleared.
+    fn rotate_a(&mut self, rotate: fn(&mut Self, Reg8)) {
+        rotate(self, Reg8::A);
+        self.regs.f.remove(Flags::Z);
+    }
+
+    /// Stores the result of a shift or rotate. (Z 0 0 C)
+    fn shifted<O: Out8>(&mut self, out8: O, value: u8, carry: bool) {
+        self.regs.f = Flags::Z.test(value == 0) | Flags::C.test(carry);
+        out8.write(self, value);
+    }
+
+    fn rlc<IO: In8+Out8>(&mut self, io8: IO) {
+        let value = io8.read(self);
+        self.shifted(io8, value.rotate_left(1), value & 0x80 != 0);
+    }
+
+    fn rrc<IO: In8+Out8>(&mut self, io8: IO) {
+        let value = io8.read(self);
+        self.shifted(io8, value.rotate_right(1), value & 0x01 != 0);
+    }
+
+    fn rl<IO: In8+Out8>(&mut self, io8: IO) {
+        let value = io8.read(self);
+        let ci = self.regs.f.contains(Flags::C) as u8;
+        self.shifted(io8, (value << 1) | ci, value & 0x80 != 0);
+    }
+
+    fn rr<IO: In8+Out8>(&mut self, io8: IO) {
+        let value = io8.read(self);
+        let ci = self.regs.f.contains(Flags::C) as u8;
+        self.shifted(io8, (value >> 1) | (ci << 7), value & 0x01 != 0);
+    }
+
+    fn sla<IO: In8+Out8>(&mut self, io8: IO) {
+        let value = io8.read(self);
+        self.shifted(io8, value << 1, value & 0x80 != 0);
+    }
+
+    fn sra<IO: In8+Out8>(&mut self, io8: IO) {
+        let value = io8.read(self);
+        self.shifted(io8, (value >> 1) | (value & 0x80), value & 0x01 != 0);
+    }
+
+    fn srl<IO: In8+Out8>(&mut self, io8: IO) {
+        let value = io8.read(self);
+        self.shifted(io8, value >> 1, value & 0x01 != 0);
+    }
+
     fn swap<IO: In8+Out8>(&mut self, op: IO) {
         let value = op.read(self);
-        let value = (value >> 4) | (value << 4);
-        self.regs.f = registers::Z.test(value == 0); // (Z 0 0 0)
+        let value = value.rotate_left(4);
+        self.regs.f = Flags::Z.test(value == 0); // (Z 0 0 0)
         op.write(self, value);
     }
 
     fn dec16<R: DecInc+In16>(&mut self, mut reg: R) {
         reg.dec(self);
-        self.mmu.cycle();
+        self.bus.cycle();
     }
 
     fn inc16<R: DecInc+In16>(&mut self, mut reg: R) {
         reg.inc(self);
-        self.mmu.cycle();
+        self.bus.cycle();
     }
 
     fn ret_cond(&mut self, cond: Cond) {
-        self.mmu.cycle();
+        self.bus.cycle();
         if cond.check(self.regs.f) {
-            let pc = self.pop_u16();
-            Reg16::PC.write(self, pc);
-            self.mmu.cycle();
+            self.ret();
         }
     }
 
     fn ret(&mut self) {
         let pc = self.pop_u16();
         Reg16::PC.write(self, pc);
-        self.mmu.cycle();
+        self.bus.cycle();
     }
 
-    fn call<I: In16>(&mut self, addr: I) {
+    fn call<I: In16>(&mut self, cond: Cond, addr: I) {
         let value = addr.read(self);
-        let pc = Reg16::PC.read(self);
-        self.mmu.cycle();
-        self.push_u16(pc);
-        Reg16::PC.write(self, value);
+        if cond.check(self.regs.f) {
+            let pc = Reg16::PC.read(self);
+            self.bus.cycle();
+            self.push_u16(pc);
+            Reg16::PC.write(self, value);
+        }
+    }
+
+    /// A minus `value`, setting the flags the way SUB and CP do. (Z 1 H C)
+    fn subtract(&mut self, value: u8) -> u8 {
+        let result = self.regs.a.wrapping_sub(value);
+        self.regs.f = Flags::Z.test(result == 0) |
+            Flags::N |
+            Flags::H.test((self.regs.a & 0xf) < (value & 0xf)) |
+            Flags::C.test((self.regs.a as u16) < (value as u16));
+        result
     }
 
     fn cp<I: In8>(&mut self, op: I) {
         let value = op.read(self);
-        let result = self.regs.a.wrapping_sub(value);
-        self.regs.f = registers::Z.test(result == 0) |
-            registers::N |
-            registers::H.test((self.regs.a & 0xf) < (value & 0xf)) |
-            registers::C.test((self.regs.a as u16) < (value as u16));
+        self.subtract(value);
+    }
+
+    fn sub<I: In8>(&mut self, op: I) {
+        let value = op.read(self);
+        self.regs.a = self.subtract(value);
+    }
+
+    fn sbc<I: In8, O: In8+Out8>(&mut self, in8: I, out8: O) {
+        let value = in8.read(self);
+        let original = out8.read(self);
+        let c = self.regs.f.contains(Flags::C) as u8;
+        let result = original.wrapping_sub(value).wrapping_sub(c);
+        self.regs.f = Flags::Z.test(result == 0) |
+            Flags::N |
+            Flags::H.test((original & 0xf) < (value & 0xf) + c) |
+            Flags::C.test((original as u16) < value as u16 + c as u16);
+        out8.write(self, result);
     }
 
     fn and<I: In8>(&mut self, in8: I) {
         let value = in8.read(self);
-        self.regs.a = self.regs.a & value;
-        self.regs.f = registers::Z.test(self.regs.a == 0) | 
-            registers::H; // (Z 0 1 0)
+        self.regs.a &= value;
+        self.regs.f = Flags::Z.test(self.regs.a == 0) |
+            Flags::H; // (Z 0 1 0)
     }
 
     fn or<I: In8>(&mut self, in8: I) {
         let value = in8.read(self);
-        self.regs.a = self.regs.a | value;
-        self.regs.f = registers::Z.test(self.regs.a == 0); // (Z 0 0 0)
+        self.regs.a |= value;
+        self.regs.f = Flags::Z.test(self.regs.a == 0); // (Z 0 0 0)
     }
 
     fn xor<I: In8>(&mut self, in8: I) {
         let value = in8.read(self);
-        self.regs.a = self.regs.a ^ value;
-        self.regs.f = registers::Z.test(self.regs.a == 0); // (Z 0 0 0)
-    }
-    
-    fn rra(&mut self) {
-        let value = self.regs.a;
-        let ci = if self.regs.f.contains(registers::C) {
-            1
-        } else {
-            0
-        };
-        let co = value & 0x01;
-        self.regs.a = (value >> 1) | (ci << 7);
-        self.regs.f = registers::C.test(co != 0);
+        self.regs.a ^= value;
+        self.regs.f = Flags::Z.test(self.regs.a == 0); // (Z 0 0 0)
     }
 
     fn jr(&mut self, cond: Cond, addr: u8) {
         if cond.check(self.regs.f) {
             self.regs.pc = self.regs.pc.wrapping_add((addr as i8) as u16);
+            self.bus.cycle();
         }
     }
 
     fn jp<I: In16>(&mut self, cond: Cond, addr: I) {
+        let addr = addr.read(self);
         if cond.check(self.regs.f) {
-            self.regs.pc = addr.read(self);
+            self.regs.pc = addr;
+            self.bus.cycle();
         }
     }
 
     fn cpl(&mut self) {
         self.regs.a = !self.regs.a;
-        self.regs.f = (self.regs.f & registers::Z) | // -
-            registers::N | // 1
-            registers::H | // 1
-            (self.regs.f & registers::C); // -
+        self.regs.f = (self.regs.f & Flags::Z) | // -
+            Flags::N | // 1
+            Flags::H | // 1
+            (self.regs.f & Flags::C); // -
     }
 
     fn adc<I: In8, O: In8+Out8>(&mut self, in8: I, out8: O) {
         let value = in8.read(self);
         let original = out8.read(self);
-        let c = if self.regs.f.contains(registers::C) {1} else {0};
+        let c = self.regs.f.contains(Flags::C) as u8;
         let result = original.wrapping_add(value).wrapping_add(c);
-        self.regs.f = registers::Z.test(result == 0) |
-            registers::C.test(original as u16 + value as u16 + c as u16 > 0xff) |
-            registers::H.test((original & 0xf) + (value & 0xf) + c > 0xf);
+        self.regs.f = Flags::Z.test(result == 0) |
+            Flags::C.test(original as u16 + value as u16 + c as u16 > 0xff) |
+            Flags::H.test((original & 0xf) + (value & 0xf) + c > 0xf);
         out8.write(self, result);
     }
 
-    fn dec8<I: DecInc+In8>(&mut self, mut reg: I) {
-        reg.dec(self);
-        let value = reg.read(self);
-
+    fn dec8<IO: In8+Out8>(&mut self, io8: IO) {
+        let value = io8.read(self).wrapping_sub(1);
         self.regs.f =
-            registers::Z.test(value == 0) | // Z
-            registers::N | // 1
-            registers::H.test((value & 0x0F) == 0x0F) | // H
-            (self.regs.f & registers::C); // -
+            Flags::Z.test(value == 0) | // Z
+            Flags::N | // 1
+            Flags::H.test((value & 0x0F) == 0x0F) | // H
+            (self.regs.f & Flags::C); // -
+        io8.write(self, value);
     }
 
-    fn inc8<I: DecInc+In8>(&mut self, mut in8: I) {
-        in8.inc(self);
-        let value = in8.read(self);
+    fn inc8<IO: In8+Out8>(&mut self, io8: IO) {
+        let value = io8.read(self).wrapping_add(1);
         self.regs.f =
-            registers::Z.test(value == 0) | // Z
-            registers::H.test((value & 0x0F) == 0x0F) | // H
-            (self.regs.f & registers::C); // -
+            Flags::Z.test(value == 0) | // Z
+            Flags::H.test((value & 0x0F) == 0x00) | // H
+            (self.regs.f & Flags::C); // -
+        io8.write(self, value);
     }
 
     fn load16<I: In16, O: Out16>(&mut self, in16: I, out16: O) {
@@ -619,3 +857,32 @@ impl<'a> CPU<'a> {
         out8.write(self, value);
     }
 }
+
+impl<M: State> State for CPU<M> {
+    fn save(&self, out: &mut Writer) {
+        self.regs.save(out);
+        out.u8(match self.ime {
+            Ime::Disabled => 0,
+            Ime::Enabled => 1,
+            Ime::Enabling => 2,
+        });
+        out.bool(self.halted);
+        out.bool(self.halt_bug);
+        out.bool(self.locked);
+        self.bus.save(out);
+    }
+
+    fn load(&mut self, input: &mut Reader) -> Result<()> {
+        self.regs.load(input)?;
+        self.ime = match input.u8()? {
+            0 => Ime::Disabled,
+            1 => Ime::Enabled,
+            2 => Ime::Enabling,
+            value => return Err(savestate::bad(&format!("invalid IME state {}", value))),
+        };
+        self.halted = input.bool()?;
+        self.halt_bug = input.bool()?;
+        self.locked = input.bool()?;
+        self.bus.load(input)
+    }
+}
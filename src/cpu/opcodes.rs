@@ -1,16 +1,50 @@
+use std::fmt;
 
 use cpu::{Cond};
 use cpu::CPU;
 use cpu::registers::{Reg8, Reg16};
+use mmu::{Bus, Master};
 
-#[derive(Debug)]
+/// Source of instruction bytes for `Opcode::decode`.
+pub trait Fetch {
+    fn fetch_u8(&mut self) -> u8;
+
+    fn fetch_u16(&mut self) -> u16 {
+        let l = self.fetch_u8();
+        let h = self.fetch_u8();
+        ((h as u16) << 8) | (l as u16)
+    }
+}
+
+/// The CPU fetches like it executes: one M-cycle per byte, advancing PC.
+impl<M: Bus + Master> Fetch for CPU<M> {
+    fn fetch_u8(&mut self) -> u8 {
+        self.next_u8()
+    }
+}
+
+/// Reads instruction bytes without touching anything else.
+struct Cursor<F> {
+    read: F,
+    addr: u16,
+}
+
+impl<F: Fn(u16) -> u8> Fetch for Cursor<F> {
+    fn fetch_u8(&mut self) -> u8 {
+        let value = (self.read)(self.addr);
+        self.addr = self.addr.wrapping_add(1);
+        value
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Op8 {
     Register(Reg8),
     Immediate(u8),
     Memory(Addr),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Addr {
     HL, HLD, HLI,
     BC, DE,
@@ -19,16 +53,16 @@ pub enum Addr {
     Immediate(u16),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Op16 {
     Register(Reg16),
     Immediate(u16),
     Memory(Addr),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Opcode {
-    Unknown(u16),
+    Illegal(u8),
     Nop,
     Dec(Op8),
     Dec16(Op16),
@@ -47,7 +81,7 @@ pub enum Opcode {
     Add(Op8, Op8),
     Add16(Op16, Op16),
     Adc(Op8, Op8),
-    Stop(u8),
+    Stop,
     Daa,
     Cpl,
     Scf,
@@ -64,81 +98,95 @@ pub enum Opcode {
     Rst(u8),
     Di,
     Ei,
+    Reti,
+    AddSp(u8),
+    LdHlSp(u8),
+    Rlc(Op8),
+    Rrc(Op8),
+    Rl(Op8),
+    Rr(Op8),
+    Sla(Op8),
+    Sra(Op8),
     Swap(Op8),
+    Srl(Op8),
+    Bit(u8, Op8),
     Res(u8, Op8),
-    Reti,
+    Set(u8, Op8),
 }
 
 impl Opcode {
-    pub fn decode(cpu: &mut CPU, opcode: u8) -> (u16, Opcode) {
-        //let opcode = cpu.next_u8();
-        let instruction = match opcode {
+    pub fn decode<F: Fetch>(input: &mut F, opcode: u8) -> Opcode {
+        match opcode {
             0x00 => Opcode::Nop,
-            0x01 => Opcode::Ld16(Op16::Register(Reg16::BC), Op16::Immediate(cpu.next_u16())),
+            0x01 => Opcode::Ld16(Op16::Register(Reg16::BC), Op16::Immediate(input.fetch_u16())),
             0x02 => Opcode::Ld(Op8::Memory(Addr::BC), Op8::Register(Reg8::A)),
             0x03 => Opcode::Inc16(Op16::Register(Reg16::BC)),
             0x04 => Opcode::Inc(Op8::Register(Reg8::B)),
             0x05 => Opcode::Dec(Op8::Register(Reg8::B)),
-            0x06 => Opcode::Ld(Op8::Register(Reg8::B), Op8::Immediate(cpu.next_u8())),
+            0x06 => Opcode::Ld(Op8::Register(Reg8::B), Op8::Immediate(input.fetch_u8())),
             0x07 => Opcode::Rlca,
-            0x08 => Opcode::Ld16(Op16::Memory(Addr::Immediate(cpu.next_u16())), Op16::Register(Reg16::SP)),
+            0x08 => Opcode::Ld16(Op16::Memory(Addr::Immediate(input.fetch_u16())), Op16::Register(Reg16::SP)),
             0x09 => Opcode::Add16(Op16::Register(Reg16::HL), Op16::Register(Reg16::BC)),
             0x0A => Opcode::Ld(Op8::Register(Reg8::A), Op8::Memory(Addr::BC)),
             0x0B => Opcode::Dec16(Op16::Register(Reg16::BC)),
             0x0C => Opcode::Inc(Op8::Register(Reg8::C)),
             0x0D => Opcode::Dec(Op8::Register(Reg8::C)),
-            0x0E => Opcode::Ld(Op8::Register(Reg8::C), Op8::Immediate(cpu.next_u8())),
+            0x0E => Opcode::Ld(Op8::Register(Reg8::C), Op8::Immediate(input.fetch_u8())),
             0x0F => Opcode::Rrca,
 
-            0x10 => Opcode::Stop(cpu.next_u8()),
-            0x11 => Opcode::Ld16(Op16::Register(Reg16::DE), Op16::Immediate(cpu.next_u16())),
+            0x10 => {
+                // STOP is followed by a padding byte
+                input.fetch_u8();
+                Opcode::Stop
+            },
+            0x11 => Opcode::Ld16(Op16::Register(Reg16::DE), Op16::Immediate(input.fetch_u16())),
             0x12 => Opcode::Ld(Op8::Memory(Addr::DE), Op8::Register(Reg8::A)),
             0x13 => Opcode::Inc16(Op16::Register(Reg16::DE)),
             0x14 => Opcode::Inc(Op8::Register(Reg8::D)),
             0x15 => Opcode::Dec(Op8::Register(Reg8::D)),
-            0x16 => Opcode::Ld(Op8::Register(Reg8::D), Op8::Immediate(cpu.next_u8())),
+            0x16 => Opcode::Ld(Op8::Register(Reg8::D), Op8::Immediate(input.fetch_u8())),
             0x17 => Opcode::Rla,
-            0x18 => Opcode::Jr(Cond::Always, cpu.next_u8()),
+            0x18 => Opcode::Jr(Cond::Always, input.fetch_u8()),
             0x19 => Opcode::Add16(Op16::Register(Reg16::HL), Op16::Register(Reg16::DE)),
             0x1A => Opcode::Ld(Op8::Register(Reg8::A), Op8::Memory(Addr::DE)),
             0x1B => Opcode::Dec16(Op16::Register(Reg16::DE)),
             0x1C => Opcode::Inc(Op8::Register(Reg8::E)),
             0x1D => Opcode::Dec(Op8::Register(Reg8::E)),
-            0x1E => Opcode::Ld(Op8::Register(Reg8::E), Op8::Immediate(cpu.next_u8())),
+            0x1E => Opcode::Ld(Op8::Register(Reg8::E), Op8::Immediate(input.fetch_u8())),
             0x1F => Opcode::Rra,
 
-            0x20 => Opcode::Jr(Cond::NZ, cpu.next_u8()),
-            0x21 => Opcode::Ld16(Op16::Register(Reg16::HL), Op16::Immediate(cpu.next_u16())),
+            0x20 => Opcode::Jr(Cond::NZ, input.fetch_u8()),
+            0x21 => Opcode::Ld16(Op16::Register(Reg16::HL), Op16::Immediate(input.fetch_u16())),
             0x22 => Opcode::Ld(Op8::Memory(Addr::HLI), Op8::Register(Reg8::A)),
             0x23 => Opcode::Inc16(Op16::Register(Reg16::HL)),
             0x24 => Opcode::Inc(Op8::Register(Reg8::H)),
             0x25 => Opcode::Dec(Op8::Register(Reg8::H)),
-            0x26 => Opcode::Ld(Op8::Register(Reg8::H), Op8::Immediate(cpu.next_u8())),
+            0x26 => Opcode::Ld(Op8::Register(Reg8::H), Op8::Immediate(input.fetch_u8())),
             0x27 => Opcode::Daa,
-            0x28 => Opcode::Jr(Cond::Z, cpu.next_u8()),
+            0x28 => Opcode::Jr(Cond::Z, input.fetch_u8()),
             0x29 => Opcode::Add16(Op16::Register(Reg16::HL), Op16::Register(Reg16::HL)),
             0x2A => Opcode::Ld(Op8::Register(Reg8::A), Op8::Memory(Addr::HLI)),
             0x2B => Opcode::Dec16(Op16::Register(Reg16::HL)),
             0x2C => Opcode::Inc(Op8::Register(Reg8::L)),
             0x2D => Opcode::Dec(Op8::Register(Reg8::L)),
-            0x2E => Opcode::Ld(Op8::Register(Reg8::L), Op8::Immediate(cpu.next_u8())),
+            0x2E => Opcode::Ld(Op8::Register(Reg8::L), Op8::Immediate(input.fetch_u8())),
             0x2F => Opcode::Cpl,
 
-            0x30 => Opcode::Jr(Cond::NC, cpu.next_u8()),
-            0x31 => Opcode::Ld16(Op16::Register(Reg16::SP), Op16::Immediate(cpu.next_u16())),
+            0x30 => Opcode::Jr(Cond::NC, input.fetch_u8()),
+            0x31 => Opcode::Ld16(Op16::Register(Reg16::SP), Op16::Immediate(input.fetch_u16())),
             0x32 => Opcode::Ld(Op8::Memory(Addr::HLD), Op8::Register(Reg8::A)),
             0x33 => Opcode::Inc16(Op16::Register(Reg16::SP)),
             0x34 => Opcode::Inc(Op8::Memory(Addr::HL)),
             0x35 => Opcode::Dec(Op8::Memory(Addr::HL)),
-            0x36 => Opcode::Ld(Op8::Memory(Addr::HL), Op8::Immediate(cpu.next_u8())),
+            0x36 => Opcode::Ld(Op8::Memory(Addr::HL), Op8::Immediate(input.fetch_u8())),
             0x37 => Opcode::Scf,
-            0x38 => Opcode::Jr(Cond::C, cpu.next_u8()),
+            0x38 => Opcode::Jr(Cond::C, input.fetch_u8()),
             0x39 => Opcode::Add16(Op16::Register(Reg16::HL), Op16::Register(Reg16::SP)),
             0x3A => Opcode::Ld(Op8::Register(Reg8::A), Op8::Memory(Addr::HLD)),
             0x3B => Opcode::Dec16(Op16::Register(Reg16::SP)),
             0x3C => Opcode::Inc(Op8::Register(Reg8::A)),
             0x3D => Opcode::Dec(Op8::Register(Reg8::A)),
-            0x3E => Opcode::Ld(Op8::Register(Reg8::A), Op8::Immediate(cpu.next_u8())),
+            0x3E => Opcode::Ld(Op8::Register(Reg8::A), Op8::Immediate(input.fetch_u8())),
             0x3F => Opcode::Ccf,
             
             0x40 => Opcode::Ld(Op8::Register(Reg8::B), Op8::Register(Reg8::B)),
@@ -279,56 +327,336 @@ impl Opcode {
 
             0xC0 => Opcode::Ret(Cond::NZ),
             0xC1 => Opcode::Pop(Op16::Register(Reg16::BC)),
-            0xC2 => Opcode::Jp(Cond::NZ, Op16::Immediate(cpu.next_u16())),
-            0xC3 => Opcode::Jp(Cond::Always, Op16::Immediate(cpu.next_u16())),
-            0xC4 => Opcode::Call(Cond::NZ, Op16::Immediate(cpu.next_u16())),
+            0xC2 => Opcode::Jp(Cond::NZ, Op16::Immediate(input.fetch_u16())),
+            0xC3 => Opcode::Jp(Cond::Always, Op16::Immediate(input.fetch_u16())),
+            0xC4 => Opcode::Call(Cond::NZ, Op16::Immediate(input.fetch_u16())),
             0xC5 => Opcode::Push(Op16::Register(Reg16::BC)),
-            0xC6 => Opcode::Add(Op8::Register(Reg8::A), Op8::Immediate(cpu.next_u8())),
+            0xC6 => Opcode::Add(Op8::Register(Reg8::A), Op8::Immediate(input.fetch_u8())),
             0xC7 => Opcode::Rst(0x00),
             0xC8 => Opcode::Ret(Cond::Z),
             0xC9 => Opcode::Ret(Cond::Always),
-            0xCA => Opcode::Jp(Cond::Z, Op16::Immediate(cpu.next_u16())),
-            0xCB => return Opcode::decode_cb(cpu),
-            0xCC => Opcode::Call(Cond::Z, Op16::Immediate(cpu.next_u16())),
-            0xCD => Opcode::Call(Cond::Always, Op16::Immediate(cpu.next_u16())),
-            0xCE => Opcode::Adc(Op8::Register(Reg8::A), Op8::Immediate(cpu.next_u8())),
+            0xCA => Opcode::Jp(Cond::Z, Op16::Immediate(input.fetch_u16())),
+            0xCB => Opcode::decode_cb(input),
+            0xCC => Opcode::Call(Cond::Z, Op16::Immediate(input.fetch_u16())),
+            0xCD => Opcode::Call(Cond::Always, Op16::Immediate(input.fetch_u16())),
+            0xCE => Opcode::Adc(Op8::Register(Reg8::A), Op8::Immediate(input.fetch_u8())),
             0xCF => Opcode::Rst(0x08),
 
+            0xD0 => Opcode::Ret(Cond::NC),
             0xD1 => Opcode::Pop(Op16::Register(Reg16::DE)),
+            0xD2 => Opcode::Jp(Cond::NC, Op16::Immediate(input.fetch_u16())),
+            0xD3 => Opcode::Illegal(opcode),
+            0xD4 => Opcode::Call(Cond::NC, Op16::Immediate(input.fetch_u16())),
             0xD5 => Opcode::Push(Op16::Register(Reg16::DE)),
+            0xD6 => Opcode::Sub(Op8::Immediate(input.fetch_u8())),
+            0xD7 => Opcode::Rst(0x10),
+            0xD8 => Opcode::Ret(Cond::C),
             0xD9 => Opcode::Reti,
+            0xDA => Opcode::Jp(Cond::C, Op16::Immediate(input.fetch_u16())),
+            0xDB => Opcode::Illegal(opcode),
+            0xDC => Opcode::Call(Cond::C, Op16::Immediate(input.fetch_u16())),
+            0xDD => Opcode::Illegal(opcode),
+            0xDE => Opcode::Sbc(Op8::Register(Reg8::A), Op8::Immediate(input.fetch_u8())),
+            0xDF => Opcode::Rst(0x18),
 
-            0xE0 => Opcode::Ld(Op8::Memory(Addr::ZeroPage(cpu.next_u8())), Op8::Register(Reg8::A)),
+            0xE0 => Opcode::Ld(Op8::Memory(Addr::ZeroPage(input.fetch_u8())), Op8::Register(Reg8::A)),
             0xE1 => Opcode::Pop(Op16::Register(Reg16::HL)),
             0xE2 => Opcode::Ld(Op8::Memory(Addr::ZeroPageC), Op8::Register(Reg8::A)),
+            0xE3 => Opcode::Illegal(opcode),
+            0xE4 => Opcode::Illegal(opcode),
             0xE5 => Opcode::Push(Op16::Register(Reg16::HL)),
-            0xE6 => Opcode::And(Op8::Immediate(cpu.next_u8())),
+            0xE6 => Opcode::And(Op8::Immediate(input.fetch_u8())),
+            0xE7 => Opcode::Rst(0x20),
+            0xE8 => Opcode::AddSp(input.fetch_u8()),
             0xE9 => Opcode::Jp(Cond::Always, Op16::Register(Reg16::HL)),
-            0xEA => Opcode::Ld(Op8::Memory(Addr::Immediate(cpu.next_u16())), Op8::Register(Reg8::A)),
+            0xEA => Opcode::Ld(Op8::Memory(Addr::Immediate(input.fetch_u16())), Op8::Register(Reg8::A)),
+            0xEB => Opcode::Illegal(opcode),
+            0xEC => Opcode::Illegal(opcode),
+            0xED => Opcode::Illegal(opcode),
+            0xEE => Opcode::Xor(Op8::Immediate(input.fetch_u8())),
             0xEF => Opcode::Rst(0x28),
 
-            0xF0 => Opcode::Ld(Op8::Register(Reg8::A), Op8::Memory(Addr::ZeroPage(cpu.next_u8()))),
+            0xF0 => Opcode::Ld(Op8::Register(Reg8::A), Op8::Memory(Addr::ZeroPage(input.fetch_u8()))),
             0xF1 => Opcode::Pop(Op16::Register(Reg16::AF)),
+            0xF2 => Opcode::Ld(Op8::Register(Reg8::A), Op8::Memory(Addr::ZeroPageC)),
             0xF3 => Opcode::Di,
+            0xF4 => Opcode::Illegal(opcode),
             0xF5 => Opcode::Push(Op16::Register(Reg16::AF)),
-            0xFA => Opcode::Ld(Op8::Register(Reg8::A), Op8::Memory(Addr::Immediate(cpu.next_u16()))),
+            0xF6 => Opcode::Or(Op8::Immediate(input.fetch_u8())),
+            0xF7 => Opcode::Rst(0x30),
+            0xF8 => Opcode::LdHlSp(input.fetch_u8()),
+            0xF9 => Opcode::Ld16(Op16::Register(Reg16::SP), Op16::Register(Reg16::HL)),
+            0xFA => Opcode::Ld(Op8::Register(Reg8::A), Op8::Memory(Addr::Immediate(input.fetch_u16()))),
             0xFB => Opcode::Ei,
-            0xFE => Opcode::Cp(Op8::Immediate(cpu.next_u8())),
+            0xFC => Opcode::Illegal(opcode),
+            0xFD => Opcode::Illegal(opcode),
+            0xFE => Opcode::Cp(Op8::Immediate(input.fetch_u8())),
+            0xFF => Opcode::Rst(0x38),
+        }
+    }
 
-            _ => Opcode::Unknown(opcode as u16),
+    fn decode_cb<F: Fetch>(input: &mut F) -> Opcode {
+        let opcode = input.fetch_u8();
+        let op = match opcode & 0x07 {
+            0 => Op8::Register(Reg8::B),
+            1 => Op8::Register(Reg8::C),
+            2 => Op8::Register(Reg8::D),
+            3 => Op8::Register(Reg8::E),
+            4 => Op8::Register(Reg8::H),
+            5 => Op8::Register(Reg8::L),
+            6 => Op8::Memory(Addr::HL),
+            _ => Op8::Register(Reg8::A),
         };
-        (opcode as u16, instruction)
+        let bit = (opcode >> 3) & 0x07;
+        match opcode >> 6 {
+            0 => match bit {
+                0 => Opcode::Rlc(op),
+                1 => Opcode::Rrc(op),
+                2 => Opcode::Rl(op),
+                3 => Opcode::Rr(op),
+                4 => Opcode::Sla(op),
+                5 => Opcode::Sra(op),
+                6 => Opcode::Swap(op),
+                _ => Opcode::Srl(op),
+            },
+            1 => Opcode::Bit(bit, op),
+            2 => Opcode::Res(bit, op),
+            _ => Opcode::Set(bit, op),
+        }
     }
+}
 
-    fn decode_cb(cpu: &mut CPU) -> (u16, Opcode) {
-        let opcode = cpu.next_u8();
-        let full_opcode = 0xCB00 | opcode as u16;
-        let instruction = match opcode {
-            0x37 => Opcode::Swap(Op8::Register(Reg8::A)),
-            
-            0x87 => Opcode::Res(0, Op8::Register(Reg8::A)),
-            _ => Opcode::Unknown(full_opcode),
+/// A decoded instruction, as found at `addr`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Instruction {
+    pub addr: u16,
+    pub opcode: Opcode,
+    /// Length in bytes, including operands.
+    pub length: u16,
+    /// M-cycles taken, or for conditional jumps, calls and returns the
+    /// M-cycles taken when the branch is not.
+    pub cycles: u8,
+    /// M-cycles taken when the branch is. Same as `cycles` for everything
+    /// that doesn't branch conditionally.
+    pub cycles_taken: u8,
+}
+
+impl Instruction {
+    /// Decodes the instruction at `addr`, reading memory through `read`,
+    /// which must not have side effects, e.g. `|addr| mmu.peek(addr)`.
+    pub fn decode_at<F: Fn(u16) -> u8>(read: F, addr: u16) -> Instruction {
+        let mut input = Cursor { read, addr };
+        let byte = input.fetch_u8();
+        let opcode = Opcode::decode(&mut input, byte);
+        let (cycles, cycles_taken) = opcode.cycles();
+        Instruction {
+            addr,
+            opcode,
+            length: input.addr.wrapping_sub(addr),
+            cycles,
+            cycles_taken,
+        }
+    }
+
+    /// Decodes the instruction at the start of `bytes`, as if it was found
+    /// at `addr`. `None` if `bytes` ends before the instruction does.
+    pub fn decode(bytes: &[u8], addr: u16) -> Option<Instruction> {
+        let instruction = Instruction::decode_at(|a| {
+            bytes.get(a.wrapping_sub(addr) as usize).cloned().unwrap_or(0)
+        }, addr);
+        if instruction.length as usize <= bytes.len() {
+            Some(instruction)
+        } else {
+            None
+        }
+    }
+
+    /// Where a jump, call or restart goes, if known without running it.
+    pub fn target(&self) -> Option<u16> {
+        match self.opcode {
+            Opcode::Jp(_, Op16::Immediate(addr)) => Some(addr),
+            Opcode::Call(_, Op16::Immediate(addr)) => Some(addr),
+            Opcode::Jr(_, offset) => Some(self.next().wrapping_add(offset as i8 as u16)),
+            Opcode::Rst(addr) => Some(addr as u16),
+            _ => None,
+        }
+    }
+
+    /// Address of the instruction that follows.
+    pub fn next(&self) -> u16 {
+        self.addr.wrapping_add(self.length)
+    }
+}
+
+/// Relative jumps are written with their target, which `Opcode` alone
+/// doesn't know.
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.opcode, self.target()) {
+            (Opcode::Jr(Cond::Always, _), Some(target)) => write!(f, "jr ${:04x}", target),
+            (Opcode::Jr(cond, _), Some(target)) => write!(f, "jr {}, ${:04x}", cond, target),
+            (opcode, _) => opcode.fmt(f),
+        }
+    }
+}
+
+impl Opcode {
+    /// M-cycles taken when a conditional branch isn't and when it is taken.
+    pub fn cycles(&self) -> (u8, u8) {
+        use self::Opcode::*;
+        let cycles = match *self {
+            Ld(a, b) | Add(a, b) | Adc(a, b) | Sbc(a, b) => 1 + a.cycles() + b.cycles(),
+            Sub(a) | And(a) | Xor(a) | Or(a) | Cp(a) => 1 + a.cycles(),
+            // Read-modify-write
+            Inc(a) | Dec(a) => 1 + 2 * a.cycles(),
+            Rlc(a) | Rrc(a) | Rl(a) | Rr(a) | Sla(a) | Sra(a) | Swap(a) | Srl(a) |
+            Res(_, a) | Set(_, a) => 2 + 2 * a.cycles(),
+            Bit(_, a) => 2 + a.cycles(),
+            Ld16(Op16::Memory(_), _) => 5,
+            Ld16(_, Op16::Immediate(_)) => 3,
+            Ld16(..) | Inc16(_) | Dec16(_) | Add16(..) => 2,
+            AddSp(_) => 4,
+            LdHlSp(_) => 3,
+            Jp(_, Op16::Register(_)) => 1,
+            Jp(Cond::Always, _) => 4,
+            Jp(..) => return (3, 4),
+            Jr(Cond::Always, _) => 3,
+            Jr(..) => return (2, 3),
+            Call(Cond::Always, _) => 6,
+            Call(..) => return (3, 6),
+            Ret(Cond::Always) | Reti | Rst(_) | Push(_) => 4,
+            Ret(_) => return (2, 5),
+            Pop(_) => 3,
+            Illegal(_) | Nop | Rra | Rla | Rlca | Rrca | Stop | Daa | Cpl | Scf | Ccf |
+            Halt | Di | Ei => 1,
         };
-        (full_opcode as u16, instruction)
+        (cycles, cycles)
+    }
+}
+
+impl Op8 {
+    /// M-cycles spent on top of the opcode fetch to get at the operand.
+    fn cycles(&self) -> u8 {
+        match *self {
+            Op8::Register(_) => 0,
+            Op8::Immediate(_) => 1,
+            Op8::Memory(Addr::Immediate(_)) => 3,
+            Op8::Memory(Addr::ZeroPage(_)) => 2,
+            Op8::Memory(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Addr::HL => write!(f, "[hl]"),
+            Addr::HLD => write!(f, "[hl-]"),
+            Addr::HLI => write!(f, "[hl+]"),
+            Addr::BC => write!(f, "[bc]"),
+            Addr::DE => write!(f, "[de]"),
+            Addr::ZeroPage(offset) => write!(f, "[$ff{:02x}]", offset),
+            Addr::ZeroPageC => write!(f, "[c]"),
+            Addr::Immediate(addr) => write!(f, "[${:04x}]", addr),
+        }
+    }
+}
+
+impl fmt::Display for Op8 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Op8::Register(reg) => reg.fmt(f),
+            Op8::Immediate(value) => write!(f, "${:02x}", value),
+            Op8::Memory(addr) => addr.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for Op16 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Op16::Register(reg) => reg.fmt(f),
+            Op16::Immediate(value) => write!(f, "${:04x}", value),
+            Op16::Memory(addr) => addr.fmt(f),
+        }
+    }
+}
+
+/// Signed 8-bit offset, as in `add sp, -$02`.
+fn signed(value: u8) -> String {
+    let value = value as i8;
+    if value < 0 {
+        format!("-${:02x}", -(value as i16))
+    } else {
+        format!("${:02x}", value)
+    }
+}
+
+/// RGBDS syntax, as documented in gbz80(7).
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Opcode::*;
+        match *self {
+            Illegal(byte) => write!(f, "db ${:02x}", byte),
+            Nop => write!(f, "nop"),
+            Stop => write!(f, "stop"),
+            Halt => write!(f, "halt"),
+            Di => write!(f, "di"),
+            Ei => write!(f, "ei"),
+            Rlca => write!(f, "rlca"),
+            Rrca => write!(f, "rrca"),
+            Rla => write!(f, "rla"),
+            Rra => write!(f, "rra"),
+            Daa => write!(f, "daa"),
+            Cpl => write!(f, "cpl"),
+            Scf => write!(f, "scf"),
+            Ccf => write!(f, "ccf"),
+            // The high page has its own mnemonic
+            Ld(a, b @ Op8::Memory(Addr::ZeroPage(_))) |
+            Ld(a, b @ Op8::Memory(Addr::ZeroPageC)) => write!(f, "ldh {}, {}", a, b),
+            Ld(a @ Op8::Memory(Addr::ZeroPage(_)), b) |
+            Ld(a @ Op8::Memory(Addr::ZeroPageC), b) => write!(f, "ldh {}, {}", a, b),
+            Ld(a, b) => write!(f, "ld {}, {}", a, b),
+            Ld16(a, b) => write!(f, "ld {}, {}", a, b),
+            LdHlSp(offset) if (offset as i8) < 0 => write!(f, "ld hl, sp{}", signed(offset)),
+            LdHlSp(offset) => write!(f, "ld hl, sp+{}", signed(offset)),
+            Add(a, b) => write!(f, "add {}, {}", a, b),
+            Adc(a, b) => write!(f, "adc {}, {}", a, b),
+            Sbc(a, b) => write!(f, "sbc {}, {}", a, b),
+            Add16(a, b) => write!(f, "add {}, {}", a, b),
+            AddSp(offset) => write!(f, "add sp, {}", signed(offset)),
+            Sub(a) => write!(f, "sub a, {}", a),
+            And(a) => write!(f, "and a, {}", a),
+            Xor(a) => write!(f, "xor a, {}", a),
+            Or(a) => write!(f, "or a, {}", a),
+            Cp(a) => write!(f, "cp a, {}", a),
+            Inc(a) => write!(f, "inc {}", a),
+            Dec(a) => write!(f, "dec {}", a),
+            Inc16(a) => write!(f, "inc {}", a),
+            Dec16(a) => write!(f, "dec {}", a),
+            Jp(Cond::Always, a) => write!(f, "jp {}", a),
+            Jp(cond, a) => write!(f, "jp {}, {}", cond, a),
+            Jr(Cond::Always, offset) => write!(f, "jr @{:+}", offset as i8 as i16 + 2),
+            Jr(cond, offset) => write!(f, "jr {}, @{:+}", cond, offset as i8 as i16 + 2),
+            Call(Cond::Always, a) => write!(f, "call {}", a),
+            Call(cond, a) => write!(f, "call {}, {}", cond, a),
+            Ret(Cond::Always) => write!(f, "ret"),
+            Ret(cond) => write!(f, "ret {}", cond),
+            Reti => write!(f, "reti"),
+            Rst(addr) => write!(f, "rst ${:02x}", addr),
+            Push(a) => write!(f, "push {}", a),
+            Pop(a) => write!(f, "pop {}", a),
+            Rlc(a) => write!(f, "rlc {}", a),
+            Rrc(a) => write!(f, "rrc {}", a),
+            Rl(a) => write!(f, "rl {}", a),
+            Rr(a) => write!(f, "rr {}", a),
+            Sla(a) => write!(f, "sla {}", a),
+            Sra(a) => write!(f, "sra {}", a),
+            Swap(a) => write!(f, "swap {}", a),
+            Srl(a) => write!(f, "srl {}", a),
+            Bit(bit, a) => write!(f, "bit {}, {}", bit, a),
+            Res(bit, a) => write!(f, "res {}, {}", bit, a),
+            Set(bit, a) => write!(f, "set {}, {}", bit, a),
+        }
     }
 }
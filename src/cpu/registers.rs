@@ -1,11 +1,14 @@
 use std::fmt;
 
+use savestate::{State, Writer, Reader};
+use error::Result;
+
 bitflags!(
     pub struct Flags: u8 {
-        const Z = 0b1000000;
-        const N = 0b0100000;
-        const H = 0b0010000;
-        const C = 0b0001000;
+        const Z = 0b10000000;
+        const N = 0b01000000;
+        const H = 0b00100000;
+        const C = 0b00010000;
     }
 );
 
@@ -44,7 +47,7 @@ impl fmt::Display for Registers {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Reg8 {
     A,
     B,
@@ -55,7 +58,7 @@ pub enum Reg8 {
     L,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Reg16 {
     PC,
     HL,
@@ -65,6 +68,35 @@ pub enum Reg16 {
     SP,
 }
 
+impl fmt::Display for Reg8 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Reg8::A => "a",
+            Reg8::B => "b",
+            Reg8::C => "c",
+            Reg8::D => "d",
+            Reg8::E => "e",
+            Reg8::H => "h",
+            Reg8::L => "l",
+        };
+        f.write_str(name)
+    }
+}
+
+impl fmt::Display for Reg16 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Reg16::PC => "pc",
+            Reg16::HL => "hl",
+            Reg16::AF => "af",
+            Reg16::BC => "bc",
+            Reg16::DE => "de",
+            Reg16::SP => "sp",
+        };
+        f.write_str(name)
+    }
+}
+
 impl Registers {
     pub fn new() -> Registers {
         Registers {
@@ -75,9 +107,39 @@ impl Registers {
             e: 0xd8,
             pc: 0x0100,
             sp: 0xfffe,
-            f: Z|H|C,
+            f: Flags::Z | Flags::H | Flags::C,
             h: 0x01,
             l: 0x4d,
         }
     }
 }
+
+impl Default for Registers {
+    fn default() -> Registers {
+        Registers::new()
+    }
+}
+
+impl State for Registers {
+    fn save(&self, out: &mut Writer) {
+        for &value in &[self.a, self.f.bits(), self.b, self.c, self.d, self.e, self.h, self.l] {
+            out.u8(value);
+        }
+        out.u16(self.sp);
+        out.u16(self.pc);
+    }
+
+    fn load(&mut self, input: &mut Reader) -> Result<()> {
+        self.a = input.u8()?;
+        self.f = Flags::from_bits_truncate(input.u8()?);
+        self.b = input.u8()?;
+        self.c = input.u8()?;
+        self.d = input.u8()?;
+        self.e = input.u8()?;
+        self.h = input.u8()?;
+        self.l = input.u8()?;
+        self.sp = input.u16()?;
+        self.pc = input.u16()?;
+        Ok(())
+    }
+}
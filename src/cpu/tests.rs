@@ -0,0 +1,491 @@
+//! Single-step tests against the SM83 JSON test vectors from
+//! https://github.com/SingleStepTests/sm83.
+//!
+//! `tests/sm83` holds a few hand-checked vectors in the same format, which
+//! always run. The full set isn't part of the repository; to run it:
+//!
+//! ```text
+//! git clone https://github.com/SingleStepTests/sm83
+//! GBM_SM83_TESTS=sm83/v1 cargo test --lib -- --ignored sm83
+//! ```
+//!
+//! and note the commit of the vectors along with the result.
+
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use cpu::{CPU, Cond, Event, Ime};
+use cpu::opcodes::{Instruction, Opcode};
+use cpu::registers::Flags;
+use error::Error;
+use irq::Interrupt;
+use mmu::{Bus, Master};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Access {
+    Idle,
+    Read(u16, u8),
+    Write(u16, u8),
+}
+
+/// Flat 64 KiB of RAM that records what happened on every M-cycle.
+struct TestBus {
+    memory: Vec<u8>,
+    log: RefCell<Vec<Access>>,
+    interrupt: Option<Interrupt>,
+}
+
+impl TestBus {
+    fn new() -> TestBus {
+        TestBus {
+            memory: vec!(0; 0x10000),
+            log: RefCell::new(vec!()),
+            interrupt: None,
+        }
+    }
+
+    fn record(&self, access: Access) {
+        let mut log = self.log.borrow_mut();
+        match log.last_mut() {
+            Some(last) if *last == Access::Idle => *last = access,
+            // An access without a cycle of its own shows up as an extra entry
+            _ => log.push(access),
+        }
+    }
+}
+
+impl Bus for TestBus {
+    fn read(&self, addr: u16) -> u8 {
+        let value = self.memory[addr as usize];
+        self.record(Access::Read(addr, value));
+        value
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        self.memory[addr as usize] = value;
+        self.record(Access::Write(addr, value));
+    }
+}
+
+impl Master for TestBus {
+    fn cycle(&mut self) {
+        self.log.borrow_mut().push(Access::Idle);
+    }
+
+    // The vectors don't exercise interrupt dispatch, only the timing tests do
+    fn has_interrupt(&mut self) -> bool {
+        self.interrupt.is_some()
+    }
+
+    fn ack_interrupt(&mut self) -> Option<Interrupt> {
+        self.interrupt.take()
+    }
+}
+
+fn field(state: &Value, name: &str) -> u16 {
+    state[name].as_u64().unwrap_or_else(|| panic!("Missing field {}", name)) as u16
+}
+
+fn ram(state: &Value) -> Vec<(u16, u8)> {
+    state["ram"].as_array().unwrap().iter()
+        .map(|entry| (entry[0].as_u64().unwrap() as u16, entry[1].as_u64().unwrap() as u8))
+        .collect()
+}
+
+fn cycles(test: &Value) -> Vec<Access> {
+    test["cycles"].as_array().unwrap().iter()
+        .map(|cycle| {
+            let kind = cycle[2].as_str().unwrap_or("---");
+            let addr = cycle[0].as_u64().unwrap_or(0) as u16;
+            let value = cycle[1].as_u64().unwrap_or(0) as u8;
+            if kind.starts_with('r') {
+                Access::Read(addr, value)
+            } else if kind.contains('w') {
+                Access::Write(addr, value)
+            } else {
+                Access::Idle
+            }
+        })
+        .collect()
+}
+
+fn setup(cpu: &mut CPU<TestBus>, state: &Value) {
+    for (addr, value) in ram(state) {
+        cpu.bus.memory[addr as usize] = value;
+    }
+    cpu.bus.log.borrow_mut().clear();
+    cpu.halted = false;
+    cpu.halt_bug = false;
+    cpu.locked = false;
+    cpu.regs.a = field(state, "a") as u8;
+    cpu.regs.b = field(state, "b") as u8;
+    cpu.regs.c = field(state, "c") as u8;
+    cpu.regs.d = field(state, "d") as u8;
+    cpu.regs.e = field(state, "e") as u8;
+    cpu.regs.f = Flags::from_bits_truncate(field(state, "f") as u8);
+    cpu.regs.h = field(state, "h") as u8;
+    cpu.regs.l = field(state, "l") as u8;
+    cpu.regs.pc = field(state, "pc");
+    cpu.regs.sp = field(state, "sp");
+    cpu.ime = if field(state, "ime") != 0 { Ime::Enabled } else { Ime::Disabled };
+}
+
+/// Describes how the CPU state differs from `state`, if it does.
+fn compare(cpu: &CPU<TestBus>, state: &Value) -> Vec<String> {
+    let ime = match cpu.ime {
+        Ime::Enabled => 1,
+        // An EI still waiting to take effect only counts if the vectors
+        // don't track it separately
+        Ime::Enabling if state.get("ei").is_none() => 1,
+        _ => 0,
+    };
+    let registers = [
+        ("a", cpu.regs.a as u16), ("b", cpu.regs.b as u16), ("c", cpu.regs.c as u16),
+        ("d", cpu.regs.d as u16), ("e", cpu.regs.e as u16), ("f", cpu.regs.f.bits() as u16),
+        ("h", cpu.regs.h as u16), ("l", cpu.regs.l as u16), ("pc", cpu.regs.pc),
+        ("sp", cpu.regs.sp), ("ime", ime),
+    ];
+    let mut diffs: Vec<String> = registers.iter()
+        .filter(|&&(name, value)| field(state, name) != value)
+        .map(|&(name, value)| format!("{} is 0x{:x}, expected 0x{:x}", name, value, field(state, name)))
+        .collect();
+    for (addr, expected) in ram(state) {
+        let value = cpu.bus.memory[addr as usize];
+        if value != expected {
+            diffs.push(format!("[0x{:04x}] is 0x{:02x}, expected 0x{:02x}", addr, value, expected));
+        }
+    }
+    diffs
+}
+
+/// Runs every vector in `path`, returning a description of each failure.
+fn run_file(path: &Path, cpu: &mut CPU<TestBus>) -> Vec<String> {
+    let text = fs::read_to_string(path).unwrap();
+    let tests: Value = serde_json::from_str(&text).unwrap();
+    let mut failures = vec!();
+    for test in tests.as_array().unwrap() {
+        let initial = &test["initial"];
+        setup(cpu, initial);
+        cpu.step().unwrap();
+        let mut diffs = compare(cpu, &test["final"]);
+        let log = cpu.bus.log.borrow().clone();
+        let expected = cycles(test);
+        if log != expected {
+            diffs.push(format!("bus activity was {:?}, expected {:?}", log, expected));
+        }
+        if !diffs.is_empty() {
+            failures.push(format!("{}: {}", test["name"].as_str().unwrap_or("?"), diffs.join(", ")));
+        }
+
+        // Leave the bus zeroed for the next vector
+        for (addr, _) in ram(initial) {
+            cpu.bus.memory[addr as usize] = 0;
+        }
+        for access in log {
+            if let Access::Write(addr, _) = access {
+                cpu.bus.memory[addr as usize] = 0;
+            }
+        }
+    }
+    failures
+}
+
+/// Runs every `.json` file in `dir`, failing with the opcodes that didn't
+/// pass.
+fn run_dir(dir: &Path) {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("Can't read {}: {}", dir.display(), e))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect();
+    files.sort();
+    assert!(!files.is_empty(), "No vectors in {}", dir.display());
+
+    let mut cpu = CPU::new(TestBus::new());
+    let mut failed = vec!();
+    for path in &files {
+        let failures = run_file(path, &mut cpu);
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        if failures.is_empty() {
+            continue;
+        }
+        println!("FAIL {} ({} vectors)", name, failures.len());
+        for failure in failures.iter().take(3) {
+            println!("  {}", failure);
+        }
+        failed.push(name);
+    }
+    assert!(failed.is_empty(), "{} of {} opcodes failed: {}", failed.len(), files.len(), failed.join(", "));
+}
+
+#[test]
+fn sm83_checked_in() {
+    run_dir(&Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/sm83"));
+}
+
+#[test]
+#[ignore = "needs the SM83 vectors, see the module docs"]
+fn sm83_single_step() {
+    let dir = env::var_os("GBM_SM83_TESTS").expect("Point GBM_SM83_TESTS at the SM83 vectors");
+    run_dir(Path::new(&dir));
+}
+
+/// The condition an instruction branches on, if it branches at all.
+fn branch(opcode: Opcode) -> Option<Cond> {
+    match opcode {
+        Opcode::Jp(cond, _) | Opcode::Jr(cond, _) | Opcode::Call(cond, _) | Opcode::Ret(cond) => Some(cond),
+        Opcode::Reti | Opcode::Rst(_) => Some(Cond::Always),
+        _ => None,
+    }
+}
+
+/// Runs `bytes` from 0xC000 with all flags clear, so NZ and NC branches are
+/// taken and Z and C ones aren't.
+fn check_decoder(cpu: &mut CPU<TestBus>, bytes: &[u8]) -> Option<String> {
+    let instruction = Instruction::decode(bytes, 0xC000).unwrap();
+    if let Opcode::Illegal(_) | Opcode::Halt | Opcode::Stop = instruction.opcode {
+        return None;
+    }
+    cpu.bus.memory = vec!(0; 0x10000);
+    cpu.bus.memory[0xC000..0xC000 + bytes.len()].copy_from_slice(bytes);
+    cpu.bus.log.borrow_mut().clear();
+    cpu.regs.f = Flags::empty();
+    cpu.regs.pc = 0xC000;
+    cpu.regs.sp = 0xD000;
+    cpu.step().unwrap();
+
+    let taken = branch(instruction.opcode).is_some_and(|cond| cond.check(Flags::empty()));
+    let expected = if taken { instruction.cycles_taken } else { instruction.cycles };
+    let cycles = cpu.bus.log.borrow().len();
+    if cycles != expected as usize {
+        return Some(format!("{:02x?} ({}) took {} cycles, decoded as {}", bytes, instruction, cycles, expected));
+    }
+    if !taken && cpu.regs.pc != instruction.next() {
+        return Some(format!("{:02x?} ({}) is {} bytes, decoded as {}",
+                            bytes, instruction, cpu.regs.pc.wrapping_sub(0xC000), instruction.length));
+    }
+    None
+}
+
+#[test]
+fn decoder_matches_execution() {
+    let mut cpu = CPU::new(TestBus::new());
+    let mut failures = vec!();
+    for opcode in 0..=0xFF {
+        if opcode != 0xCB {
+            failures.extend(check_decoder(&mut cpu, &[opcode, 0x12, 0x34]));
+        }
+        failures.extend(check_decoder(&mut cpu, &[0xCB, opcode]));
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+/// M-cycles per opcode with branches not taken, from Blargg's instr_timing.
+/// Zero for HALT, STOP, CB and the opcodes that lock up.
+const INSTR_TIMING: [u8; 256] = [
+    1, 3, 2, 2, 1, 1, 2, 1, 5, 2, 2, 2, 1, 1, 2, 1,
+    0, 3, 2, 2, 1, 1, 2, 1, 3, 2, 2, 2, 1, 1, 2, 1,
+    2, 3, 2, 2, 1, 1, 2, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+    2, 3, 2, 2, 3, 3, 3, 1, 2, 2, 2, 2, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    2, 2, 2, 2, 2, 2, 0, 2, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    1, 1, 1, 1, 1, 1, 2, 1, 1, 1, 1, 1, 1, 1, 2, 1,
+    2, 3, 3, 4, 3, 4, 2, 4, 2, 4, 3, 0, 3, 6, 2, 4,
+    2, 3, 3, 0, 3, 4, 2, 4, 2, 4, 3, 0, 3, 0, 2, 4,
+    3, 3, 2, 0, 0, 4, 2, 4, 4, 1, 4, 0, 0, 0, 2, 4,
+    3, 3, 2, 1, 0, 4, 2, 4, 3, 2, 4, 1, 0, 0, 2, 4,
+];
+
+/// M-cycles for the conditional branches when taken, from instr_timing.
+const TAKEN_TIMING: &[(u8, u8)] = &[
+    (0x20, 3), (0x28, 3), (0x30, 3), (0x38, 3),
+    (0xC0, 5), (0xC2, 4), (0xC4, 6), (0xC8, 5), (0xCA, 4), (0xCC, 6),
+    (0xD0, 5), (0xD2, 4), (0xD4, 6), (0xD8, 5), (0xDA, 4), (0xDC, 6),
+];
+
+/// Runs `bytes` from 0xC000 with HL at 0xD000 and SP at 0xD100, returning
+/// what happened on every M-cycle.
+fn run_once(cpu: &mut CPU<TestBus>, bytes: &[u8], flags: Flags) -> Vec<Access> {
+    cpu.bus.memory = vec!(0; 0x10000);
+    cpu.bus.memory[0xC000..0xC000 + bytes.len()].copy_from_slice(bytes);
+    cpu.bus.log.borrow_mut().clear();
+    cpu.regs.f = flags;
+    cpu.regs.pc = 0xC000;
+    cpu.regs.sp = 0xD100;
+    cpu.regs.h = 0xD0;
+    cpu.regs.l = 0x00;
+    cpu.step().unwrap();
+    cpu.bus.log.borrow().clone()
+}
+
+#[test]
+fn instructions_take_their_time() {
+    let mut cpu = CPU::new(TestBus::new());
+    let mut failures = vec!();
+    // NZ and NC branches are taken without flags, Z and C ones with both
+    for &flags in &[Flags::empty(), Flags::Z | Flags::C] {
+        for opcode in 0..=0xFF {
+            let expected = match TAKEN_TIMING.iter().find(|&&(taken, _)| taken == opcode) {
+                Some(&(_, cycles)) if branch(Instruction::decode(&[opcode, 0, 0], 0).unwrap().opcode)
+                    .is_some_and(|cond| cond.check(flags)) => cycles,
+                _ => INSTR_TIMING[opcode as usize],
+            };
+            if expected == 0 {
+                continue;
+            }
+            let cycles = run_once(&mut cpu, &[opcode, 0x12, 0x34], flags).len();
+            if cycles != expected as usize {
+                failures.push(format!("{:02x} with {:?} took {} cycles instead of {}", opcode, flags, cycles, expected));
+            }
+        }
+    }
+    for opcode in 0..=0xFF {
+        let expected = match opcode & 0x07 {
+            6 if (0x40..0x80).contains(&opcode) => 3,
+            6 => 4,
+            _ => 2,
+        };
+        let cycles = run_once(&mut cpu, &[0xCB, opcode], Flags::empty()).len();
+        if cycles != expected {
+            failures.push(format!("cb {:02x} took {} cycles instead of {}", opcode, cycles, expected));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+/// Every M-cycle of an instruction, as in Blargg's mem_timing: `p` fetches
+/// from the instruction, `r` reads and `w` writes elsewhere, `-` does
+/// neither.
+fn accesses(log: &[Access]) -> String {
+    log.iter()
+        .map(|access| match *access {
+            Access::Read(0xC000 ..= 0xC002, _) => 'p',
+            Access::Read(..) => 'r',
+            Access::Write(..) => 'w',
+            Access::Idle => '-',
+        })
+        .collect()
+}
+
+#[test]
+fn memory_accesses_land_on_their_cycle() {
+    let cases: &[(&[u8], &str)] = &[
+        (&[0x7E], "pr"),                   // LD A,(HL)
+        (&[0x77], "pw"),                   // LD (HL),A
+        (&[0x36, 0x12], "ppw"),            // LD (HL),n
+        (&[0x34], "prw"),                  // INC (HL)
+        (&[0xBE], "pr"),                   // CP (HL)
+        (&[0xF0, 0x12], "ppr"),            // LDH A,(n)
+        (&[0xE0, 0x12], "ppw"),            // LDH (n),A
+        (&[0xF2], "pr"),                   // LDH A,(C)
+        (&[0xFA, 0x12, 0x34], "pppr"),     // LD A,(nn)
+        (&[0xEA, 0x12, 0x34], "pppw"),     // LD (nn),A
+        (&[0x08, 0x12, 0x34], "pppww"),    // LD (nn),SP
+        (&[0xCB, 0x46], "ppr"),            // BIT 0,(HL)
+        (&[0xCB, 0x06], "pprw"),           // RLC (HL)
+        (&[0xCB, 0xC6], "pprw"),           // SET 0,(HL)
+        (&[0xC5], "p-ww"),                 // PUSH BC
+        (&[0xC1], "prr"),                  // POP BC
+        (&[0xCD, 0x12, 0x34], "ppp-ww"),   // CALL nn
+        (&[0xFF], "p-ww"),                 // RST 38
+        (&[0xC9], "prr-"),                 // RET
+        (&[0xC0], "p-rr-"),                // RET NZ
+        (&[0xD9], "prr-"),                 // RETI
+        (&[0xC3, 0x12, 0x34], "ppp-"),     // JP nn
+        (&[0x18, 0x12], "pp-"),            // JR e
+        (&[0xE8, 0x12], "pp--"),           // ADD SP,e
+        (&[0xF8, 0x12], "pp-"),            // LD HL,SP+e
+        (&[0xF9], "p-"),                   // LD SP,HL
+        (&[0x03], "p-"),                   // INC BC
+        (&[0x09], "p-"),                   // ADD HL,BC
+    ];
+    let mut cpu = CPU::new(TestBus::new());
+    for &(bytes, expected) in cases {
+        let log = run_once(&mut cpu, bytes, Flags::empty());
+        assert_eq!(accesses(&log), expected, "{:02x?}", bytes);
+    }
+}
+
+#[test]
+fn interrupt_dispatch_takes_five_cycles() {
+    let mut cpu = CPU::new(TestBus::new());
+    cpu.ime = Ime::Enabled;
+    cpu.bus.interrupt = Some(Interrupt::Timer);
+    let log = run_once(&mut cpu, &[0x3C], Flags::empty());
+    assert_eq!(log, [
+        Access::Read(0xC000, 0x3C),
+        Access::Idle,
+        Access::Write(0xD0FF, 0xC0),
+        Access::Write(0xD0FE, 0x00),
+        Access::Idle,
+    ]);
+    assert_eq!(cpu.regs.pc, 0x0050);
+    assert_eq!(cpu.regs.sp, 0xD0FE);
+    assert_eq!(cpu.ime, Ime::Disabled);
+}
+
+#[test]
+fn illegal_opcodes_lock_up() {
+    let mut cpu = CPU::new(TestBus::new());
+    cpu.bus.memory[0xC000] = 0xD3;
+    cpu.regs.pc = 0xC000;
+    let event = cpu.step().unwrap().unwrap();
+    assert_eq!(event, Event::Lockup { opcode: 0xD3, pc: 0xC000 });
+    assert_eq!(Error::from(event).to_string(), "Illegal opcode 0xd3 at 0xc000");
+
+    // Only the first step reports it
+    assert_eq!(cpu.step().unwrap(), None);
+    assert_eq!(cpu.regs.pc, 0xC001);
+}
+
+#[test]
+fn decoder_needs_whole_instruction() {
+    assert_eq!(Instruction::decode(&[0xC3, 0x50], 0x0100), None);
+    assert_eq!(Instruction::decode(&[0xCB], 0x0100), None);
+    assert_eq!(Instruction::decode(&[0xC3, 0x50, 0x01], 0x0100).map(|i| i.length), Some(3));
+}
+
+#[test]
+fn rgbds_mnemonics() {
+    let cases: &[(&[u8], &str)] = &[
+        (&[0x00], "nop"),
+        (&[0x10, 0x00], "stop"),
+        (&[0x08, 0x00, 0xC0], "ld [$c000], sp"),
+        (&[0x22], "ld [hl+], a"),
+        (&[0x3A], "ld a, [hl-]"),
+        (&[0x36, 0x7F], "ld [hl], $7f"),
+        (&[0xE0, 0x44], "ldh [$ff44], a"),
+        (&[0xF2], "ldh a, [c]"),
+        (&[0xFA, 0x34, 0x12], "ld a, [$1234]"),
+        (&[0xF8, 0xFE], "ld hl, sp-$02"),
+        (&[0xE8, 0x05], "add sp, $05"),
+        (&[0x09], "add hl, bc"),
+        (&[0x8E], "adc a, [hl]"),
+        (&[0xD6, 0x01], "sub a, $01"),
+        (&[0x18, 0xFE], "jr $0100"),
+        (&[0x20, 0x03], "jr nz, $0105"),
+        (&[0xC3, 0x50, 0x01], "jp $0150"),
+        (&[0xE9], "jp hl"),
+        (&[0xDC, 0x00, 0x40], "call c, $4000"),
+        (&[0xC8], "ret z"),
+        (&[0xFF], "rst $38"),
+        (&[0xF5], "push af"),
+        (&[0xCB, 0x7E], "bit 7, [hl]"),
+        (&[0xCB, 0x37], "swap a"),
+        (&[0xD3], "db $d3"),
+    ];
+    for &(bytes, text) in cases {
+        assert_eq!(Instruction::decode(bytes, 0x0100).unwrap().to_string(), text);
+    }
+    // Without its address a relative jump can only be relative
+    assert_eq!(Instruction::decode(&[0x18, 0xFE], 0x0100).unwrap().opcode.to_string(), "jr @+0");
+}
@@ -0,0 +1,99 @@
+//! Execution traces in the Gameboy Doctor format, one line per instruction:
+//!
+//! ```text
+//! A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+//! ```
+//!
+//! Each line shows the state right before the instruction at PC executes,
+//! along with the four bytes at PC. Reference traces from Gameboy Doctor
+//! are made with LY stuck at 0x90, so they only line up with ours until
+//! the first read of LY, unless `stub_ly` makes ours read the same.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use cpu::registers::Registers;
+use error::Result;
+use hooks::{HookId, Trigger};
+use mmu::MMU;
+
+/// What LY reads as in Gameboy Doctor's reference traces.
+pub const DOCTOR_LY: u8 = 0x90;
+
+/// Which instructions make it into a trace. Without any filter, all of
+/// them do.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Filter {
+    /// First and last PC to trace, inclusive
+    pub pc: Option<(u16, u16)>,
+    /// Only trace code running from this ROM bank
+    pub bank: Option<usize>,
+}
+
+impl Filter {
+    fn matches(&self, pc: u16, bank: Option<usize>) -> bool {
+        let pc_matches = match self.pc {
+            Some((start, end)) => start <= pc && pc <= end,
+            None => true,
+        };
+        let bank_matches = match self.bank {
+            Some(wanted) => bank == Some(wanted),
+            None => true,
+        };
+        pc_matches && bank_matches
+    }
+}
+
+pub struct Trace {
+    out: Box<dyn Write>,
+    filter: Filter,
+    /// First write that failed. Tracing stops there.
+    error: Option<io::Error>,
+}
+
+impl Trace {
+    pub fn new<W: Write + 'static>(out: W, filter: Filter) -> Trace {
+        Trace {
+            out: Box::new(out),
+            filter,
+            error: None,
+        }
+    }
+
+    pub fn create(filename: &str, filter: Filter) -> Result<Trace> {
+        Ok(Trace::new(BufWriter::new(File::create(filename)?), filter))
+    }
+
+    pub(super) fn wants(&self, pc: u16, bank: Option<usize>) -> bool {
+        self.error.is_none() && self.filter.matches(pc, bank)
+    }
+
+    pub(super) fn record(&mut self, regs: &Registers, memory: [u8; 4]) {
+        if let Err(e) = writeln!(self.out, "{}", line(regs, memory)) {
+            self.error = Some(e);
+        }
+    }
+
+    /// Flushes the trace, reporting the first write that failed.
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.out.flush(),
+        }
+    }
+}
+
+/// Makes the CPU read LY as `DOCTOR_LY`, so a trace can be compared with
+/// Gameboy Doctor's past the first wait for VBlank. Only reads change: the
+/// display keeps its own timing and `peek` still shows the real line.
+/// Returns the hook, to remove it again.
+pub fn stub_ly(mmu: &mut MMU) -> HookId {
+    mmu.add_hook(Trigger::READ, 0xFF44 ..= 0xFF44, |_| Some(DOCTOR_LY))
+}
+
+/// A single line of trace, without the newline.
+pub fn line(regs: &Registers, memory: [u8; 4]) -> String {
+    format!("A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            regs.a, regs.f.bits(), regs.b, regs.c, regs.d, regs.e, regs.h, regs.l, regs.sp, regs.pc,
+            memory[0], memory[1], memory[2], memory[3])
+}
@@ -0,0 +1,546 @@
+//! Interactive debugger: breakpoints, watchpoints, stepping and inspection,
+//! driven by text commands so any front-end can feed it lines.
+//!
+//! Addresses and values are hexadecimal, with or without a `0x` or `$`
+//! prefix; counts are decimal. ROM addresses are shown as `bank:addr`.
+
+use std::fmt;
+use std::fmt::Write;
+
+use cheats::Cheat;
+use cpu::Event;
+use cpu::opcodes::Instruction;
+use cpu::registers::Flags;
+use gameboy::GameBoy;
+use mmu::{Bus, Access, Watchpoint, WatchHit};
+
+pub const HELP: &str = "\
+Commands:
+    s, step [n]             Execute n instructions, 1 by default
+    n, next                 Step over calls and RSTs
+    finish                  Run until the current function returns
+    c, continue             Run until a breakpoint or watchpoint hits
+    frame [n]               Run n frames, 1 by default, or until a breakpoint
+                            or watchpoint hits
+    b, break [bank:]addr    Break when PC reaches addr, in ROM bank if given
+    watch addr              Stop after a write to addr
+    rwatch addr             Stop after a read from addr, instruction fetches
+                            included
+    awatch addr             Stop after any access to addr, instruction
+                            fetches included
+    i, info                 List breakpoints and watchpoints
+    d, delete [n]           Delete point n, or all of them
+    r, regs                 Show the registers
+    set reg value           Change a register, e.g. set hl c000
+    x addr [n]              Dump n bytes of memory, 64 by default
+    write addr byte...      Write bytes to memory as the CPU would
+    l, list [addr] [n]      Disassemble n instructions, around PC by default
+    cheat [code]            Add a Game Genie or GameShark code, or list them
+    cheat on|off n          Enable or disable cheat n
+    q, quit                 Leave the debugger
+An empty line repeats the last command.";
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Breakpoint {
+    pub addr: u16,
+    /// ROM bank the address has to be mapped from, or any bank.
+    pub bank: Option<usize>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Point {
+    Break(Breakpoint),
+    Watch(Watchpoint),
+}
+
+/// Why execution stopped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    Done,
+    Breakpoint(u16),
+    Watchpoint(WatchHit),
+    Lockup(Event),
+    /// The emulator couldn't go on, with the error it gave.
+    Error(String),
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Stop::Done => Ok(()),
+            Stop::Breakpoint(addr) => write!(f, "Breakpoint hit at 0x{:04x}", addr),
+            Stop::Watchpoint(hit) => {
+                let access = if hit.write { "Write" } else { "Read" };
+                write!(f, "{} of 0x{:02x} at 0x{:04x}", access, hit.value, hit.addr)
+            },
+            Stop::Lockup(event) => write!(f, "{}", event),
+            Stop::Error(ref message) => write!(f, "{}", message),
+        }
+    }
+}
+
+pub enum Output {
+    Text(String),
+    Quit,
+}
+
+pub struct Debugger {
+    points: Vec<Point>,
+    last: String,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            points: vec!(),
+            last: String::new(),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.points.push(Point::Break(breakpoint));
+    }
+
+    pub fn add_watchpoint(&mut self, gameboy: &mut GameBoy, watchpoint: Watchpoint) {
+        self.points.push(Point::Watch(watchpoint));
+        self.sync_watchpoints(gameboy);
+    }
+
+    /// Removes breakpoints at `addr` in any bank. Returns whether there
+    /// were any.
+    pub fn remove_breakpoint(&mut self, addr: u16) -> bool {
+        let count = self.points.len();
+        self.points.retain(|point| match *point {
+            Point::Break(b) => b.addr != addr,
+            Point::Watch(_) => true,
+        });
+        self.points.len() != count
+    }
+
+    pub fn remove_watchpoint(&mut self, gameboy: &mut GameBoy, watchpoint: Watchpoint) -> bool {
+        let count = self.points.len();
+        self.points.retain(|&point| point != Point::Watch(watchpoint));
+        self.sync_watchpoints(gameboy);
+        self.points.len() != count
+    }
+
+    fn sync_watchpoints(&self, gameboy: &mut GameBoy) {
+        let watchpoints = self.points.iter()
+            .filter_map(|point| match *point {
+                Point::Watch(watchpoint) => Some(watchpoint),
+                Point::Break(_) => None,
+            })
+            .collect();
+        gameboy.mmu_mut().set_watchpoints(watchpoints);
+    }
+
+    /// Executes one instruction, reporting a watchpoint, lock-up or error
+    /// on the way.
+    pub fn step(&mut self, gameboy: &mut GameBoy) -> Option<Stop> {
+        let event = match gameboy.step() {
+            Ok(event) => event,
+            Err(e) => return Some(Stop::Error(e.to_string())),
+        };
+        if let Some(hit) = gameboy.mmu_mut().take_watch_hit() {
+            return Some(Stop::Watchpoint(hit));
+        }
+        event.map(Stop::Lockup)
+    }
+
+    /// Runs until a breakpoint, watchpoint or lock-up, or until `done`
+    /// returns true. `done` gets the opcode that was just executed.
+    pub fn run<F: FnMut(&GameBoy, u8) -> bool>(&mut self, gameboy: &mut GameBoy, mut done: F) -> Stop {
+        loop {
+            let opcode = gameboy.mmu().peek(gameboy.cpu().registers().pc);
+            if let Some(stop) = self.step(gameboy) {
+                return stop;
+            }
+            if done(gameboy, opcode) {
+                return Stop::Done;
+            }
+            if let Some(addr) = self.breakpoint(gameboy) {
+                return Stop::Breakpoint(addr);
+            }
+        }
+    }
+
+    fn breakpoint(&self, gameboy: &GameBoy) -> Option<u16> {
+        if gameboy.cpu().is_halted() {
+            return None;
+        }
+        let pc = gameboy.cpu().registers().pc;
+        let hit = self.points.iter().any(|point| match *point {
+            Point::Break(b) => b.addr == pc && (b.bank.is_none() || b.bank == bank(gameboy, pc)),
+            Point::Watch(_) => false,
+        });
+        if hit {
+            Some(pc)
+        } else {
+            None
+        }
+    }
+
+    /// Runs one command line and returns what to show.
+    pub fn execute(&mut self, gameboy: &mut GameBoy, line: &str) -> Output {
+        let line = if line.trim().is_empty() {
+            self.last.clone()
+        } else {
+            line.trim().to_string()
+        };
+        self.last = line.clone();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (command, args) = match words.split_first() {
+            Some((command, args)) => (*command, args),
+            None => return Output::Text(String::new()),
+        };
+        let text = match command {
+            "s" | "step" => match count(args.first(), 1) {
+                Some(n) => {
+                    let mut steps = 0;
+                    let stop = self.run(gameboy, |_, _| {
+                        steps += 1;
+                        steps >= n
+                    });
+                    self.stopped(gameboy, stop)
+                },
+                None => "step takes a count".to_string(),
+            },
+            "n" | "next" => {
+                let stop = self.next(gameboy);
+                self.stopped(gameboy, stop)
+            },
+            "finish" => {
+                let sp = gameboy.cpu().registers().sp;
+                let stop = self.run(gameboy, |gameboy, opcode| {
+                    is_return(opcode) && gameboy.cpu().registers().sp > sp
+                });
+                self.stopped(gameboy, stop)
+            },
+            "c" | "continue" => {
+                let stop = self.run(gameboy, |_, _| false);
+                self.stopped(gameboy, stop)
+            },
+            "frame" => match count(args.first(), 1) {
+                Some(n) => {
+                    let target = gameboy.frames() + n;
+                    let stop = self.run(gameboy, |gameboy, _| gameboy.frames() >= target);
+                    self.stopped(gameboy, stop)
+                },
+                None => "frame takes a count".to_string(),
+            },
+            "b" | "break" => match args.first().and_then(|arg| parse_breakpoint(arg)) {
+                Some(breakpoint) => {
+                    self.add_breakpoint(breakpoint);
+                    format!("Point {}: {}", self.points.len(), describe(&Point::Break(breakpoint)))
+                },
+                None => "break needs an address".to_string(),
+            },
+            "watch" | "rwatch" | "awatch" => match args.first().and_then(|arg| parse_address(arg)) {
+                Some(addr) => {
+                    let access = match command {
+                        "watch" => Access::Write,
+                        "rwatch" => Access::Read,
+                        _ => Access::Any,
+                    };
+                    let watchpoint = Watchpoint { addr, access };
+                    self.add_watchpoint(gameboy, watchpoint);
+                    format!("Point {}: {}", self.points.len(), describe(&Point::Watch(watchpoint)))
+                },
+                None => format!("{} needs an address", command),
+            },
+            "i" | "info" => {
+                let mut out = String::new();
+                for (i, point) in self.points.iter().enumerate() {
+                    let _ = writeln!(out, "{}: {}", i + 1, describe(point));
+                }
+                if out.is_empty() {
+                    "No breakpoints or watchpoints".to_string()
+                } else {
+                    out.trim_end().to_string()
+                }
+            },
+            "d" | "delete" => match args.first() {
+                None => {
+                    self.points.clear();
+                    self.sync_watchpoints(gameboy);
+                    "Deleted all points".to_string()
+                },
+                Some(arg) => match arg.parse::<usize>() {
+                    Ok(n) if n >= 1 && n <= self.points.len() => {
+                        self.points.remove(n - 1);
+                        self.sync_watchpoints(gameboy);
+                        format!("Deleted point {}", n)
+                    },
+                    _ => format!("No point {}", arg),
+                },
+            },
+            "r" | "regs" => format!("{}", gameboy.cpu().registers()),
+            "set" => match args {
+                [register, value] if set_register(gameboy, register, value) => {
+                    format!("{}", gameboy.cpu().registers())
+                },
+                [register, value] => format!("Can't set {} to {}", register, value),
+                _ => "set takes a register and a value".to_string(),
+            },
+            "x" => match (args.first().and_then(|arg| parse_address(arg)), count(args.get(1), 64)) {
+                (Some(addr), Some(n)) => dump(gameboy, addr, n),
+                _ => "x takes an address and optionally a count".to_string(),
+            },
+            "write" => {
+                let values: Option<Vec<u16>> = args.iter().map(|arg| parse_address(arg)).collect();
+                match values {
+                    Some(ref values) if values.len() >= 2 && values[1..].iter().all(|&v| v <= 0xFF) => {
+                        for (i, &value) in values[1..].iter().enumerate() {
+                            gameboy.mmu_mut().poke(values[0].wrapping_add(i as u16), value as u8);
+                        }
+                        dump(gameboy, values[0], values.len() as u64 - 1)
+                    },
+                    _ => "write takes an address and one or more bytes".to_string(),
+                }
+            },
+            "l" | "list" => match args.first().map(|arg| parse_address(arg)) {
+                None => list_around(gameboy),
+                Some(Some(addr)) => match count(args.get(1), 10) {
+                    Some(n) => list(gameboy, addr, n as usize),
+                    None => "list takes an address and optionally a count".to_string(),
+                },
+                Some(None) => "list takes an address and optionally a count".to_string(),
+            },
+            "cheat" => match args {
+                [] => list_cheats(gameboy),
+                [code] => match Cheat::new(code) {
+                    Ok(cheat) => {
+                        let description = cheat.code.to_string();
+                        let mut cheats = gameboy.cheats().clone();
+                        let index = cheats.add(cheat);
+                        gameboy.set_cheats(cheats);
+                        format!("Cheat {}: {}", index + 1, description)
+                    },
+                    Err(e) => e.to_string(),
+                },
+                [state, n] if *state == "on" || *state == "off" => match n.parse::<usize>() {
+                    Ok(n) if n >= 1 && gameboy.set_cheat_enabled(n - 1, *state == "on") =>
+                        format!("Cheat {} {}", n, state),
+                    _ => format!("No cheat {}", n),
+                },
+                _ => "cheat takes a code, or on or off and a number".to_string(),
+            },
+            "q" | "quit" => return Output::Quit,
+            "h" | "help" => HELP.to_string(),
+            _ => format!("Unknown command {}, try help", command),
+        };
+        Output::Text(text)
+    }
+
+    /// Steps over a call or RST by running until it returns.
+    fn next(&mut self, gameboy: &mut GameBoy) -> Stop {
+        let pc = gameboy.cpu().registers().pc;
+        let sp = gameboy.cpu().registers().sp;
+        let opcode = gameboy.mmu().peek(pc);
+        if !is_call(opcode) {
+            return self.run(gameboy, |_, _| true);
+        }
+        let target = decode(gameboy, pc).next();
+        self.run(gameboy, |gameboy, _| {
+            let regs = gameboy.cpu().registers();
+            regs.pc == target && regs.sp >= sp
+        })
+    }
+
+    fn stopped(&self, gameboy: &GameBoy, stop: Stop) -> String {
+        let pc = gameboy.cpu().registers().pc;
+        match stop {
+            Stop::Done => line(gameboy, pc, true),
+            _ => format!("{}\n{}", stop, line(gameboy, pc, true)),
+        }
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
+
+fn is_call(opcode: u8) -> bool {
+    match opcode {
+        0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC => true,
+        _ => opcode & 0xC7 == 0xC7, // RST
+    }
+}
+
+fn is_return(opcode: u8) -> bool {
+    matches!(opcode, 0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9)
+}
+
+fn bank(gameboy: &GameBoy, addr: u16) -> Option<usize> {
+    if addr < 0x8000 {
+        Some(gameboy.mmu().cartridge().rom_bank(addr))
+    } else {
+        None
+    }
+}
+
+fn location(gameboy: &GameBoy, addr: u16) -> String {
+    match bank(gameboy, addr) {
+        Some(bank) => format!("{:02x}:{:04x}", bank, addr),
+        None => format!("--:{:04x}", addr),
+    }
+}
+
+fn list_cheats(gameboy: &GameBoy) -> String {
+    let mut out = String::new();
+    for (i, cheat) in gameboy.cheats().iter().enumerate() {
+        let state = if cheat.enabled { "on" } else { "off" };
+        let _ = writeln!(out, "{}: {} {:<3} {}  {}", i + 1, cheat.text, state, cheat.code, cheat.name);
+    }
+    if out.is_empty() {
+        "No cheats".to_string()
+    } else {
+        out.lines().map(str::trim_end).collect::<Vec<_>>().join("\n")
+    }
+}
+
+fn describe(point: &Point) -> String {
+    match *point {
+        Point::Break(Breakpoint { addr, bank: Some(bank) }) => format!("break at {:02x}:{:04x}", bank, addr),
+        Point::Break(Breakpoint { addr, bank: None }) => format!("break at {:04x}", addr),
+        Point::Watch(Watchpoint { addr, access }) => {
+            let access = match access {
+                Access::Read => "reads",
+                Access::Write => "writes",
+                Access::Any => "accesses",
+            };
+            format!("watch {} of {:04x}", access, addr)
+        },
+    }
+}
+
+fn decode(gameboy: &GameBoy, addr: u16) -> Instruction {
+    Instruction::decode_at(|addr| gameboy.mmu().peek(addr), addr)
+}
+
+fn line(gameboy: &GameBoy, addr: u16, current: bool) -> String {
+    let instruction = decode(gameboy, addr);
+    let bytes: Vec<String> = (0..instruction.length)
+        .map(|i| format!("{:02x}", gameboy.mmu().peek(addr.wrapping_add(i))))
+        .collect();
+    let marker = if current { ">" } else { " " };
+    format!("{} {}  {:<9} {}", marker, location(gameboy, addr), bytes.join(" "), instruction)
+}
+
+fn list(gameboy: &GameBoy, mut addr: u16, count: usize) -> String {
+    let pc = gameboy.cpu().registers().pc;
+    let mut lines = vec!();
+    for _ in 0..count {
+        lines.push(line(gameboy, addr, addr == pc));
+        addr = decode(gameboy, addr).next();
+    }
+    lines.join("\n")
+}
+
+/// A few instructions before PC, PC itself and a few after. Instructions
+/// can't be decoded backwards, so this looks for the furthest start within
+/// a few bytes that decodes into PC exactly.
+fn list_around(gameboy: &GameBoy) -> String {
+    let pc = gameboy.cpu().registers().pc;
+    let mut before = vec!();
+    for back in (1..=12u16).rev().filter(|&back| back <= pc) {
+        let mut addr = pc - back;
+        let mut addrs = vec!();
+        while addr < pc {
+            addrs.push(addr);
+            match addr.checked_add(decode(gameboy, addr).length) {
+                Some(next) => addr = next,
+                // Ran off the top of memory without landing on PC
+                None => break,
+            }
+        }
+        if addr == pc {
+            before = addrs;
+            break;
+        }
+    }
+    let start = before.len().saturating_sub(4);
+    let first = before.get(start).cloned().unwrap_or(pc);
+    list(gameboy, first, before.len() - start + 6)
+}
+
+/// Dumps up to the whole address space once, wrapping around at the top.
+fn dump(gameboy: &GameBoy, addr: u16, count: u64) -> String {
+    let count = count.min(0x10000);
+    let mut out = String::new();
+    for row in 0..count.div_ceil(16) {
+        let start = addr.wrapping_add((row * 16) as u16);
+        let n = (count - row * 16).min(16) as u16;
+        let bytes: Vec<String> = (0..n)
+            .map(|i| format!("{:02x}", gameboy.mmu().peek(start.wrapping_add(i))))
+            .collect();
+        let _ = writeln!(out, "{:04x}: {}", start, bytes.join(" "));
+    }
+    out.trim_end().to_string()
+}
+
+fn set_register(gameboy: &mut GameBoy, register: &str, value: &str) -> bool {
+    let value = match parse_address(value) {
+        Some(value) => value,
+        None => return false,
+    };
+    let byte = value as u8;
+    let regs = gameboy.cpu_mut().registers_mut();
+    match register.to_lowercase().as_str() {
+        "a" if value <= 0xFF => regs.a = byte,
+        "f" if value <= 0xFF => regs.f = Flags::from_bits_truncate(byte),
+        "b" if value <= 0xFF => regs.b = byte,
+        "c" if value <= 0xFF => regs.c = byte,
+        "d" if value <= 0xFF => regs.d = byte,
+        "e" if value <= 0xFF => regs.e = byte,
+        "h" if value <= 0xFF => regs.h = byte,
+        "l" if value <= 0xFF => regs.l = byte,
+        "af" => {
+            regs.a = (value >> 8) as u8;
+            regs.f = Flags::from_bits_truncate(byte);
+        },
+        "bc" => {
+            regs.b = (value >> 8) as u8;
+            regs.c = byte;
+        },
+        "de" => {
+            regs.d = (value >> 8) as u8;
+            regs.e = byte;
+        },
+        "hl" => {
+            regs.h = (value >> 8) as u8;
+            regs.l = byte;
+        },
+        "sp" => regs.sp = value,
+        "pc" => regs.pc = value,
+        _ => return false,
+    }
+    true
+}
+
+/// Parses an address written as `0x1234`, `$1234` or plain hex.
+pub fn parse_address(value: &str) -> Option<u16> {
+    let digits = value.trim_start_matches("0x").trim_start_matches('$');
+    u16::from_str_radix(digits, 16).ok()
+}
+
+fn count(value: Option<&&str>, default: u64) -> Option<u64> {
+    match value {
+        Some(value) => value.parse().ok(),
+        None => Some(default),
+    }
+}
+
+fn parse_breakpoint(value: &str) -> Option<Breakpoint> {
+    let mut parts = value.splitn(2, ':');
+    let first = parts.next()?;
+    match parts.next() {
+        Some(addr) => Some(Breakpoint {
+            addr: parse_address(addr)?,
+            bank: Some(usize::from_str_radix(first, 16).ok()?),
+        }),
+        None => Some(Breakpoint { addr: parse_address(first)?, bank: None }),
+    }
+}
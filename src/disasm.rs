@@ -0,0 +1,357 @@
+//! Static disassembler for whole ROMs, producing source that rgbasm (0.6 or
+//! later) assembles back into the very same bytes.
+//!
+//! Code is told apart from data by following every path the CPU can take
+//! from the entry point and the interrupt vectors. Calls and jumps from
+//! bank 0 into the switchable bank can't be followed unless the ROM has
+//! only the one, as the bank depends on what was written to the MBC. Code
+//! only reached that way, or through `jp hl`, stays data. Everything that
+//! isn't known to be code is written out as bytes, and so are instructions
+//! rgbasm would encode differently: `stop` with anything but a zero after
+//! it, and `ld a, [$ff44]` and the like, which it shortens to `ldh`.
+//!
+//! Names come from an RGBDS `.sym` file when one is given. Symbols in ROM
+//! label their address, and symbols in RAM replace the addresses of memory
+//! operands, so `ld a, [$c0a0]` reads `ld a, [wScore]`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::fs;
+
+use cpu::Cond;
+use cpu::opcodes::{Instruction, Opcode, Op8, Op16, Addr};
+use error::{Error, Result};
+
+const BANK_SIZE: usize = 0x4000;
+
+/// Where execution can start without anything jumping there: the entry
+/// point and the interrupt vectors. Vectors that are nothing but padding
+/// are skipped.
+const ENTRY_POINTS: [u16; 6] = [0x0100, 0x0040, 0x0048, 0x0050, 0x0058, 0x0060];
+
+/// Fewer identical bytes than this in a row are written with `db`.
+const MIN_RUN: usize = 16;
+
+/// Names from an RGBDS `.sym` file, by bank and address.
+#[derive(Debug, Default)]
+pub struct Symbols {
+    names: BTreeMap<(usize, u16), String>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols { names: BTreeMap::new() }
+    }
+
+    pub fn open(filename: &str) -> Result<Symbols> {
+        Symbols::parse(&fs::read_to_string(filename)?)
+    }
+
+    /// Parses lines of the form `bank:addr name`, both in hex. Everything
+    /// after a `;` is a comment.
+    pub fn parse(text: &str) -> Result<Symbols> {
+        let mut symbols = Symbols::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let bad = || Error::BadSymbols(format!("line {}: {}", number + 1, line));
+            let mut words = line.split_whitespace();
+            let location = words.next().ok_or_else(bad)?;
+            let name = words.next().ok_or_else(bad)?;
+            let (bank, addr) = match location.find(':') {
+                Some(colon) => (&location[..colon], &location[colon + 1..]),
+                None => return Err(bad()),
+            };
+            let bank = usize::from_str_radix(bank, 16).map_err(|_| bad())?;
+            let addr = u16::from_str_radix(addr, 16).map_err(|_| bad())?;
+            symbols.insert(bank, addr, name);
+        }
+        Ok(symbols)
+    }
+
+    /// Names `bank:addr`, unless it already has a name.
+    pub fn insert(&mut self, bank: usize, addr: u16, name: &str) {
+        self.names.entry((bank, addr)).or_insert_with(|| name.to_string());
+    }
+
+    pub fn get(&self, bank: usize, addr: u16) -> Option<&str> {
+        self.names.get(&(bank, addr)).map(|name| name.as_str())
+    }
+
+    /// The closest symbol at or before `addr` in the same bank, and how far
+    /// past it `addr` is.
+    pub fn lookup(&self, bank: usize, addr: u16) -> Option<(&str, u16)> {
+        self.names.range((bank, 0) ..= (bank, addr))
+            .next_back()
+            .map(|(&(_, start), name)| (name.as_str(), addr - start))
+    }
+
+    /// A name for `addr` outside of ROM, in whatever bank. Only names that
+    /// can be defined as constants count, which rules out local labels.
+    fn ram(&self, addr: u16) -> Option<&str> {
+        self.names.iter()
+            .find(|&(&(_, a), name)| a == addr && addr >= 0x8000 && !name.contains('.'))
+            .map(|(_, name)| name.as_str())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Byte {
+    Unknown,
+    /// First byte of an instruction
+    Code,
+    /// Any other byte of an instruction
+    Operand,
+}
+
+/// Address the CPU sees `offset` in the ROM at.
+fn address(offset: usize) -> u16 {
+    if offset < BANK_SIZE {
+        offset as u16
+    } else {
+        (BANK_SIZE + offset % BANK_SIZE) as u16
+    }
+}
+
+/// Offset in the ROM of `addr` when it sits in `bank`. `bank` is ignored
+/// for bank 0.
+fn offset(bank: usize, addr: u16) -> Option<usize> {
+    match addr as usize {
+        addr if addr < BANK_SIZE => Some(addr),
+        addr if addr < 2 * BANK_SIZE && bank > 0 => Some(bank * BANK_SIZE + addr - BANK_SIZE),
+        _ => None,
+    }
+}
+
+/// Whether the CPU never falls through to the next instruction.
+fn ends_flow(opcode: Opcode) -> bool {
+    matches!(opcode, Opcode::Jp(Cond::Always, _) | Opcode::Jr(Cond::Always, _) | Opcode::Ret(Cond::Always) |
+             Opcode::Reti | Opcode::Illegal(_))
+}
+
+/// Address of the memory operand, when it's written out as one.
+fn memory_operand(opcode: Opcode) -> Option<u16> {
+    let addr = match opcode {
+        Opcode::Ld(Op8::Memory(addr), _) | Opcode::Ld(_, Op8::Memory(addr)) => addr,
+        Opcode::Ld16(Op16::Memory(addr), _) => addr,
+        _ => return None,
+    };
+    match addr {
+        Addr::Immediate(addr) => Some(addr),
+        Addr::ZeroPage(offset) => Some(0xFF00 | offset as u16),
+        _ => None,
+    }
+}
+
+pub struct Disassembler<'a> {
+    rom: &'a [u8],
+    symbols: &'a Symbols,
+    bytes: Vec<Byte>,
+    /// Offsets of every jump, call and restart target that's in the ROM
+    targets: BTreeSet<usize>,
+}
+
+impl<'a> Disassembler<'a> {
+    /// Follows the code in `rom` from its entry points.
+    pub fn new(rom: &'a [u8], symbols: &'a Symbols) -> Disassembler<'a> {
+        let mut disassembler = Disassembler {
+            rom,
+            symbols,
+            bytes: vec!(Byte::Unknown; rom.len()),
+            targets: BTreeSet::new(),
+        };
+        for &addr in ENTRY_POINTS.iter() {
+            let vector = &rom[(addr as usize).min(rom.len())..(addr as usize + 8).min(rom.len())];
+            // Unused vectors are usually left as padding
+            if addr != 0x0100 && vector.windows(2).all(|pair| pair[0] == pair[1]) {
+                continue;
+            }
+            disassembler.trace(addr as usize);
+        }
+        disassembler
+    }
+
+    /// Whether the byte at `offset` was found to be the start of an
+    /// instruction.
+    pub fn is_code(&self, offset: usize) -> bool {
+        self.bytes.get(offset) == Some(&Byte::Code)
+    }
+
+    /// The ROM offset a jump from `bank` to `addr` ends up at, if known.
+    fn resolve(&self, bank: usize, addr: u16) -> Option<usize> {
+        let bank = match bank {
+            // Without an MBC, there's only the one bank to switch to
+            0 if self.rom.len() <= 2 * BANK_SIZE => 1,
+            bank => bank,
+        };
+        offset(bank, addr).filter(|&offset| offset < self.rom.len())
+    }
+
+    fn decode(&self, offset: usize) -> Option<Instruction> {
+        let end = ((offset / BANK_SIZE + 1) * BANK_SIZE).min(self.rom.len());
+        Instruction::decode(&self.rom[offset..end], address(offset))
+    }
+
+    fn trace(&mut self, start: usize) {
+        let mut pending = vec!(start);
+        while let Some(mut offset) = pending.pop() {
+            while offset < self.rom.len() && self.bytes[offset] == Byte::Unknown {
+                let instruction = match self.decode(offset) {
+                    Some(instruction) => instruction,
+                    // Runs off the end of the bank
+                    None => break,
+                };
+                let length = instruction.length as usize;
+                if let Opcode::Illegal(_) = instruction.opcode {
+                    break;
+                }
+                if self.bytes[offset + 1..offset + length].iter().any(|&byte| byte != Byte::Unknown) {
+                    break;
+                }
+                self.bytes[offset] = Byte::Code;
+                for byte in &mut self.bytes[offset + 1..offset + length] {
+                    *byte = Byte::Operand;
+                }
+                if let Some(target) = instruction.target().and_then(|target| self.resolve(offset / BANK_SIZE, target)) {
+                    self.targets.insert(target);
+                    pending.push(target);
+                }
+                if ends_flow(instruction.opcode) {
+                    break;
+                }
+                offset += length;
+            }
+        }
+    }
+
+    /// The label at `offset`, if anything refers to it or it has a symbol.
+    fn label(&self, offset: usize) -> Option<String> {
+        if self.bytes[offset] == Byte::Operand {
+            return None;
+        }
+        let bank = offset / BANK_SIZE;
+        let addr = address(offset);
+        match self.symbols.get(bank, addr) {
+            Some(name) => Some(name.to_string()),
+            None if self.targets.contains(&offset) && self.bytes[offset] == Byte::Code =>
+                Some(format!("L{:02x}_{:04x}", bank, addr)),
+            None => None,
+        }
+    }
+
+    /// The instruction at `offset` in rgbasm syntax, with labels and
+    /// symbols in place of addresses.
+    fn instruction(&self, offset: usize, instruction: &Instruction) -> String {
+        let text = instruction.to_string();
+        match instruction.opcode {
+            // rgbasm always pads STOP with a zero
+            Opcode::Stop if self.rom[offset + 1] != 0x00 =>
+                return format!("db ${:02x}, ${:02x}", self.rom[offset], self.rom[offset + 1]),
+            Opcode::Rst(_) => return text,
+            _ => (),
+        }
+        let target = instruction.target()
+            .and_then(|target| self.resolve(offset / BANK_SIZE, target))
+            .and_then(|target| self.label(target));
+        if let Some(label) = target {
+            // The target is always written last, as $xxxx
+            return format!("{}{}", &text[..text.len() - 5], label);
+        }
+        let name = memory_operand(instruction.opcode).and_then(|addr| {
+            self.symbols.ram(addr).map(|name| (addr, name))
+        });
+        let text = match name {
+            Some((addr, name)) => text.replace(&format!("[${:04x}]", addr), &format!("[{}]", name)),
+            None => text,
+        };
+        match instruction.opcode {
+            // rgbasm turns these into the shorter LDH, so keep the bytes
+            Opcode::Ld(Op8::Memory(Addr::Immediate(0xFF00 ..= 0xFFFF)), _) |
+            Opcode::Ld(_, Op8::Memory(Addr::Immediate(0xFF00 ..= 0xFFFF))) => {
+                let bytes = &self.rom[offset..offset + 3];
+                format!("db ${:02x}, ${:02x}, ${:02x} ; {}", bytes[0], bytes[1], bytes[2], text)
+            },
+            _ => text,
+        }
+    }
+
+    /// The whole ROM as rgbasm source.
+    pub fn source(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "; Assemble with rgbasm 0.6 or later, then link with rgblink");
+        let constants: BTreeSet<(u16, &str)> = (0..self.rom.len())
+            .filter(|&offset| self.is_code(offset))
+            .filter_map(|offset| self.decode(offset).and_then(|i| memory_operand(i.opcode)))
+            .filter_map(|addr| self.symbols.ram(addr).map(|name| (addr, name)))
+            .collect();
+        if !constants.is_empty() {
+            out.push('\n');
+        }
+        for (addr, name) in constants {
+            let _ = writeln!(out, "DEF {} EQU ${:04x}", name, addr);
+        }
+        for bank in 0..self.rom.len().div_ceil(BANK_SIZE) {
+            out.push('\n');
+            if bank == 0 {
+                let _ = writeln!(out, "SECTION \"ROM Bank $000\", ROM0[$0000]");
+            } else {
+                let _ = writeln!(out, "SECTION \"ROM Bank ${:03x}\", ROMX[$4000], BANK[${:x}]", bank, bank);
+            }
+            self.bank(&mut out, bank);
+        }
+        out
+    }
+
+    fn bank(&self, out: &mut String, bank: usize) {
+        let end = ((bank + 1) * BANK_SIZE).min(self.rom.len());
+        let mut offset = bank * BANK_SIZE;
+        while offset < end {
+            if let Some(label) = self.label(offset) {
+                let _ = writeln!(out, "{}:", label);
+            }
+            if self.is_code(offset) {
+                let instruction = self.decode(offset).expect("traced code decodes");
+                let _ = writeln!(out, "    {}", self.instruction(offset, &instruction));
+                offset += instruction.length as usize;
+                continue;
+            }
+            // Data runs up to the next label or instruction
+            let mut next = offset + 1;
+            while next < end && !self.is_code(next) && self.label(next).is_none() {
+                next += 1;
+            }
+            data(out, &self.rom[offset..next]);
+            offset = next;
+        }
+    }
+}
+
+/// Writes `bytes` as `db` lines, with long runs of one value as `ds`.
+fn data(out: &mut String, bytes: &[u8]) {
+    let mut line = vec!();
+    let mut i = 0;
+    while i < bytes.len() {
+        let run = bytes[i..].iter().take_while(|&&byte| byte == bytes[i]).count();
+        if run >= MIN_RUN || line.len() == 8 {
+            db(out, &line);
+            line.clear();
+        }
+        if run >= MIN_RUN {
+            let _ = writeln!(out, "    ds {}, ${:02x}", run, bytes[i]);
+            i += run;
+        } else {
+            line.push(bytes[i]);
+            i += 1;
+        }
+    }
+    db(out, &line);
+}
+
+fn db(out: &mut String, bytes: &[u8]) {
+    if !bytes.is_empty() {
+        let bytes: Vec<String> = bytes.iter().map(|byte| format!("${:02x}", byte)).collect();
+        let _ = writeln!(out, "    db {}", bytes.join(", "));
+    }
+}
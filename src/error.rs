@@ -0,0 +1,51 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    BadRomHeader(String),
+    UnsupportedCartridgeType(u8),
+    IllegalOpcode { opcode: u8, pc: u16 },
+    BadSaveState(String),
+    BadMovie(String),
+    BadSymbols(String),
+    BadCheat(String),
+    RomMismatch { expected: u32, found: u32 },
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::BadRomHeader(ref reason) => write!(f, "Bad ROM header: {}", reason),
+            Error::UnsupportedCartridgeType(value) => write!(f, "Unsupported cartridge type 0x{:02x}", value),
+            Error::IllegalOpcode { opcode, pc } => write!(f, "Illegal opcode 0x{:02x} at 0x{:04x}", opcode, pc),
+            Error::BadSaveState(ref reason) => write!(f, "Bad save state: {}", reason),
+            Error::BadMovie(ref reason) => write!(f, "Bad movie: {}", reason),
+            Error::BadSymbols(ref reason) => write!(f, "Bad symbol file: {}", reason),
+            Error::BadCheat(ref reason) => write!(f, "Bad cheat: {}", reason),
+            Error::RomMismatch { expected, found } =>
+                write!(f, "Made for another ROM (checksum 0x{:08x}, expected 0x{:08x})", found, expected),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,178 @@
+use cartridge::Cartridge;
+use cheats::{Cheats, GameShark};
+use cpu::{CPU, Event};
+use gpu::Color;
+use joypad::Buttons;
+use mmu::MMU;
+use profile::Profile;
+use savestate::{self, State, Writer, Reader};
+use error::Result;
+
+/// A complete DMG: CPU, memory and peripherals, wired up and ready to run.
+pub struct GameBoy {
+    cpu: CPU<MMU>,
+    profile: Option<Profile>,
+    cheats: Cheats,
+    /// Enabled GameShark codes out of `cheats`
+    writes: Vec<GameShark>,
+}
+
+impl GameBoy {
+    pub fn new(cart: Cartridge) -> GameBoy {
+        GameBoy {
+            cpu: CPU::new(MMU::new(cart)),
+            profile: None,
+            cheats: Cheats::new(),
+            writes: vec!(),
+        }
+    }
+
+    /// Power-cycles the machine, keeping the cartridge and its RAM.
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.cpu.bus_mut().reset();
+    }
+
+    /// Puts the machine in the same state as right after `new`, wiping
+    /// cartridge RAM too.
+    pub fn power_on(&mut self) {
+        self.cpu.reset();
+        self.cpu.bus_mut().power_on();
+    }
+
+    /// Executes a single instruction.
+    pub fn step(&mut self) -> Result<Option<Event>> {
+        let frame = self.frames();
+        let event = match self.profile {
+            Some(ref mut profile) => profile.step(&mut self.cpu),
+            None => self.cpu.step(),
+        };
+        if self.frames() != frame {
+            for write in &self.writes {
+                self.cpu.bus_mut().poke(write.addr, write.value);
+            }
+        }
+        event
+    }
+
+    /// Runs until the PPU finishes the current frame. Returns the first
+    /// event raised along the way, if any, or stops at an error.
+    pub fn run_frame(&mut self) -> Result<Option<Event>> {
+        let frame = self.frames();
+        let mut event = None;
+        while self.frames() == frame {
+            let stepped = self.step()?;
+            event = event.or(stepped);
+        }
+        Ok(event)
+    }
+
+    /// Number of frames completed since power-on.
+    pub fn frames(&self) -> u64 {
+        self.cpu.bus().gpu().frames()
+    }
+
+    /// The last completed frame, row by row.
+    pub fn framebuffer(&self) -> &[Color] {
+        self.cpu.bus().gpu().framebuffer()
+    }
+
+    /// Turns drawing frames on or off, to skip those nobody will see.
+    /// Emulation carries on exactly the same either way.
+    pub fn set_rendering(&mut self, rendering: bool) {
+        self.cpu.bus_mut().set_rendering(rendering);
+    }
+
+    /// Takes the audio produced since the last call: interleaved stereo at
+    /// `apu::SAMPLE_RATE`.
+    pub fn drain_audio(&mut self) -> Vec<i16> {
+        self.cpu.bus_mut().drain_audio()
+    }
+
+    /// Snapshots the whole machine. See `savestate` for the format.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = Writer::new();
+        savestate::write_header(&mut out, self.checksum());
+        self.cpu.save(&mut out);
+        out.finish()
+    }
+
+    /// Restores a snapshot taken with `save_state`. States from another ROM
+    /// or a different format version are refused, and on any error the
+    /// machine is left exactly as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<()> {
+        let mut input = Reader::new(data);
+        savestate::read_header(&mut input, self.checksum())?;
+        let backup = self.save_state();
+        let result = self.cpu.load(&mut input).and_then(|_| input.finish());
+        if result.is_err() {
+            let mut input = Reader::new(&backup[savestate::HEADER_SIZE..]);
+            self.cpu.load(&mut input).expect("own state loads");
+        }
+        result
+    }
+
+    /// Starts profiling everything executed from now on, replacing any
+    /// profile already running.
+    pub fn set_profile(&mut self, mut profile: Profile) {
+        self.take_profile();
+        profile.attach(self.cpu.bus_mut());
+        self.profile = Some(profile);
+    }
+
+    /// Stops profiling and hands back the profile.
+    pub fn take_profile(&mut self) -> Option<Profile> {
+        let mut profile = self.profile.take()?;
+        profile.detach(self.cpu.bus_mut());
+        Some(profile)
+    }
+
+    pub fn cheats(&self) -> &Cheats {
+        &self.cheats
+    }
+
+    /// Replaces every cheat. Game Genie codes apply right away, GameShark
+    /// codes from the next VBlank.
+    pub fn set_cheats(&mut self, cheats: Cheats) {
+        self.cheats = cheats;
+        self.apply_cheats();
+    }
+
+    /// Returns whether there is such a cheat.
+    pub fn set_cheat_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        let found = self.cheats.set_enabled(index, enabled);
+        self.apply_cheats();
+        found
+    }
+
+    /// Sets which buttons are held down from now on.
+    pub fn set_input(&mut self, buttons: Buttons) {
+        self.cpu.bus_mut().set_buttons(buttons);
+    }
+
+    pub fn cpu(&self) -> &CPU<MMU> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU<MMU> {
+        &mut self.cpu
+    }
+
+    pub fn mmu(&self) -> &MMU {
+        self.cpu.bus()
+    }
+
+    pub fn mmu_mut(&mut self) -> &mut MMU {
+        self.cpu.bus_mut()
+    }
+
+    fn apply_cheats(&mut self) {
+        let patches = self.cheats.patches();
+        self.cpu.bus_mut().cartridge_mut().set_patches(patches);
+        self.writes = self.cheats.writes();
+    }
+
+    fn checksum(&self) -> u32 {
+        self.cpu.bus().cartridge().checksum()
+    }
+}
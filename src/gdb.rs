@@ -0,0 +1,398 @@
+//! A GDB remote serial protocol stub, so GDB and other tools that speak RSP
+//! can drive the emulator over TCP.
+//!
+//! The SM83 isn't a target GDB knows, so the stub describes its registers
+//! in `target.xml`: AF, BC, DE, HL, SP and PC, 16 bits each, in that order.
+//! Memory is read without side effects and written the way the CPU would,
+//! but without tripping watchpoints. The cartridge ROM can't be written.
+//! Software and hardware breakpoints are the same thing here, and
+//! watchpoints cover every byte of the range they're given. GDB's interrupt
+//! (Ctrl-C) stops a `continue`.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use cpu::registers::{Registers, Flags};
+use debugger::{Debugger, Breakpoint, Stop};
+use error::Result;
+use gameboy::GameBoy;
+use mmu::{Bus, Access, Watchpoint};
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gbm-rust.sm83.cpu">
+    <reg name="af" bitsize="16" type="int"/>
+    <reg name="bc" bitsize="16" type="int"/>
+    <reg name="de" bitsize="16" type="int"/>
+    <reg name="hl" bitsize="16" type="int"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+/// Instructions to run between checks for an interrupt from GDB.
+const POLL_INTERVAL: u32 = 4096;
+
+enum Reply {
+    Send(String),
+    /// Send, then hang up
+    Last(String),
+    Hangup,
+}
+
+pub struct Stub {
+    debugger: Debugger,
+    ack: bool,
+}
+
+impl Stub {
+    pub fn new() -> Stub {
+        Stub {
+            debugger: Debugger::new(),
+            ack: true,
+        }
+    }
+
+    /// Waits for a single connection on `addr`, e.g. `127.0.0.1:1234`, and
+    /// serves it until the debugger detaches.
+    pub fn listen(&mut self, gameboy: &mut GameBoy, addr: &str) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        self.serve(gameboy, stream)
+    }
+
+    /// Serves one connection until the debugger detaches, kills the target
+    /// or goes away.
+    pub fn serve(&mut self, gameboy: &mut GameBoy, mut stream: TcpStream) -> Result<()> {
+        stream.set_nodelay(true)?;
+        self.ack = true;
+        while let Some(packet) = self.receive(&mut stream)? {
+            match self.handle(gameboy, &stream, &packet) {
+                Reply::Send(reply) => self.send(&mut stream, &reply)?,
+                Reply::Last(reply) => {
+                    self.send(&mut stream, &reply)?;
+                    break;
+                },
+                Reply::Hangup => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the next packet and acknowledges it. `None` once the other
+    /// side has gone.
+    fn receive(&mut self, stream: &mut TcpStream) -> io::Result<Option<String>> {
+        loop {
+            match next_byte(stream)? {
+                None => return Ok(None),
+                Some(b'$') => (),
+                // Acks, and interrupts when there's nothing to interrupt
+                Some(_) => continue,
+            }
+            let mut data = vec!();
+            loop {
+                match next_byte(stream)? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut checksum = [0; 2];
+            stream.read_exact(&mut checksum)?;
+            let valid = std::str::from_utf8(&checksum).ok()
+                .and_then(|text| u8::from_str_radix(text, 16).ok()) == Some(sum(&data));
+            if self.ack {
+                stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    fn send(&self, stream: &mut TcpStream, data: &str) -> io::Result<()> {
+        loop {
+            write!(stream, "${}#{:02x}", data, sum(data.as_bytes()))?;
+            if !self.ack {
+                return Ok(());
+            }
+            match next_byte(stream)? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn handle(&mut self, gameboy: &mut GameBoy, stream: &TcpStream, packet: &str) -> Reply {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+        let reply = match command {
+            "?" => "S05".to_string(),
+            "g" => registers(gameboy.cpu().registers()),
+            "G" => match decode_hex(args) {
+                Some(ref bytes) if bytes.len() == 12 => {
+                    for (i, pair) in bytes.chunks(2).enumerate() {
+                        set_register(gameboy.cpu_mut().registers_mut(), i, u16::from_le_bytes([pair[0], pair[1]]));
+                    }
+                    "OK".to_string()
+                },
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(args, 16).ok().and_then(|i| register(gameboy.cpu().registers(), i)) {
+                Some(value) => hex(&value.to_le_bytes()),
+                None => "E01".to_string(),
+            },
+            "P" => {
+                let mut parts = args.splitn(2, '=');
+                let index = parts.next().and_then(|i| usize::from_str_radix(i, 16).ok());
+                let value = parts.next().and_then(decode_hex);
+                match (index, value) {
+                    (Some(index), Some(ref value)) if index < 6 && value.len() == 2 => {
+                        set_register(gameboy.cpu_mut().registers_mut(), index, u16::from_le_bytes([value[0], value[1]]));
+                        "OK".to_string()
+                    },
+                    _ => "E01".to_string(),
+                }
+            },
+            "m" => match parse_range(args) {
+                Some((addr, length)) => {
+                    let bytes: Vec<u8> = (0..length).map(|i| gameboy.mmu().peek((addr + i) as u16)).collect();
+                    hex(&bytes)
+                },
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let range = parts.next().and_then(parse_range);
+                let bytes = parts.next().and_then(decode_hex);
+                match (range, bytes) {
+                    // Writes to ROM would switch banks on the MBC instead
+                    (Some((addr, _)), _) if addr < 0x8000 => "E02".to_string(),
+                    (Some((addr, length)), Some(ref bytes)) if bytes.len() == length as usize => {
+                        for (i, &byte) in bytes.iter().enumerate() {
+                            gameboy.mmu_mut().poke((addr + i as u32) as u16, byte);
+                        }
+                        "OK".to_string()
+                    },
+                    _ => "E01".to_string(),
+                }
+            },
+            "Z" | "z" => self.point(gameboy, command == "Z", args),
+            "s" if args.is_empty() => self.step(gameboy),
+            "c" if args.is_empty() => self.resume(gameboy, stream),
+            "v" => match args {
+                "Cont?" => "vCont;c;s".to_string(),
+                _ if args.starts_with("Cont;s") => self.step(gameboy),
+                _ if args.starts_with("Cont;c") => self.resume(gameboy, stream),
+                _ => String::new(),
+            },
+            "H" => "OK".to_string(),
+            "T" => "OK".to_string(),
+            "q" => query(args),
+            "Q" if args == "StartNoAckMode" => {
+                self.ack = false;
+                "OK".to_string()
+            },
+            "D" => return Reply::Last("OK".to_string()),
+            "k" => return Reply::Hangup,
+            // Anything else isn't supported, which an empty reply says
+            _ => String::new(),
+        };
+        Reply::Send(reply)
+    }
+
+    /// Inserts or removes a breakpoint or watchpoint: `type,addr,kind`.
+    fn point(&mut self, gameboy: &mut GameBoy, insert: bool, args: &str) -> String {
+        let mut parts = args.splitn(2, ',');
+        let kind = parts.next();
+        let range = parts.next().and_then(parse_range);
+        let (addr, length) = match range {
+            Some(range) => range,
+            None => return "E01".to_string(),
+        };
+        let access = match kind {
+            Some("0") | Some("1") => {
+                let addr = addr as u16;
+                if insert {
+                    self.debugger.add_breakpoint(Breakpoint { addr, bank: None });
+                } else {
+                    self.debugger.remove_breakpoint(addr);
+                }
+                return "OK".to_string();
+            },
+            Some("2") => Access::Write,
+            Some("3") => Access::Read,
+            Some("4") => Access::Any,
+            _ => return String::new(),
+        };
+        for i in 0..length.max(1) {
+            let watchpoint = Watchpoint { addr: (addr + i) as u16, access };
+            if insert {
+                self.debugger.add_watchpoint(gameboy, watchpoint);
+            } else {
+                self.debugger.remove_watchpoint(gameboy, watchpoint);
+            }
+        }
+        "OK".to_string()
+    }
+
+    fn step(&mut self, gameboy: &mut GameBoy) -> String {
+        stop_reply(self.debugger.step(gameboy).unwrap_or(Stop::Done))
+    }
+
+    fn resume(&mut self, gameboy: &mut GameBoy, stream: &TcpStream) -> String {
+        let mut steps: u32 = 0;
+        let mut interrupted = false;
+        let stop = self.debugger.run(gameboy, |_, _| {
+            steps += 1;
+            interrupted = steps % POLL_INTERVAL == 0 && interrupt(stream);
+            interrupted
+        });
+        if interrupted {
+            "S02".to_string()
+        } else {
+            stop_reply(stop)
+        }
+    }
+}
+
+impl Default for Stub {
+    fn default() -> Stub {
+        Stub::new()
+    }
+}
+
+fn stop_reply(stop: Stop) -> String {
+    match stop {
+        Stop::Done => "S05".to_string(),
+        Stop::Breakpoint(_) => "T05swbreak:;".to_string(),
+        Stop::Watchpoint(hit) => {
+            let kind = if hit.write { "watch" } else { "rwatch" };
+            format!("T05{}:{:04x};", kind, hit.addr)
+        },
+        // SIGILL
+        Stop::Lockup(_) | Stop::Error(_) => "S04".to_string(),
+    }
+}
+
+fn query(args: &str) -> String {
+    let name = args.split(':').next().unwrap_or("");
+    match name {
+        "Supported" => "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+".to_string(),
+        "Attached" => "1".to_string(),
+        "C" => "QC1".to_string(),
+        "fThreadInfo" => "m1".to_string(),
+        "sThreadInfo" => "l".to_string(),
+        "Xfer" => {
+            // Xfer:features:read:target.xml:offset,length
+            let parts: Vec<&str> = args.splitn(5, ':').collect();
+            match (parts.get(1..4), parts.get(4).and_then(|range| parse_range(range))) {
+                (Some(["features", "read", "target.xml"]), Some((offset, length))) => {
+                    let start = (offset as usize).min(TARGET_XML.len());
+                    let end = (start + length as usize).min(TARGET_XML.len());
+                    let more = if end < TARGET_XML.len() { "m" } else { "l" };
+                    format!("{}{}", more, &TARGET_XML[start..end])
+                },
+                _ => "E00".to_string(),
+            }
+        },
+        _ => String::new(),
+    }
+}
+
+/// Whether GDB sent an interrupt since the last look, without waiting for
+/// one. A closed connection counts too.
+fn interrupt(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut byte = [0];
+    let interrupted = match (&*stream).read(&mut byte) {
+        Ok(0) => true,
+        Ok(_) => byte[0] == 0x03,
+        Err(_) => false,
+    };
+    let _ = stream.set_nonblocking(false);
+    interrupted
+}
+
+fn next_byte(stream: &mut TcpStream) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match stream.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+fn sum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 {
+        return None;
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| text.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect()
+}
+
+/// Parses `addr,length`, refusing anything past the end of the address
+/// space.
+fn parse_range(text: &str) -> Option<(u32, u32)> {
+    let mut parts = text.splitn(2, ',');
+    let addr = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let length = u32::from_str_radix(parts.next()?, 16).ok()?;
+    if addr.checked_add(length)? <= 0x10000 {
+        Some((addr, length))
+    } else {
+        None
+    }
+}
+
+fn register(regs: &Registers, index: usize) -> Option<u16> {
+    let pair = |h: u8, l: u8| ((h as u16) << 8) | l as u16;
+    match index {
+        0 => Some(pair(regs.a, regs.f.bits())),
+        1 => Some(pair(regs.b, regs.c)),
+        2 => Some(pair(regs.d, regs.e)),
+        3 => Some(pair(regs.h, regs.l)),
+        4 => Some(regs.sp),
+        5 => Some(regs.pc),
+        _ => None,
+    }
+}
+
+fn set_register(regs: &mut Registers, index: usize, value: u16) {
+    let (h, l) = ((value >> 8) as u8, value as u8);
+    match index {
+        0 => {
+            regs.a = h;
+            regs.f = Flags::from_bits_truncate(l);
+        },
+        1 => {
+            regs.b = h;
+            regs.c = l;
+        },
+        2 => {
+            regs.d = h;
+            regs.e = l;
+        },
+        3 => {
+            regs.h = h;
+            regs.l = l;
+        },
+        4 => regs.sp = value,
+        _ => regs.pc = value,
+    }
+}
+
+fn registers(regs: &Registers) -> String {
+    let bytes: Vec<u8> = (0..6).flat_map(|i| register(regs, i).unwrap_or(0).to_le_bytes().to_vec()).collect();
+    hex(&bytes)
+}
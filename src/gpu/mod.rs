@@ -1,8 +1,19 @@
+mod render;
+
 use mmu::Bus;
 use memory::Ram;
-use mmu::InterruptCycle;
 use irq::{Irq, Interrupt};
+use scheduler::{Clock, Scheduled};
+use savestate::{self, State, Writer, Reader};
+use error::Result;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+/// Length of a full frame in M-cycles.
+pub const FRAME_CYCLES: usize = 154 * 114;
 
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Color {
     White = 0,
     Light = 1,
@@ -20,6 +31,16 @@ impl Color {
             _ => White,
         }
     }
+
+    /// 8-bit grayscale value of this shade.
+    pub fn to_gray(self) -> u8 {
+        match self {
+            Color::White => 0xFF,
+            Color::Light => 0xAA,
+            Color::Dark => 0x55,
+            Color::Black => 0x00,
+        }
+    }
 }
 
 //wwxxyyzz
@@ -32,6 +53,7 @@ struct Palette {
     dark: Color,
     light: Color,
     white: Color,
+    value: u8,
 }
 
 impl Palette {
@@ -40,12 +62,22 @@ impl Palette {
             black: Color::from_u8((value >> 6) & 0x3),
             dark:  Color::from_u8((value >> 4) & 0x3),
             light: Color::from_u8((value >> 2) & 0x3),
-            white: Color::from_u8((value >> 0) & 0x3),
+            white: Color::from_u8(value & 0x3),
+            value,
+        }
+    }
+
+    fn color(&self, index: u8) -> Color {
+        match index {
+            3 => self.black,
+            2 => self.dark,
+            1 => self.light,
+            _ => self.white,
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Mode {
     VBlank,
     HBlank,
@@ -53,6 +85,26 @@ pub enum Mode {
     ReadVram,
 }
 
+impl Mode {
+    fn bits(self) -> u8 {
+        match self {
+            Mode::HBlank => 0,
+            Mode::VBlank => 1,
+            Mode::ReadOam => 2,
+            Mode::ReadVram => 3,
+        }
+    }
+
+    fn from_bits(bits: u8) -> Mode {
+        match bits & 0x03 {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::ReadOam,
+            _ => Mode::ReadVram,
+        }
+    }
+}
+
 bitflags!(
     pub struct Control: u8 {
         const BG_ON        = 0b00000001;
@@ -66,11 +118,31 @@ bitflags!(
     }
 );
 
+bitflags!(
+    pub struct Stat: u8 {
+        const COINCIDENCE  = 0b00000100;
+        const INT_HBLANK   = 0b00001000;
+        const INT_VBLANK   = 0b00010000;
+        const INT_OAM      = 0b00100000;
+        const INT_LYC      = 0b01000000;
+    }
+);
+
+/// Runs lazily: `cycles` is as of `last`, and nothing else changes before
+/// the next mode transition.
 pub struct Gpu {
+    clock: Clock,
+    last: u64,
     scroll_y: u8,
     scroll_x: u8,
     current_line: u8,
+    compare_line: u8,
     control: Control,
+    stat: Stat,
+    stat_line: bool,
+    /// A register the STAT line depends on was written, so it has to be
+    /// looked at again on the next cycle.
+    stat_stale: bool,
     mode: Mode,
     cycles: usize,
     bg_palette: Palette,
@@ -80,16 +152,27 @@ pub struct Gpu {
     oam: Ram,
     window_y: u8,
     window_x: u8,
+    window_line: u8,
+    framebuffer: Vec<Color>,
+    rendering: bool,
+    frames: u64,
 }
 
 impl Gpu {
-    pub fn new() -> Gpu {
+    pub fn new(clock: Clock) -> Gpu {
         Gpu {
+            last: clock.now(),
+            clock,
             scroll_y: 0,
             scroll_x: 0,
             current_line: 0,
-            control: Control::empty(),
-            mode: Mode::ReadVram,
+            compare_line: 0,
+            // Values left behind by the boot ROM
+            control: Control::LCD_ON | Control::BG_TILE_BASE | Control::BG_ON,
+            stat: Stat::empty(),
+            stat_line: false,
+            stat_stale: false,
+            mode: Mode::ReadOam,
             cycles: 0,
             bg_palette:   Palette::from_u8(0b11111100),
             obj0_palette: Palette::from_u8(0b11111111),
@@ -98,86 +181,273 @@ impl Gpu {
             oam: Ram::new(160),
             window_y: 0,
             window_x: 0,
+            window_line: 0,
+            framebuffer: vec!(Color::White; SCREEN_WIDTH * SCREEN_HEIGHT),
+            rendering: true,
+            frames: 0,
+        }
+    }
+
+    /// The last rendered frame, row by row.
+    pub fn framebuffer(&self) -> &[Color] {
+        &self.framebuffer
+    }
+
+    /// Number of frames completed so far.
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Turns drawing into the framebuffer on or off, to skip frames nobody
+    /// will see. Nothing the CPU can tell changes, but the framebuffer
+    /// keeps whatever was last drawn.
+    pub fn set_rendering(&mut self, rendering: bool) {
+        self.rendering = rendering;
+    }
+
+    fn set_control(&mut self, value: u8) {
+        let control = Control::from_bits_truncate(value);
+        if self.control.contains(Control::LCD_ON) && !control.contains(Control::LCD_ON) {
+            self.current_line = 0;
+            self.cycles = 0;
+            self.mode = Mode::HBlank;
+        } else if !self.control.contains(Control::LCD_ON) && control.contains(Control::LCD_ON) {
+            self.cycles = 0;
+            self.window_line = 0;
+            self.mode = Mode::ReadOam;
+        }
+        self.control = control;
+    }
+
+    fn get_stat(&self) -> u8 {
+        let coincidence = if self.current_line == self.compare_line {
+            Stat::COINCIDENCE
+        } else {
+            Stat::empty()
+        };
+        let mode = if self.control.contains(Control::LCD_ON) {
+            self.mode.bits()
+        } else {
+            0
+        };
+        0x80 | (self.stat | coincidence).bits() | mode
+    }
+
+    fn update_stat_line(&mut self, irq: &mut Irq) {
+        let line = (self.stat.contains(Stat::INT_LYC) && self.current_line == self.compare_line) ||
+            (self.stat.contains(Stat::INT_HBLANK) && self.mode == Mode::HBlank) ||
+            (self.stat.contains(Stat::INT_VBLANK) && self.mode == Mode::VBlank) ||
+            (self.stat.contains(Stat::INT_OAM) && self.mode == Mode::ReadOam);
+        // The interrupt fires on the rising edge of the combined STAT line
+        if line && !self.stat_line {
+            irq.request_interrupt(Interrupt::LcdStat);
+        }
+        self.stat_line = line;
+    }
+
+    /// Length of the current mode, or of a whole frame with the LCD off.
+    fn mode_cycles(&self) -> usize {
+        if !self.control.contains(Control::LCD_ON) {
+            return FRAME_CYCLES;
+        }
+        match self.mode {
+            Mode::ReadOam => 20,
+            Mode::ReadVram => 43,
+            Mode::HBlank => 51,
+            Mode::VBlank => 114,
+        }
+    }
+
+    fn until_transition(&self) -> usize {
+        self.mode_cycles().saturating_sub(self.cycles).max(1)
+    }
+
+    /// Moves on to the next mode if the current one is over.
+    fn transition(&mut self, irq: &mut Irq) {
+        let length = self.mode_cycles();
+        if self.cycles < length {
+            return;
         }
+        self.cycles -= length;
+
+        if !self.control.contains(Control::LCD_ON) {
+            // Keep counting frames so frame limits still work with the LCD off
+            self.frames += 1;
+            return;
+        }
+
+        match self.mode {
+            Mode::VBlank => {
+                self.current_line += 1;
+                if self.current_line > 153 {
+                    self.mode = Mode::ReadOam;
+                    self.current_line = 0;
+                    self.window_line = 0;
+                }
+            },
+            Mode::HBlank => {
+                self.current_line += 1;
+                if self.current_line == 144 {
+                    self.mode =  Mode::VBlank;
+                    self.frames += 1;
+                    irq.request_interrupt(Interrupt::VBlank);
+                } else {
+                    self.mode = Mode::ReadOam;
+                }
+            },
+            Mode::ReadOam => self.mode = Mode::ReadVram,
+            Mode::ReadVram => {
+                self.render_line();
+                self.mode = Mode::HBlank;
+            },
+        }
+    }
+}
+
+impl Default for Gpu {
+    fn default() -> Gpu {
+        Gpu::new(Clock::new())
     }
 }
 
 impl Bus for Gpu {
     fn read(&self, addr: u16) -> u8 {
         match addr {
-            0x8000 ... 0x9FFF => self.vram.read(addr & 0x1FFF),
-            0xFE00 ... 0xFE9F => self.oam.read(addr & 0xFF),
+            0x8000 ..= 0x9FFF => self.vram.read(addr & 0x1FFF),
+            0xFE00 ..= 0xFE9F => self.oam.read(addr & 0xFF),
             0xFF40 => self.control.bits(),
+            0xFF41 => self.get_stat(),
             0xFF42 => self.scroll_y,
             0xFF43 => self.scroll_x,
             0xFF44 => self.current_line,
-            _ => panic!("Not yet implemented read 0x{:04x}", addr)
+            0xFF45 => self.compare_line,
+            0xFF47 => self.bg_palette.value,
+            0xFF48 => self.obj0_palette.value,
+            0xFF49 => self.obj1_palette.value,
+            0xFF4A => self.window_y,
+            0xFF4B => self.window_x,
+            _ => 0xFF, //TODO Not yet implemented
         }
     }
 
     fn write(&mut self, addr: u16, value: u8) {
         match addr {
-            0x8000 ... 0x9FFF => self.vram.write(addr & 0x1FFF, value),
-            0xFE00 ... 0xFE9F => self.oam.write(addr & 0xFF, value),
-            0xFF40 => self.control = Control::from_bits_truncate(value),
-            0xFF41 => println!("GPU 0xFF41 = 0x{:02x} STAT WRITE NOT YET IMPLEMENTED", value),
+            0x8000 ..= 0x9FFF => self.vram.write(addr & 0x1FFF, value),
+            0xFE00 ..= 0xFE9F => self.oam.write(addr & 0xFF, value),
+            0xFF40 => self.set_control(value),
+            0xFF41 => self.stat = Stat::from_bits_truncate(value) - Stat::COINCIDENCE,
             0xFF42 => self.scroll_y = value,
             0xFF43 => self.scroll_x = value,
-            0xFF44 => self.current_line = 0,
-            0xFF46 => (), //TODO DMA
+            0xFF44 => (), // Read-only
+            0xFF45 => self.compare_line = value,
             0xFF47 => self.bg_palette = Palette::from_u8(value),
             0xFF48 => self.obj0_palette = Palette::from_u8(value),
             0xFF49 => self.obj1_palette = Palette::from_u8(value),
             0xFF4A => self.window_y = value,
             0xFF4B => self.window_x = value,
-            _ => panic!("Not yet implemented write 0x{:04x} = 0x{:02x}", addr, value)
+            _ => (), //TODO Not yet implemented
+        }
+        if let 0xFF40 | 0xFF41 | 0xFF45 = addr {
+            self.stat_stale = true;
         }
     }
 }
 
-impl InterruptCycle for Gpu {
-    fn cycle(&mut self, irq: &mut Irq) {
-        if !self.control.contains(LCD_ON) {
-            return;
+impl Scheduled for Gpu {
+    fn catch_up(&mut self, irq: &mut Irq) {
+        let mut elapsed = (self.clock.now() - self.last) as usize;
+        self.last = self.clock.now();
+        // Nothing the STAT line depends on moves between transitions, so
+        // stepping from one to the next is the same as going cycle by cycle
+        while elapsed > 0 {
+            let step = elapsed.min(self.until_transition());
+            self.cycles += step;
+            elapsed -= step;
+            self.transition(irq);
+            if self.control.contains(Control::LCD_ON) {
+                self.update_stat_line(irq);
+            }
+            self.stat_stale = false;
         }
+    }
 
-        self.cycles += 1;
+    /// The next mode transition, or the next cycle after a write to the
+    /// LCD registers.
+    fn next_event(&self) -> Option<u64> {
+        if self.stat_stale && self.control.contains(Control::LCD_ON) {
+            Some(self.last + 1)
+        } else {
+            Some(self.last + self.until_transition() as u64)
+        }
+    }
 
-        match self.mode {
-            Mode::VBlank => {
-                if self.cycles >= 114 {
-                    self.cycles -= 114;
-                    self.current_line += 1;
-                    if self.current_line > 153 {
-                        self.mode = Mode::ReadOam;
-                        self.current_line = 0;
-                    }
-                }
-            },
-            Mode::HBlank => {
-                if self.cycles >= 51 {
-                    self.cycles -= 51;
-                    self.current_line += 1;
-                    if self.current_line == 144 {
-                        self.mode =  Mode::VBlank;
-                        irq.request_interrupt(Interrupt::VBlank);
-                    } else {
-                        self.mode = Mode::ReadOam;
-                    }
-                }
-            },
-            Mode::ReadOam => {
-                if self.cycles >= 20 {
-                    self.cycles -= 20;
-                    self.mode = Mode::ReadVram;
-                }
-            },
-            Mode::ReadVram => {
-                if self.cycles >= 43 {
-                    self.cycles -= 43;
-                    self.mode = Mode::HBlank;
-                }
-            },
+    fn restart(&mut self) {
+        self.last = self.clock.now();
+        self.stat_stale = true;
+    }
+}
+
+impl State for Gpu {
+    fn save(&self, out: &mut Writer) {
+        out.u8(self.scroll_y);
+        out.u8(self.scroll_x);
+        out.u8(self.current_line);
+        out.u8(self.compare_line);
+        out.u8(self.control.bits());
+        out.u8(self.stat.bits());
+        out.bool(self.stat_line);
+        out.u8(self.mode.bits());
+        out.u32((self.cycles + (self.clock.now() - self.last) as usize) as u32);
+        out.u8(self.bg_palette.value);
+        out.u8(self.obj0_palette.value);
+        out.u8(self.obj1_palette.value);
+        self.vram.save(out);
+        self.oam.save(out);
+        out.u8(self.window_y);
+        out.u8(self.window_x);
+        out.u8(self.window_line);
+        for &color in &self.framebuffer {
+            out.u8(color as u8);
+        }
+        out.u64(self.frames);
+    }
+
+    fn load(&mut self, input: &mut Reader) -> Result<()> {
+        self.scroll_y = input.u8()?;
+        self.scroll_x = input.u8()?;
+        self.current_line = input.u8()?;
+        self.compare_line = input.u8()?;
+        self.control = Control::from_bits_truncate(input.u8()?);
+        self.stat = Stat::from_bits_truncate(input.u8()?);
+        self.stat_line = input.bool()?;
+        self.mode = Mode::from_bits(input.u8()?);
+        self.cycles = input.u32()? as usize;
+        self.bg_palette = Palette::from_u8(input.u8()?);
+        self.obj0_palette = Palette::from_u8(input.u8()?);
+        self.obj1_palette = Palette::from_u8(input.u8()?);
+        self.vram.load(input)?;
+        self.oam.load(input)?;
+        self.window_y = input.u8()?;
+        self.window_x = input.u8()?;
+        self.window_line = input.u8()?;
+        for color in self.framebuffer.iter_mut() {
+            *color = Color::from_u8(input.u8()?);
+        }
+        self.frames = input.u64()?;
+
+        // Anything else would run LY past 153 or render off the screen
+        if self.current_line > 153 {
+            return Err(savestate::bad("LY out of range"));
+        }
+        if (self.mode == Mode::VBlank) != (self.current_line >= 144) {
+            return Err(savestate::bad("LCD mode doesn't match LY"));
+        }
+        if self.cycles > self.mode_cycles() {
+            return Err(savestate::bad("LCD mode runs past its length"));
+        }
+        if self.window_line > 144 {
+            return Err(savestate::bad("window line out of range"));
         }
+        Ok(())
     }
 }
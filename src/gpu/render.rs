@@ -0,0 +1,138 @@
+use mmu::Bus;
+use gpu::{Gpu, Control, SCREEN_WIDTH};
+
+const OBJ_BG_PRIORITY: u8 = 0b10000000;
+const OBJ_Y_FLIP: u8      = 0b01000000;
+const OBJ_X_FLIP: u8      = 0b00100000;
+const OBJ_PALETTE: u8     = 0b00010000;
+
+struct Sprite {
+    y: i16,
+    x: i16,
+    tile: u8,
+    flags: u8,
+}
+
+impl Gpu {
+    /// Color index (0-3) of a pixel in a tile, addressed using the current tile base.
+    fn tile_pixel(&self, tile_addr: u16, x: u8, y: u8) -> u8 {
+        let addr = tile_addr + (y as u16) * 2;
+        let low = self.vram.read(addr & 0x1FFF);
+        let high = self.vram.read((addr + 1) & 0x1FFF);
+        let bit = 7 - x;
+        (((high >> bit) & 1) << 1) | ((low >> bit) & 1)
+    }
+
+    fn bg_tile_addr(&self, tile: u8) -> u16 {
+        if self.control.contains(Control::BG_TILE_BASE) {
+            0x8000 + (tile as u16) * 16
+        } else {
+            (0x9000i32 + (tile as i8 as i32) * 16) as u16
+        }
+    }
+
+    fn map_pixel(&self, map_base: u16, x: u8, y: u8) -> u8 {
+        let tile_index = map_base + ((y as u16) / 8) * 32 + (x as u16) / 8;
+        let tile = self.vram.read(tile_index & 0x1FFF);
+        self.tile_pixel(self.bg_tile_addr(tile), x % 8, y % 8)
+    }
+
+    fn line_sprites(&self, line: u8) -> Vec<Sprite> {
+        let height = if self.control.contains(Control::OBJ_SIZE) { 16 } else { 8 };
+        let mut sprites = vec!();
+        for i in 0..40 {
+            let y = self.oam.read(i * 4) as i16 - 16;
+            if (line as i16) < y || (line as i16) >= y + height {
+                continue;
+            }
+            sprites.push(Sprite {
+                y,
+                x: self.oam.read(i * 4 + 1) as i16 - 8,
+                tile: self.oam.read(i * 4 + 2),
+                flags: self.oam.read(i * 4 + 3),
+            });
+            // Only the first ten sprites in OAM order are drawn
+            if sprites.len() == 10 {
+                break;
+            }
+        }
+        // Lower X has priority, ties go to the earlier OAM entry (sort is stable)
+        sprites.sort_by_key(|s| s.x);
+        sprites
+    }
+
+    pub(super) fn render_line(&mut self) {
+        let line = self.current_line;
+        let mut bg_index = [0u8; SCREEN_WIDTH];
+
+        if self.control.contains(Control::BG_ON) {
+            let bg_map = if self.control.contains(Control::BG_MAP_BASE) { 0x9C00 } else { 0x9800 };
+            let wnd_map = if self.control.contains(Control::WND_MAP_BASE) { 0x9C00 } else { 0x9800 };
+            let window = self.control.contains(Control::WND_ON) &&
+                line >= self.window_y && self.window_x <= 166;
+            if !self.rendering {
+                // The window line is machine state, so it still counts
+                if window {
+                    self.window_line += 1;
+                }
+                return;
+            }
+            let window_start = self.window_x as i16 - 7;
+
+            for (x, index) in bg_index.iter_mut().enumerate() {
+                *index = if window && x as i16 >= window_start {
+                    self.map_pixel(wnd_map, (x as i16 - window_start) as u8, self.window_line)
+                } else {
+                    let bx = (x as u8).wrapping_add(self.scroll_x);
+                    let by = line.wrapping_add(self.scroll_y);
+                    self.map_pixel(bg_map, bx, by)
+                };
+            }
+            if window {
+                self.window_line += 1;
+            }
+        }
+
+        if !self.rendering {
+            return;
+        }
+        let offset = line as usize * SCREEN_WIDTH;
+        for (x, &index) in bg_index.iter().enumerate() {
+            self.framebuffer[offset + x] = self.bg_palette.color(index);
+        }
+
+        if !self.control.contains(Control::OBJ_ON) {
+            return;
+        }
+
+        let tall = self.control.contains(Control::OBJ_SIZE);
+        let mut drawn = [false; SCREEN_WIDTH];
+        for sprite in self.line_sprites(line) {
+            let mut row = (line as i16 - sprite.y) as u8;
+            if sprite.flags & OBJ_Y_FLIP != 0 {
+                row = if tall { 15 - row } else { 7 - row };
+            }
+            let tile = if tall { sprite.tile & 0xFE } else { sprite.tile };
+            let tile_addr = 0x8000 + (tile as u16) * 16;
+            let palette = if sprite.flags & OBJ_PALETTE != 0 { &self.obj1_palette } else { &self.obj0_palette };
+
+            for col in 0..8u8 {
+                let x = sprite.x + col as i16;
+                if x < 0 || x >= SCREEN_WIDTH as i16 || drawn[x as usize] {
+                    continue;
+                }
+                let px = if sprite.flags & OBJ_X_FLIP != 0 { 7 - col } else { col };
+                let index = self.tile_pixel(tile_addr, px, row);
+                if index == 0 {
+                    continue;
+                }
+                // Higher priority sprites claim the pixel even if hidden behind the background
+                drawn[x as usize] = true;
+                if sprite.flags & OBJ_BG_PRIORITY != 0 && bg_index[x as usize] != 0 {
+                    continue;
+                }
+                self.framebuffer[offset + x as usize] = palette.color(index);
+            }
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use std::fmt;
+use error::{Error, Result};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MemoryBankController
+{
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mbc3,
+    Mbc5,
+    Mbc6,
+    Mbc7,
+    Mmm01,
+    PocketCamera,
+    Tama5,
+    HuC1,
+    HuC3,
+}
+
+/// The cartridge type byte (0x147) split into its controller and features.
+#[derive(Debug, Copy, Clone)]
+pub struct CartridgeType {
+    pub code: u8,
+    pub mbc: Option<MemoryBankController>,
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+    pub sensor: bool,
+}
+
+impl CartridgeType {
+    pub fn from_u8(code: u8) -> CartridgeType {
+        use self::MemoryBankController::*;
+        //                     mbc           ram    battery timer  rumble sensor
+        let (mbc, ram, battery, timer, rumble, sensor) = match code {
+            0x00 => (Some(RomOnly),      false, false, false, false, false),
+            0x01 => (Some(Mbc1),         false, false, false, false, false),
+            0x02 => (Some(Mbc1),         true,  false, false, false, false),
+            0x03 => (Some(Mbc1),         true,  true,  false, false, false),
+            0x05 => (Some(Mbc2),         false, false, false, false, false),
+            0x06 => (Some(Mbc2),         false, true,  false, false, false),
+            0x08 => (Some(RomOnly),      true,  false, false, false, false),
+            0x09 => (Some(RomOnly),      true,  true,  false, false, false),
+            0x0B => (Some(Mmm01),        false, false, false, false, false),
+            0x0C => (Some(Mmm01),        true,  false, false, false, false),
+            0x0D => (Some(Mmm01),        true,  true,  false, false, false),
+            0x0F => (Some(Mbc3),         false, true,  true,  false, false),
+            0x10 => (Some(Mbc3),         true,  true,  true,  false, false),
+            0x11 => (Some(Mbc3),         false, false, false, false, false),
+            0x12 => (Some(Mbc3),         true,  false, false, false, false),
+            0x13 => (Some(Mbc3),         true,  true,  false, false, false),
+            0x19 => (Some(Mbc5),         false, false, false, false, false),
+            0x1A => (Some(Mbc5),         true,  false, false, false, false),
+            0x1B => (Some(Mbc5),         true,  true,  false, false, false),
+            0x1C => (Some(Mbc5),         false, false, false, true,  false),
+            0x1D => (Some(Mbc5),         true,  false, false, true,  false),
+            0x1E => (Some(Mbc5),         true,  true,  false, true,  false),
+            0x20 => (Some(Mbc6),         false, false, false, false, false),
+            0x22 => (Some(Mbc7),         true,  true,  false, true,  true),
+            0xFC => (Some(PocketCamera), false, false, false, false, false),
+            0xFD => (Some(Tama5),        false, false, false, false, false),
+            0xFE => (Some(HuC3),         false, false, false, false, false),
+            0xFF => (Some(HuC1),         true,  true,  false, false, false),
+            _    => (None,               false, false, false, false, false),
+        };
+        CartridgeType { code, mbc, ram, battery, timer, rumble, sensor }
+    }
+}
+
+impl fmt::Display for CartridgeType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.mbc {
+            Some(mbc) => write!(f, "{:?}", mbc)?,
+            None => write!(f, "Unknown(0x{:02x})", self.code)?,
+        }
+        if self.ram { write!(f, "+RAM")?; }
+        if self.battery { write!(f, "+BATTERY")?; }
+        if self.timer { write!(f, "+TIMER")?; }
+        if self.rumble { write!(f, "+RUMBLE")?; }
+        if self.sensor { write!(f, "+SENSOR")?; }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CgbSupport {
+    None,
+    Enhanced,
+    Only,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Destination {
+    Japan,
+    Overseas,
+    Unknown(u8),
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Checksum<T> {
+    pub expected: T,
+    pub computed: T,
+}
+
+impl<T: PartialEq> Checksum<T> {
+    pub fn is_valid(&self) -> bool {
+        self.expected == self.computed
+    }
+}
+
+/// Parsed cartridge header (0x0100 - 0x014F).
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub title: String,
+    pub manufacturer_code: Option<String>,
+    pub cgb: CgbSupport,
+    pub sgb: bool,
+    pub new_licensee_code: Option<String>,
+    pub old_licensee_code: u8,
+    pub cartridge_type: CartridgeType,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    pub destination: Destination,
+    pub version: u8,
+    pub header_checksum: Checksum<u8>,
+    pub global_checksum: Checksum<u16>,
+}
+
+impl Header {
+    pub fn parse(rom: &[u8]) -> Result<Header> {
+        if rom.len() < 0x150 {
+            return Err(Error::BadRomHeader(format!("ROM is only {} bytes long", rom.len())));
+        }
+
+        let cgb = match rom[0x143] {
+            0x80 => CgbSupport::Enhanced,
+            0xC0 => CgbSupport::Only,
+            _ => CgbSupport::None,
+        };
+
+        // Newer cartridges reuse the end of the title for the manufacturer code and CGB flag
+        let (title, manufacturer_code) = if cgb == CgbSupport::None {
+            (ascii(&rom[0x134..0x144]), None)
+        } else {
+            let code = &rom[0x13F..0x143];
+            if code.iter().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit()) {
+                (ascii(&rom[0x134..0x13F]), Some(ascii(code)))
+            } else {
+                (ascii(&rom[0x134..0x143]), None)
+            }
+        };
+
+        let old_licensee_code = rom[0x14B];
+        let new_licensee_code = if old_licensee_code == 0x33 {
+            Some(ascii(&rom[0x144..0x146]))
+        } else {
+            None
+        };
+
+        let rom_size = match rom[0x148] {
+            code @ 0x00..=0x08 => 0x8000 << code,
+            0x52 => 72 * 0x4000,
+            0x53 => 80 * 0x4000,
+            0x54 => 96 * 0x4000,
+            code => return Err(Error::BadRomHeader(format!("Unknown ROM size 0x{:02x}", code))),
+        };
+
+        let ram_size = match rom[0x149] {
+            0x00 => 0,
+            0x01 => 0x800,
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x20000,
+            0x05 => 0x10000,
+            code => return Err(Error::BadRomHeader(format!("Unknown RAM size 0x{:02x}", code))),
+        };
+
+        let destination = match rom[0x14A] {
+            0x00 => Destination::Japan,
+            0x01 => Destination::Overseas,
+            code => Destination::Unknown(code),
+        };
+
+        let header_checksum = rom[0x134..0x14D].iter()
+            .fold(0u8, |x, &b| x.wrapping_sub(b).wrapping_sub(1));
+        let global_checksum = rom.iter().enumerate()
+            .filter(|&(i, _)| i != 0x14E && i != 0x14F)
+            .fold(0u16, |x, (_, &b)| x.wrapping_add(b as u16));
+
+        Ok(Header {
+            title,
+            manufacturer_code,
+            cgb,
+            sgb: rom[0x146] == 0x03,
+            new_licensee_code,
+            old_licensee_code,
+            cartridge_type: CartridgeType::from_u8(rom[0x147]),
+            rom_size,
+            ram_size,
+            destination,
+            version: rom[0x14C],
+            header_checksum: Checksum {
+                expected: rom[0x14D],
+                computed: header_checksum,
+            },
+            global_checksum: Checksum {
+                expected: ((rom[0x14E] as u16) << 8) | (rom[0x14F] as u16),
+                computed: global_checksum,
+            },
+        })
+    }
+
+    pub fn rom_banks(&self) -> usize {
+        self.rom_size / 0x4000
+    }
+
+    pub fn ram_banks(&self) -> usize {
+        self.ram_size.div_ceil(0x2000)
+    }
+}
+
+fn ascii(bytes: &[u8]) -> String {
+    bytes.iter()
+        .take_while(|&&b| b != 0)
+        .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '?' })
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
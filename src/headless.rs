@@ -0,0 +1,143 @@
+use std::fmt;
+use cpu::Event;
+use cpu::registers::Registers;
+use error::Result;
+use gameboy::GameBoy;
+use mmu::{Bus, MMU};
+
+/// `LD B,B`, used by test ROMs as a software breakpoint.
+pub const LD_B_B: u8 = 0x40;
+
+/// How a test ROM reports its result.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Protocol {
+    /// "Passed"/"Failed" on the serial port, or a result code in cartridge RAM
+    /// at 0xA000 once the signature DE B0 61 is present at 0xA001.
+    Blargg,
+    /// Fibonacci numbers 3, 5, 8, 13, 21, 34 in B, C, D, E, H, L at `LD B,B`.
+    Mooneye,
+}
+
+/// When to stop a headless run. Without any limit the run only ends on
+/// a CPU lock-up.
+#[derive(Default)]
+pub struct Limits {
+    pub frames: Option<u64>,
+    pub cycles: Option<u64>,
+    pub breakpoints: Vec<u16>,
+    pub ld_b_b: bool,
+    pub check: Option<Protocol>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Exit {
+    Frames(u64),
+    Cycles(u64),
+    Breakpoint(u16),
+    Sentinel(u16),
+    Lockup { opcode: u8, pc: u16 },
+    Passed,
+    Failed,
+}
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Exit::Frames(frames) => write!(f, "Frame limit reached after {} frames", frames),
+            Exit::Cycles(cycles) => write!(f, "Cycle limit reached after {} cycles", cycles),
+            Exit::Breakpoint(pc) => write!(f, "Breakpoint hit at 0x{:04x}", pc),
+            Exit::Sentinel(pc) => write!(f, "LD B,B hit at 0x{:04x}", pc),
+            Exit::Lockup { opcode, pc } => write!(f, "CPU locked up on opcode 0x{:02x} at 0x{:04x}", opcode, pc),
+            Exit::Passed => write!(f, "Test passed"),
+            Exit::Failed => write!(f, "Test failed"),
+        }
+    }
+}
+
+/// Runs the machine until one of the limits is reached, or an error stops it.
+pub fn run(gameboy: &mut GameBoy, limits: &Limits) -> Result<Exit> {
+    let mut serial_len = 0;
+    let mut frame = 0;
+    loop {
+        let pc = gameboy.cpu().registers().pc;
+        if limits.breakpoints.contains(&pc) {
+            return Ok(Exit::Breakpoint(pc));
+        }
+        if gameboy.mmu().peek(pc) == LD_B_B {
+            if limits.check == Some(Protocol::Mooneye) {
+                return Ok(mooneye_verdict(gameboy.cpu().registers()));
+            }
+            if limits.ld_b_b {
+                return Ok(Exit::Sentinel(pc));
+            }
+        }
+
+        if let Some(Event::Lockup { opcode, pc }) = gameboy.step()? {
+            return Ok(Exit::Lockup { opcode, pc });
+        }
+
+        if limits.check == Some(Protocol::Blargg) {
+            // Only look for a verdict when something could have changed
+            let mmu = gameboy.mmu();
+            if mmu.serial().output().len() != serial_len || mmu.gpu().frames() != frame {
+                serial_len = mmu.serial().output().len();
+                frame = mmu.gpu().frames();
+                if let Some(exit) = blargg_verdict(mmu) {
+                    return Ok(exit);
+                }
+            }
+        }
+
+        let frames = gameboy.frames();
+        if limits.frames.is_some_and(|limit| frames >= limit) {
+            return Ok(Exit::Frames(frames));
+        }
+        let cycles = gameboy.mmu().cycles();
+        if limits.cycles.is_some_and(|limit| cycles >= limit) {
+            return Ok(Exit::Cycles(cycles));
+        }
+    }
+}
+
+fn mooneye_verdict(regs: &Registers) -> Exit {
+    if [regs.b, regs.c, regs.d, regs.e, regs.h, regs.l] == [3, 5, 8, 13, 21, 34] {
+        Exit::Passed
+    } else {
+        Exit::Failed
+    }
+}
+
+fn has_blargg_signature(mmu: &MMU) -> bool {
+    mmu.peek(0xA001) == 0xDE && mmu.peek(0xA002) == 0xB0 && mmu.peek(0xA003) == 0x61
+}
+
+fn blargg_verdict(mmu: &MMU) -> Option<Exit> {
+    let output = String::from_utf8_lossy(mmu.serial().output());
+    if output.contains("Passed") {
+        return Some(Exit::Passed);
+    }
+    if output.contains("Failed") {
+        return Some(Exit::Failed);
+    }
+    if has_blargg_signature(mmu) {
+        match mmu.peek(0xA000) {
+            0x80 => (), // Still running
+            0x00 => return Some(Exit::Passed),
+            _ => return Some(Exit::Failed),
+        }
+    }
+    None
+}
+
+/// Text a Blargg test ROM left in cartridge RAM, if it uses that protocol.
+pub fn blargg_text(mmu: &MMU) -> Option<String> {
+    if !has_blargg_signature(mmu) {
+        return None;
+    }
+    let text = (0xA004..0xC000u16)
+        .map(|addr| mmu.peek(addr))
+        .take_while(|&b| b != 0)
+        .map(|b| b as char)
+        .collect();
+    Some(text)
+}
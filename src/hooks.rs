@@ -0,0 +1,93 @@
+//! Callbacks on bus traffic, for tools that need to see or change what the
+//! CPU reads, writes and executes: watchpoints, cheats, coverage and the
+//! like.
+//!
+//! A hook covers a range of addresses and any mix of reads, writes and
+//! executes. It gets every matching access and may return a value to use
+//! instead: the value read for reads and executes, the value stored for
+//! writes. Hooks run in the order they were added, each seeing the value
+//! left by the one before.
+//!
+//! Executes are reported once the CPU commits to an opcode, after the
+//! fetch itself went by as a read. Reads made to look at memory without
+//! touching it, through `Bus::peek`, don't reach hooks at all.
+
+use std::cell::RefCell;
+use std::ops::RangeInclusive;
+
+bitflags!(
+    /// Kinds of access a hook wants to hear about.
+    pub struct Trigger: u8 {
+        const READ    = 0b001;
+        const WRITE   = 0b010;
+        const EXECUTE = 0b100;
+    }
+);
+
+/// One access on the bus, as handed to hooks. `trigger` is a single kind.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BusAccess {
+    pub trigger: Trigger,
+    pub addr: u16,
+    pub value: u8,
+}
+
+/// Identifies a hook, to remove it again.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct HookId(u32);
+
+struct Hook {
+    id: HookId,
+    triggers: Trigger,
+    range: RangeInclusive<u16>,
+    callback: Box<dyn FnMut(BusAccess) -> Option<u8>>,
+}
+
+#[derive(Default)]
+pub struct Hooks {
+    // Reads only get `&self`
+    hooks: RefCell<Vec<Hook>>,
+    next: u32,
+}
+
+impl Hooks {
+    pub fn new() -> Hooks {
+        Hooks::default()
+    }
+
+    pub fn add<F>(&mut self, triggers: Trigger, range: RangeInclusive<u16>, callback: F) -> HookId
+        where F: FnMut(BusAccess) -> Option<u8> + 'static {
+        let id = HookId(self.next);
+        self.next += 1;
+        self.hooks.get_mut().push(Hook {
+            id,
+            triggers,
+            range,
+            callback: Box::new(callback),
+        });
+        id
+    }
+
+    /// Returns whether the hook was still there.
+    pub fn remove(&mut self, id: HookId) -> bool {
+        let hooks = self.hooks.get_mut();
+        let count = hooks.len();
+        hooks.retain(|hook| hook.id != id);
+        hooks.len() != count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.borrow().is_empty()
+    }
+
+    /// Passes an access through every matching hook and returns the value
+    /// to use.
+    pub fn run(&self, trigger: Trigger, addr: u16, value: u8) -> u8 {
+        let mut hooks = self.hooks.borrow_mut();
+        hooks.iter_mut()
+            .filter(|hook| hook.triggers.contains(trigger) && hook.range.contains(&addr))
+            .fold(value, |value, hook| {
+                (hook.callback)(BusAccess { trigger, addr, value }).unwrap_or(value)
+            })
+    }
+}
@@ -1,3 +1,5 @@
+use savestate::{State, Writer, Reader};
+use error::Result;
 
 #[derive(Debug, Copy, Clone)]
 pub enum Interrupt {
@@ -21,12 +23,12 @@ impl Interrupt {
     }
 
     pub fn addr(&self) -> u16 {
-        match self {
-            &Interrupt::VBlank => 0x40,
-            &Interrupt::LcdStat => 0x48,
-            &Interrupt::Timer => 0x50,
-            &Interrupt::Serial => 0x58,
-            &Interrupt::Joypad => 0x60,
+        match *self {
+            Interrupt::VBlank => 0x40,
+            Interrupt::LcdStat => 0x48,
+            Interrupt::Timer => 0x50,
+            Interrupt::Serial => 0x58,
+            Interrupt::Joypad => 0x60,
         }
     }
 }
@@ -84,4 +86,23 @@ impl Irq {
         self.request -= Interrupts::from_bits_truncate(int);
         Interrupt::from_u8(int)
     }
-}
\ No newline at end of file
+}
+
+impl Default for Irq {
+    fn default() -> Irq {
+        Irq::new()
+    }
+}
+
+impl State for Irq {
+    fn save(&self, out: &mut Writer) {
+        out.u8(self.enable.bits());
+        out.u8(self.request.bits());
+    }
+
+    fn load(&mut self, input: &mut Reader) -> Result<()> {
+        self.enable = Interrupts::from_bits_truncate(input.u8()?);
+        self.request = Interrupts::from_bits_truncate(input.u8()?);
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use mmu::Bus;
+use irq::{Irq, Interrupt};
+use savestate::{State, Writer, Reader};
+use error::Result;
+
+bitflags!(
+    /// Buttons held down, as passed in by the host.
+    pub struct Buttons: u8 {
+        const RIGHT  = 0b00000001;
+        const LEFT   = 0b00000010;
+        const UP     = 0b00000100;
+        const DOWN   = 0b00001000;
+        const A      = 0b00010000;
+        const B      = 0b00100000;
+        const SELECT = 0b01000000;
+        const START  = 0b10000000;
+    }
+);
+
+const SELECT_DIRECTIONS: u8 = 0b00010000;
+const SELECT_ACTIONS: u8 = 0b00100000;
+
+pub struct Joypad {
+    select: u8,
+    pressed: Buttons,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            select: SELECT_DIRECTIONS | SELECT_ACTIONS,
+            pressed: Buttons::empty(),
+        }
+    }
+
+    /// Low nibble of P1 for the selected button groups, 1 meaning pressed.
+    fn lines(&self, pressed: Buttons) -> u8 {
+        let mut lines = 0;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            lines |= pressed.bits() & 0x0F;
+        }
+        if self.select & SELECT_ACTIONS == 0 {
+            lines |= pressed.bits() >> 4;
+        }
+        lines
+    }
+
+    pub fn set_buttons(&mut self, buttons: Buttons, irq: &mut Irq) {
+        let before = self.lines(self.pressed);
+        self.pressed = buttons;
+        // The interrupt fires when a selected line goes low
+        if self.lines(buttons) & !before != 0 {
+            irq.request_interrupt(Interrupt::Joypad);
+        }
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Joypad {
+        Joypad::new()
+    }
+}
+
+impl Bus for Joypad {
+    fn read(&self, _addr: u16) -> u8 {
+        0xC0 | self.select | (!self.lines(self.pressed) & 0x0F)
+    }
+
+    fn write(&mut self, _addr: u16, value: u8) {
+        self.select = value & (SELECT_DIRECTIONS | SELECT_ACTIONS);
+    }
+}
+
+impl State for Joypad {
+    fn save(&self, out: &mut Writer) {
+        out.u8(self.select);
+        out.u8(self.pressed.bits());
+    }
+
+    fn load(&mut self, input: &mut Reader) -> Result<()> {
+        self.select = input.u8()? & (SELECT_DIRECTIONS | SELECT_ACTIONS);
+        self.pressed = Buttons::from_bits_truncate(input.u8()?);
+        Ok(())
+    }
+}
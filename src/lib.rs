@@ -0,0 +1,49 @@
+//! A Game Boy (DMG) emulator core.
+//!
+//! `GameBoy` is the easiest way in: it owns a CPU and its `MMU` and runs
+//! frames, takes input and hands out video and audio. The individual
+//! components are public as well, for tools that need to poke at them.
+
+#![allow(clippy::upper_case_acronyms)]
+
+#[macro_use]
+extern crate bitflags;
+extern crate png;
+#[cfg(test)]
+extern crate serde_json;
+
+pub mod error;
+pub mod rom;
+pub mod header;
+pub mod cartridge;
+mod memory;
+pub mod mmu;
+pub mod scheduler;
+pub mod hooks;
+pub mod cpu;
+pub mod gpu;
+pub mod timer;
+pub mod serial;
+pub mod apu;
+pub mod joypad;
+pub mod irq;
+pub mod gameboy;
+pub mod headless;
+pub mod screenshot;
+pub mod savestate;
+pub mod rewind;
+pub mod movie;
+pub mod debugger;
+pub mod disasm;
+pub mod gdb;
+pub mod profile;
+pub mod cheats;
+pub mod pacing;
+
+pub use error::{Error, Result};
+pub use cartridge::Cartridge;
+pub use gameboy::GameBoy;
+pub use joypad::Buttons;
+
+//TODO Use actual bios
+//     Use boot-values for stuff in that case!
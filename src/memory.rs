@@ -1,4 +1,6 @@
 use mmu::Bus;
+use savestate::{State, Writer, Reader};
+use error::Result;
 
 pub struct Ram {
     ram: Vec<u8>,
@@ -20,3 +22,13 @@ impl Bus for Ram {
         self.ram[addr as usize] = value;
     }
 }
+
+impl State for Ram {
+    fn save(&self, out: &mut Writer) {
+        out.bytes(&self.ram);
+    }
+
+    fn load(&mut self, input: &mut Reader) -> Result<()> {
+        input.bytes(&mut self.ram)
+    }
+}
@@ -1,20 +1,78 @@
+use std::cell::{Cell, RefCell};
+use std::ops::RangeInclusive;
+use std::rc::Rc;
+
 use cartridge::Cartridge;
 use memory::Ram;
 use gpu::Gpu;
 use timer::Timer;
+use serial::Serial;
+use apu::Apu;
+use joypad::{Joypad, Buttons};
 use irq::{Irq, Interrupt};
+use scheduler::{Clock, Scheduler, Scheduled, Source};
+use hooks::{Hooks, HookId, Trigger, BusAccess};
+use savestate::{State, Writer, Reader};
+use error::Result;
 
 pub trait Bus {
     fn read(&self, addr: u16) -> u8;
     fn write(&mut self, addr: u16, value: u8);
+
+    /// Reads memory for a look, without any of the side effects of a read.
+    fn peek(&self, addr: u16) -> u8 {
+        self.read(addr)
+    }
+
+    /// Told about every opcode right before the CPU executes it, once its
+    /// fetch is done. Returns the opcode to execute instead.
+    fn execute(&self, _addr: u16, opcode: u8) -> u8 {
+        opcode
+    }
 }
 pub trait Master { // Rename to Master
     fn cycle(&mut self);
     fn has_interrupt(&mut self) -> bool;
     fn ack_interrupt(&mut self) -> Option<Interrupt>;
+
+    /// ROM bank mapped at `addr`, if it's in ROM and there's a cartridge
+    /// to tell.
+    fn rom_bank(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+}
+
+/// Kind of access a watchpoint triggers on. The bus can't tell data reads
+/// from the CPU fetching opcodes and operands, so reads include both.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Any,
+}
+
+impl Access {
+    fn matches(self, write: bool) -> bool {
+        match self {
+            Access::Read => !write,
+            Access::Write => write,
+            Access::Any => true,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Watchpoint {
+    pub addr: u16,
+    pub access: Access,
 }
-pub trait InterruptCycle { //TODO Rename to Slave
-    fn cycle(&mut self, irq: &mut Irq);
+
+/// A memory access that matched a watchpoint.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WatchHit {
+    pub addr: u16,
+    pub value: u8,
+    pub write: bool,
 }
 
 pub struct MMU {
@@ -24,66 +82,264 @@ pub struct MMU {
     irq: Irq,
     gpu: Gpu,
     timer: Timer,
+    serial: Serial,
+    /// Has to be caught up to be saved, which only takes `&self`
+    apu: RefCell<Apu>,
+    joypad: Joypad,
+    clock: Clock,
+    scheduler: Scheduler,
+    hooks: Hooks,
+    watch_hook: Option<HookId>,
+    watch_hit: Rc<Cell<Option<WatchHit>>>,
 }
 
 impl MMU {
     pub fn new(cart: Cartridge) -> MMU {
-        MMU {
-            cart: cart,
+        let clock = Clock::new();
+        let mut mmu = MMU {
+            cart,
             wram: Ram::new(8192),
             zram: Ram::new(128),
             irq: Irq::new(),
-            gpu: Gpu::new(),
-            timer: Timer::new(),
+            gpu: Gpu::new(clock.clone()),
+            timer: Timer::new(clock.clone()),
+            serial: Serial::new(clock.clone()),
+            apu: RefCell::new(Apu::new(clock.clone())),
+            joypad: Joypad::new(),
+            clock,
+            scheduler: Scheduler::new(),
+            hooks: Hooks::new(),
+            watch_hook: None,
+            watch_hit: Rc::new(Cell::new(None)),
+        };
+        mmu.reschedule_all();
+        mmu
+    }
+
+    /// Puts everything back in its power-on state. Cartridge RAM survives,
+    /// as it would with a battery.
+    pub fn reset(&mut self) {
+        self.cart.reset();
+        self.wram = Ram::new(8192);
+        self.zram = Ram::new(128);
+        self.irq = Irq::new();
+        self.clock.set(0);
+        self.gpu = Gpu::new(self.clock.clone());
+        self.timer = Timer::new(self.clock.clone());
+        self.serial = Serial::new(self.clock.clone());
+        self.apu = RefCell::new(Apu::new(self.clock.clone()));
+        self.joypad = Joypad::new();
+        self.reschedule_all();
+    }
+
+    /// Like `reset`, but cartridge RAM is wiped as well.
+    pub fn power_on(&mut self) {
+        self.reset();
+        self.cart.clear_ram();
+    }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.cart
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut Cartridge {
+        &mut self.cart
+    }
+
+    pub fn gpu(&self) -> &Gpu {
+        &self.gpu
+    }
+
+    /// See `Gpu::set_rendering`.
+    pub fn set_rendering(&mut self, rendering: bool) {
+        self.gpu.set_rendering(rendering);
+    }
+
+    pub fn serial(&self) -> &Serial {
+        &self.serial
+    }
+
+    pub fn drain_audio(&mut self) -> Vec<i16> {
+        self.apu.get_mut().drain()
+    }
+
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.joypad.set_buttons(buttons, &mut self.irq);
+    }
+
+    /// Number of M-cycles elapsed since power-on.
+    pub fn cycles(&self) -> u64 {
+        self.clock.now()
+    }
+
+    /// Calls `callback` on every access of a kind in `triggers` within
+    /// `range`. See `hooks` for what it can do.
+    pub fn add_hook<F>(&mut self, triggers: Trigger, range: RangeInclusive<u16>, callback: F) -> HookId
+        where F: FnMut(BusAccess) -> Option<u8> + 'static {
+        self.hooks.add(triggers, range, callback)
+    }
+
+    /// Returns whether the hook was still there.
+    pub fn remove_hook(&mut self, id: HookId) -> bool {
+        self.hooks.remove(id)
+    }
+
+    /// Watchpoints to check every access against. Only the first hit is
+    /// kept until `take_watch_hit` is called.
+    pub fn set_watchpoints(&mut self, watchpoints: Vec<Watchpoint>) {
+        if let Some(id) = self.watch_hook.take() {
+            self.hooks.remove(id);
+        }
+        if watchpoints.is_empty() {
+            return;
+        }
+        let hit = self.watch_hit.clone();
+        let id = self.hooks.add(Trigger::READ | Trigger::WRITE, 0x0000 ..= 0xFFFF, move |access| {
+            let write = access.trigger == Trigger::WRITE;
+            let matches = watchpoints.iter().any(|w| w.addr == access.addr && w.access.matches(write));
+            if matches && hit.get().is_none() {
+                hit.set(Some(WatchHit { addr: access.addr, value: access.value, write }));
+            }
+            None
+        });
+        self.watch_hook = Some(id);
+    }
+
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    /// Writes memory like the CPU would, but without any hooks seeing it.
+    pub fn poke(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000 ..= 0x3FFF => self.cart.write(addr, value),
+            0x4000 ..= 0x7FFF => self.cart.write(addr, value),
+            0x8000 ..= 0x9FFF => self.write_to(Source::Gpu, |mmu| mmu.gpu.write(addr, value)),
+            0xA000 ..= 0xBFFF => self.cart.write(addr, value),
+            0xC000 ..= 0xDFFF => self.wram.write(addr & 0x1FFF, value),
+            0xE000 ..= 0xFDFF => self.wram.write(addr & 0x1FFF, value),
+            0xFE00 ..= 0xFE9F => self.write_to(Source::Gpu, |mmu| mmu.gpu.write(addr, value)),
+            0xFEA0 ..= 0xFEFF => (), //TODO unusable
+            0xFF00 => self.joypad.write(addr, value),
+            0xFF01 ..= 0xFF02 => self.write_to(Source::Serial, |mmu| mmu.serial.write(addr, value)),
+            0xFF04 => self.write_to(Source::Timer, |mmu| mmu.timer.reset_divider(&mut mmu.irq)),
+            0xFF05 ..= 0xFF07 => self.write_to(Source::Timer, |mmu| mmu.timer.write(addr, value)),
+            0xFF0F => self.irq.set_request(value),
+            0xFF10 ..= 0xFF3F => self.write_to(Source::Apu, |mmu| mmu.apu.get_mut().write(addr, value)),
+            0xFF46 => self.write_to(Source::Gpu, |mmu| mmu.dma(value)),
+            0xFF40 ..= 0xFF55 => self.write_to(Source::Gpu, |mmu| mmu.gpu.write(addr, value)),
+            0xFF7F => (), //TODO unknown
+            0xFF80 ..= 0xFFFE => self.zram.write(addr & 0x7F, value),
+            0xFFFF => self.irq.set_enable(value),
+            _ => (), // Unmapped
+        }
+    }
+
+    /// Catches `source` up before `write` changes it, then finds out what
+    /// it has coming after.
+    fn write_to<F: FnOnce(&mut MMU)>(&mut self, source: Source, write: F) {
+        self.catch_up(source);
+        write(self);
+        self.reschedule(source);
+    }
+
+    fn catch_up(&mut self, source: Source) {
+        match source {
+            Source::Gpu => self.gpu.catch_up(&mut self.irq),
+            Source::Timer => self.timer.catch_up(&mut self.irq),
+            Source::Serial => self.serial.catch_up(&mut self.irq),
+            Source::Apu => self.apu.get_mut().catch_up(&mut self.irq),
+        }
+        self.reschedule(source);
+    }
+
+    fn reschedule(&mut self, source: Source) {
+        let at = match source {
+            Source::Gpu => self.gpu.next_event(),
+            Source::Timer => self.timer.next_event(),
+            Source::Serial => self.serial.next_event(),
+            Source::Apu => self.apu.get_mut().next_event(),
+        };
+        self.scheduler.schedule(source, at);
+    }
+
+    fn reschedule_all(&mut self) {
+        for &source in &Source::ALL {
+            self.reschedule(source);
+        }
+    }
+
+    //TODO DMA should take 160 cycles and block the bus
+    fn dma(&mut self, value: u8) {
+        let source = (value as u16) << 8;
+        for i in 0..0xA0 {
+            let byte = self.read(source + i);
+            self.gpu.write(0xFE00 + i, byte);
         }
     }
 }
 
 impl Bus for MMU {
     fn read(&self, addr: u16) -> u8 {
+        let value = self.peek(addr);
+        if self.hooks.is_empty() {
+            value
+        } else {
+            self.hooks.run(Trigger::READ, addr, value)
+        }
+    }
+
+    /// Reads memory like the CPU would, but without any hooks seeing it.
+    fn peek(&self, addr: u16) -> u8 {
         match addr {
-            0x0000 ... 0x3FFF => self.cart.read(addr),
-            0x4000 ... 0x7FFF => self.cart.read(addr),
-            0x8000 ... 0x9FFF => self.gpu.read(addr),
-            0xC000 ... 0xDFFF => self.wram.read(addr & 0x1FFF),
-            0xE000 ... 0xFDFF => self.wram.read(addr & 0x1FFF),
-            0xFE00 ... 0xFE9F => self.gpu.read(addr),
-            0xFF00 => 0xFF, //TODO Joypad
-            0xFF04 ... 0xFF07 => self.timer.read(addr),
-            0xFF0F => self.irq.get_request(),
-            0xFF40 ... 0xFF55 => self.gpu.read(addr),
-            0xFF80 ... 0xFFFE => self.zram.read(addr & 0x7F),
+            0x0000 ..= 0x3FFF => self.cart.read(addr),
+            0x4000 ..= 0x7FFF => self.cart.read(addr),
+            0x8000 ..= 0x9FFF => self.gpu.read(addr),
+            0xA000 ..= 0xBFFF => self.cart.read(addr),
+            0xC000 ..= 0xDFFF => self.wram.read(addr & 0x1FFF),
+            0xE000 ..= 0xFDFF => self.wram.read(addr & 0x1FFF),
+            0xFE00 ..= 0xFE9F => self.gpu.read(addr),
+            0xFF00 => self.joypad.read(addr),
+            0xFF01 ..= 0xFF02 => self.serial.read(addr),
+            0xFF04 ..= 0xFF07 => self.timer.read(addr),
+            0xFF0F => 0xE0 | self.irq.get_request(),
+            0xFF10 ..= 0xFF3F => self.apu.borrow().read(addr),
+            0xFF40 ..= 0xFF55 => self.gpu.read(addr),
+            0xFF80 ..= 0xFFFE => self.zram.read(addr & 0x7F),
             0xFFFF => self.irq.get_enable(),
-            _ => panic!("Unsupported read 0x{:04x}", addr)
+            _ => 0xFF, // Unmapped
         }
     }
 
-    fn write(&mut self, addr: u16, value: u8) {
-        match addr {
-            0x0000 ... 0x3FFF => self.cart.write(addr, value),
-            0x4000 ... 0x7FFF => self.cart.write(addr, value),
-            0x8000 ... 0x9FFF => self.gpu.write(addr, value),
-            0xC000 ... 0xDFFF => self.wram.write(addr & 0x1FFF, value),
-            0xE000 ... 0xFDFF => self.wram.write(addr & 0x1FFF, value),
-            0xFE00 ... 0xFE9F => self.gpu.write(addr, value),
-            0xFEA0 ... 0xFEFF => (), //TODO unusable
-            0xFF00 => (), //TODO Joypad
-            0xFF01 ... 0xFF02 => (), //TODO serial
-            0xFF04 ... 0xFF07 => self.timer.write(addr, value),
-            0xFF0F => self.irq.set_request(value),
-            0xFF10 ... 0xFF26 => (), //TODO Sound
-            0xFF40 ... 0xFF55 => self.gpu.write(addr, value),
-            0xFF7F => (), //TODO unknown
-            0xFF80 ... 0xFFFE => self.zram.write(addr & 0x7F, value),
-            0xFFFF => self.irq.set_enable(value),
-            _ => panic!("Unsupported write 0x{:04x} = 0x{:02x}", addr, value)
+    fn execute(&self, addr: u16, opcode: u8) -> u8 {
+        if self.hooks.is_empty() {
+            opcode
+        } else {
+            self.hooks.run(Trigger::EXECUTE, addr, opcode)
         }
     }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        let value = if self.hooks.is_empty() {
+            value
+        } else {
+            self.hooks.run(Trigger::WRITE, addr, value)
+        };
+        self.poke(addr, value);
+    }
 }
 
 impl Master for MMU {
     fn cycle(&mut self) {
-        self.gpu.cycle(&mut self.irq);
+        let now = self.clock.tick();
+        if now < self.scheduler.next() {
+            return;
+        }
+        for &source in &Source::ALL {
+            if self.scheduler.is_due(source, now) {
+                self.catch_up(source);
+            }
+        }
     }
 
     fn has_interrupt(&mut self) -> bool {
@@ -93,4 +349,46 @@ impl Master for MMU {
     fn ack_interrupt(&mut self) -> Option<Interrupt> {
         self.irq.ack_interrupt()
     }
-}
\ No newline at end of file
+
+    fn rom_bank(&self, addr: u16) -> Option<usize> {
+        if addr < 0x8000 {
+            Some(self.cart.rom_bank(addr))
+        } else {
+            None
+        }
+    }
+}
+impl State for MMU {
+    fn save(&self, out: &mut Writer) {
+        self.cart.save(out);
+        self.wram.save(out);
+        self.zram.save(out);
+        self.irq.save(out);
+        self.gpu.save(out);
+        self.timer.save(out);
+        self.serial.save(out);
+        self.apu.borrow_mut().run();
+        self.apu.borrow().save(out);
+        self.joypad.save(out);
+        out.u64(self.clock.now());
+    }
+
+    fn load(&mut self, input: &mut Reader) -> Result<()> {
+        self.cart.load(input)?;
+        self.wram.load(input)?;
+        self.zram.load(input)?;
+        self.irq.load(input)?;
+        self.gpu.load(input)?;
+        self.timer.load(input)?;
+        self.serial.load(input)?;
+        self.apu.get_mut().load(input)?;
+        self.joypad.load(input)?;
+        self.clock.set(input.u64()?);
+        self.gpu.restart();
+        self.timer.restart();
+        self.serial.restart();
+        self.apu.get_mut().restart();
+        self.reschedule_all();
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+//! Input movies: the buttons held during every frame, enough to replay a run
+//! bit for bit.
+//!
+//! A movie starts either from power-on or from an embedded save state, and
+//! is tied to a ROM by its CRC-32. Next to the input, every frame records a
+//! hash of the picture it produced, so a replay can tell exactly where it
+//! went off track.
+//!
+//! The file starts with the magic bytes `GBMV`, a version, the ROM checksum
+//! and the length of the embedded save state, zero when starting from
+//! power-on, followed by the state itself. Then comes the number of frames
+//! and for each frame the buttons (one byte) and the frame hash (eight
+//! bytes). Everything is little-endian.
+//!
+//! Input to record comes from a script: one line per change, a frame number
+//! followed by the buttons held from that frame on, e.g. `120 start` or
+//! `300 a right`. A frame number alone releases everything, and `#` starts
+//! a comment.
+
+use std::fmt;
+use std::fs;
+
+use cpu::Event;
+use error::{Error, Result};
+use gameboy::GameBoy;
+use joypad::Buttons;
+use savestate::{Writer, Reader};
+
+pub const MAGIC: &[u8; 4] = b"GBMV";
+pub const VERSION: u16 = 1;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Frame {
+    pub input: Buttons,
+    pub hash: u64,
+}
+
+pub struct Movie {
+    checksum: u32,
+    start: Option<Vec<u8>>,
+    frames: Vec<Frame>,
+}
+
+/// The first frame where a replay didn't match the recording.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Desync {
+    pub frame: usize,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+impl fmt::Display for Desync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Desync at frame {}: expected hash 0x{:016x}, got 0x{:016x}",
+               self.frame, self.expected, self.actual)
+    }
+}
+
+impl Movie {
+    pub fn open(filename: &str) -> Result<Movie> {
+        Movie::from_bytes(&fs::read(filename)?)
+    }
+
+    pub fn save(&self, filename: &str) -> Result<()> {
+        fs::write(filename, self.to_bytes())?;
+        Ok(())
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie> {
+        let mut input = Reader::new(data);
+        let movie = Movie::parse(&mut input).and_then(|movie| input.finish().map(|_| movie));
+        movie.map_err(|e| match e {
+            Error::BadSaveState(reason) => Error::BadMovie(reason),
+            e => e,
+        })
+    }
+
+    fn parse(input: &mut Reader) -> Result<Movie> {
+        let mut magic = [0; 4];
+        input.bytes(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::BadMovie("not a movie".to_string()));
+        }
+        let version = input.u16()?;
+        if version != VERSION {
+            return Err(Error::BadMovie(format!("unsupported version {}", version)));
+        }
+        let checksum = input.u32()?;
+        let start = match input.u32()? as usize {
+            0 => None,
+            length if length > input.remaining() => return Err(Error::BadMovie("truncated".to_string())),
+            length => {
+                let mut state = vec!(0; length);
+                input.bytes(&mut state)?;
+                Some(state)
+            },
+        };
+        let count = input.u32()?;
+        let mut frames = vec!();
+        for _ in 0..count {
+            frames.push(Frame {
+                input: Buttons::from_bits_truncate(input.u8()?),
+                hash: input.u64()?,
+            });
+        }
+        Ok(Movie { checksum, start, frames })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Writer::new();
+        out.bytes(MAGIC);
+        out.u16(VERSION);
+        out.u32(self.checksum);
+        match self.start {
+            Some(ref state) => {
+                out.u32(state.len() as u32);
+                out.bytes(state);
+            },
+            None => out.u32(0),
+        }
+        out.u32(self.frames.len() as u32);
+        for frame in &self.frames {
+            out.u8(frame.input.bits());
+            out.u64(frame.hash);
+        }
+        out.finish()
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Whether the movie starts from a save state rather than power-on.
+    pub fn has_start_state(&self) -> bool {
+        self.start.is_some()
+    }
+
+    /// Puts the machine where the movie starts. Fails if the movie was
+    /// recorded with another ROM.
+    pub fn start(&self, gameboy: &mut GameBoy) -> Result<()> {
+        let checksum = gameboy.mmu().cartridge().checksum();
+        if checksum != self.checksum {
+            return Err(Error::RomMismatch { expected: checksum, found: self.checksum });
+        }
+        match self.start {
+            Some(ref state) => gameboy.load_state(state),
+            None => {
+                gameboy.power_on();
+                Ok(())
+            },
+        }
+    }
+
+    /// Replays the whole movie, stopping at the first frame that doesn't
+    /// match the recording.
+    pub fn play(&self, gameboy: &mut GameBoy) -> Result<Option<Desync>> {
+        self.start(gameboy)?;
+        for (i, frame) in self.frames.iter().enumerate() {
+            gameboy.set_input(frame.input);
+            gameboy.run_frame()?;
+            let actual = frame_hash(gameboy);
+            if actual != frame.hash {
+                return Ok(Some(Desync { frame: i, expected: frame.hash, actual }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+pub struct Recorder {
+    movie: Movie,
+}
+
+impl Recorder {
+    /// Powers the machine on and starts recording from there.
+    pub fn power_on(gameboy: &mut GameBoy) -> Recorder {
+        gameboy.power_on();
+        Recorder::new(gameboy, None)
+    }
+
+    /// Starts recording from wherever the machine is now.
+    pub fn from_here(gameboy: &GameBoy) -> Recorder {
+        Recorder::new(gameboy, Some(gameboy.save_state()))
+    }
+
+    fn new(gameboy: &GameBoy, start: Option<Vec<u8>>) -> Recorder {
+        Recorder {
+            movie: Movie {
+                checksum: gameboy.mmu().cartridge().checksum(),
+                start,
+                frames: vec!(),
+            },
+        }
+    }
+
+    /// Runs one frame with `input` held down and records it.
+    pub fn frame(&mut self, gameboy: &mut GameBoy, input: Buttons) -> Result<Option<Event>> {
+        gameboy.set_input(input);
+        let event = gameboy.run_frame()?;
+        self.movie.frames.push(Frame {
+            input,
+            hash: frame_hash(gameboy),
+        });
+        Ok(event)
+    }
+
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+/// Buttons to hold, frame by frame, read from an input script.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Script {
+    /// Frame each change starts on and what's held from then on, in order.
+    changes: Vec<(u64, Buttons)>,
+}
+
+impl Script {
+    pub fn open(filename: &str) -> Result<Script> {
+        Script::parse(&fs::read_to_string(filename)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Script> {
+        let mut changes: Vec<(u64, Buttons)> = vec!();
+        for (i, line) in text.lines().enumerate() {
+            let bad = |reason: String| Error::BadMovie(format!("input script line {}: {}", i + 1, reason));
+            let mut words = line.split('#').next().unwrap_or("").split_whitespace();
+            let frame = match words.next() {
+                Some(word) => word.parse::<u64>().map_err(|_| bad(format!("{} isn't a frame number", word)))?,
+                None => continue,
+            };
+            if changes.last().is_some_and(|&(last, _)| frame <= last) {
+                return Err(bad(format!("frame {} doesn't come after the line before", frame)));
+            }
+            let mut input = Buttons::empty();
+            for word in words {
+                input |= button(word).ok_or_else(|| bad(format!("unknown button {}", word)))?;
+            }
+            changes.push((frame, input));
+        }
+        Ok(Script { changes })
+    }
+
+    /// What's held during `frame`, counting from zero.
+    pub fn input(&self, frame: u64) -> Buttons {
+        self.changes.iter()
+            .take_while(|&&(start, _)| start <= frame)
+            .last()
+            .map_or(Buttons::empty(), |&(_, input)| input)
+    }
+}
+
+fn button(name: &str) -> Option<Buttons> {
+    match name.to_lowercase().as_str() {
+        "right" => Some(Buttons::RIGHT),
+        "left" => Some(Buttons::LEFT),
+        "up" => Some(Buttons::UP),
+        "down" => Some(Buttons::DOWN),
+        "a" => Some(Buttons::A),
+        "b" => Some(Buttons::B),
+        "select" => Some(Buttons::SELECT),
+        "start" => Some(Buttons::START),
+        _ => None,
+    }
+}
+
+/// FNV-1a hash of the current picture.
+pub fn frame_hash(gameboy: &GameBoy) -> u64 {
+    gameboy.framebuffer().iter().fold(0xCBF29CE484222325, |hash, &color| {
+        (hash ^ color as u64).wrapping_mul(0x100000001B3)
+    })
+}
@@ -0,0 +1,186 @@
+//! Pacing emulation to real time on the host clock.
+//!
+//! A `Pacer` is told whenever a frame has been emulated and returns how
+//! long to wait before starting the next one. It also decides which frames
+//! are worth rendering: when fast-forwarding, more frames are emulated
+//! than a display can show, and when the host falls behind, skipping the
+//! rendering of a few frames helps it catch up.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use gpu::FRAME_CYCLES;
+
+/// M-cycles per second.
+const CLOCK: f64 = (1 << 20) as f64;
+
+/// Frames per second of the real thing, about 59.73.
+pub const FRAME_RATE: f64 = CLOCK / FRAME_CYCLES as f64;
+
+/// Falling further behind than this many frames gives up on catching up.
+const MAX_LAG: f64 = 15.0;
+
+/// Reports cover at least this long.
+const REPORT_PERIOD: Duration = Duration::from_secs(1);
+
+/// Guards against rounding when frames land right on a display refresh.
+const EPSILON: f64 = 1e-6;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Speed {
+    /// Multiple of real time: 2.0 fast-forwards, 0.5 plays in slow motion
+    Times(f64),
+    /// As fast as the host can go
+    Uncapped,
+}
+
+/// Frames emulated over a stretch of host time.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Report {
+    pub frames: u64,
+    /// Frames emulated without being rendered
+    pub skipped: u64,
+    pub elapsed: Duration,
+}
+
+impl Report {
+    /// Emulated frames per second.
+    pub fn fps(&self) -> f64 {
+        self.frames as f64 / self.elapsed.as_secs_f64()
+    }
+
+    /// Emulation speed as a multiple of real time.
+    pub fn speed(&self) -> f64 {
+        self.fps() / FRAME_RATE
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} frames in {:.2} s: {:.1} fps, {:.2}x real time",
+               self.frames, self.elapsed.as_secs_f64(), self.fps(), self.speed())?;
+        if self.skipped > 0 {
+            write!(f, ", {} skipped", self.skipped)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Pacer {
+    speed: Speed,
+    max_skip: u32,
+    /// Where frame 0 of the current stretch was due
+    start: Instant,
+    frames: u64,
+    /// Display refresh, counted from `start`, the last rendered frame went
+    /// out on
+    shown: Option<u64>,
+    skipped: u32,
+    render: bool,
+    report: Report,
+    report_start: Instant,
+    total: Report,
+    total_start: Instant,
+}
+
+impl Pacer {
+    /// Starts pacing from `now`. At most `max_skip` frames in a row go
+    /// unrendered, 0 renders them all.
+    pub fn new(speed: Speed, max_skip: u32, now: Instant) -> Pacer {
+        let report = Report { frames: 0, skipped: 0, elapsed: Duration::from_secs(0) };
+        Pacer {
+            speed,
+            max_skip,
+            start: now,
+            frames: 0,
+            shown: None,
+            skipped: 0,
+            render: true,
+            report,
+            report_start: now,
+            total: report,
+            total_start: now,
+        }
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    /// Changes speed from `now` on, say for fast-forward while a key is
+    /// held.
+    pub fn set_speed(&mut self, speed: Speed, now: Instant) {
+        self.speed = speed;
+        self.restart(now);
+    }
+
+    /// Whether the next frame should be rendered.
+    pub fn render(&self) -> bool {
+        self.render
+    }
+
+    /// Called once a frame has been emulated, rendered or not as `render`
+    /// said. Returns how long to wait before emulating the next one.
+    pub fn frame_done(&mut self, now: Instant) -> Duration {
+        self.frames += 1;
+        self.report.frames += 1;
+        self.total.frames += 1;
+        let rendered = self.render;
+        if rendered {
+            self.skipped = 0;
+        } else {
+            self.skipped += 1;
+            self.report.skipped += 1;
+            self.total.skipped += 1;
+        }
+
+        // Times are counted in real-time frames since `start`, which is
+        // also when a display refreshes
+        let elapsed = now.duration_since(self.start).as_secs_f64() * FRAME_RATE;
+        let (due, next) = match self.speed {
+            Speed::Times(speed) => {
+                let due = self.frames as f64 / speed;
+                if elapsed - due > MAX_LAG {
+                    // Too slow to ever catch up, so start over from here
+                    self.restart(now);
+                    return Duration::from_secs(0);
+                }
+                (due.max(elapsed), ((self.frames + 1) as f64 / speed).max(elapsed))
+            },
+            Speed::Uncapped => (elapsed, elapsed),
+        };
+        if rendered {
+            self.shown = Some((due + EPSILON) as u64);
+        }
+        // Render the first frame to go out on a new refresh
+        self.render = self.skipped >= self.max_skip ||
+            self.shown.map_or(true, |shown| (next + EPSILON) as u64 > shown);
+        Duration::from_secs_f64((due - elapsed) / FRAME_RATE)
+    }
+
+    /// Frames emulated since the last report, once `REPORT_PERIOD` has
+    /// passed.
+    pub fn take_report(&mut self, now: Instant) -> Option<Report> {
+        let elapsed = now.duration_since(self.report_start);
+        if elapsed < REPORT_PERIOD {
+            return None;
+        }
+        let report = Report { elapsed, ..self.report };
+        self.report = Report { frames: 0, skipped: 0, elapsed: Duration::from_secs(0) };
+        self.report_start = now;
+        Some(report)
+    }
+
+    /// Frames emulated since the pacer was created.
+    pub fn total(&self, now: Instant) -> Report {
+        Report { elapsed: now.duration_since(self.total_start), ..self.total }
+    }
+
+    fn restart(&mut self, now: Instant) {
+        self.start = now;
+        self.frames = 0;
+        self.shown = None;
+        self.skipped = 0;
+        self.render = true;
+    }
+}
@@ -0,0 +1,184 @@
+//! Code coverage and profiling: how often every instruction executed and
+//! how many M-cycles it took, keyed by bank and address.
+//!
+//! Code outside of ROM counts as bank 0, the way RGBDS names WRAM0 and
+//! HRAM. Cycles spent halted or dispatching interrupts don't belong to any
+//! instruction and are counted apart.
+//!
+//! A profile is exported three ways: a report listing every address, a
+//! flat profile adding up addresses by the symbol they fall under, and a
+//! coverage map of the ROM.
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::rc::Rc;
+
+use cpu::{CPU, Event};
+use cpu::opcodes::Instruction;
+use disasm::Symbols;
+use error::Result;
+use hooks::{HookId, Trigger};
+use mmu::{Master, MMU};
+
+/// Set in the coverage map for the first byte of an executed instruction.
+pub const EXECUTED: u8 = 0x01;
+/// Set in the coverage map for every byte of an executed instruction.
+pub const COVERED: u8 = 0x02;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct Entry {
+    pub executions: u64,
+    pub cycles: u64,
+}
+
+#[derive(Default)]
+pub struct Profile {
+    entries: BTreeMap<(usize, u16), Entry>,
+    halted: u64,
+    interrupts: u64,
+    executed: Rc<Cell<bool>>,
+    hook: Option<HookId>,
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    /// Executions and cycles by bank and address.
+    pub fn entries(&self) -> &BTreeMap<(usize, u16), Entry> {
+        &self.entries
+    }
+
+    /// M-cycles spent halted or locked up.
+    pub fn halted_cycles(&self) -> u64 {
+        self.halted
+    }
+
+    /// M-cycles spent dispatching interrupts.
+    pub fn interrupt_cycles(&self) -> u64 {
+        self.interrupts
+    }
+
+    pub fn total_cycles(&self) -> u64 {
+        self.entries.values().map(|entry| entry.cycles).sum::<u64>() + self.halted + self.interrupts
+    }
+
+    /// Starts listening for executed instructions.
+    pub(crate) fn attach(&mut self, mmu: &mut MMU) {
+        let executed = self.executed.clone();
+        self.hook = Some(mmu.add_hook(Trigger::EXECUTE, 0x0000 ..= 0xFFFF, move |_| {
+            executed.set(true);
+            None
+        }));
+    }
+
+    pub(crate) fn detach(&mut self, mmu: &mut MMU) {
+        if let Some(id) = self.hook.take() {
+            mmu.remove_hook(id);
+        }
+    }
+
+    /// Steps the CPU and puts the cycles it took where they belong.
+    pub(crate) fn step(&mut self, cpu: &mut CPU<MMU>) -> Result<Option<Event>> {
+        let pc = cpu.registers().pc;
+        let bank = cpu.bus().rom_bank(pc).unwrap_or(0);
+        let idle = cpu.is_halted() || cpu.is_locked();
+        let start = cpu.bus().cycles();
+        self.executed.set(false);
+        let event = cpu.step();
+        let cycles = cpu.bus().cycles() - start;
+        if self.executed.get() {
+            let entry = self.entries.entry((bank, pc)).or_default();
+            entry.executions += 1;
+            entry.cycles += cycles;
+        } else if idle {
+            self.halted += cycles;
+        } else {
+            self.interrupts += cycles;
+        }
+        event
+    }
+
+    /// Every address that executed, in order, with what share of the time
+    /// it took.
+    pub fn report(&self, symbols: &Symbols) -> String {
+        let total = self.total_cycles();
+        let mut out = String::new();
+        let _ = writeln!(out, "{:<9} {:>12} {:>12} {:>7}  symbol", "address", "executions", "cycles", "%");
+        for (&(bank, addr), entry) in &self.entries {
+            let symbol = match symbols.lookup(bank, addr) {
+                Some((name, 0)) => name.to_string(),
+                Some((name, offset)) => format!("{}+{}", name, offset),
+                None => String::new(),
+            };
+            let _ = writeln!(out, "{:02x}:{:04x}   {:>12} {:>12} {:>7.2}  {}",
+                             bank, addr, entry.executions, entry.cycles, percent(entry.cycles, total), symbol);
+        }
+        self.footer(&mut out, total);
+        out
+    }
+
+    /// Cycles and executions added up by symbol, most expensive first.
+    /// Addresses before the first symbol of their bank are added up by
+    /// bank.
+    pub fn flat(&self, symbols: &Symbols) -> String {
+        let mut functions: BTreeMap<String, Entry> = BTreeMap::new();
+        for (&(bank, addr), entry) in &self.entries {
+            let name = match symbols.lookup(bank, addr) {
+                Some((name, _)) => name.to_string(),
+                None => format!("bank {:02x}", bank),
+            };
+            let function = functions.entry(name).or_default();
+            function.executions += entry.executions;
+            function.cycles += entry.cycles;
+        }
+        let mut functions: Vec<(String, Entry)> = functions.into_iter().collect();
+        functions.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then_with(|| a.0.cmp(&b.0)));
+
+        let total = self.total_cycles();
+        let mut out = String::new();
+        let _ = writeln!(out, "{:>7} {:>12} {:>12}  symbol", "%", "cycles", "executions");
+        for (name, function) in functions {
+            let _ = writeln!(out, "{:>7.2} {:>12} {:>12}  {}",
+                             percent(function.cycles, total), function.cycles, function.executions, name);
+        }
+        self.footer(&mut out, total);
+        out
+    }
+
+    fn footer(&self, out: &mut String, total: u64) {
+        let _ = writeln!(out, "halted: {} cycles ({:.2}%)", self.halted, percent(self.halted, total));
+        let _ = writeln!(out, "interrupts: {} cycles ({:.2}%)", self.interrupts, percent(self.interrupts, total));
+        let _ = writeln!(out, "total: {} cycles", total);
+    }
+
+    /// One byte for every byte of `rom`, made of `EXECUTED` and `COVERED`.
+    pub fn coverage(&self, rom: &[u8]) -> Vec<u8> {
+        let mut map = vec!(0; rom.len());
+        for &(bank, addr) in self.entries.keys().filter(|&&(_, addr)| addr < 0x8000) {
+            let offset = (bank << 14) | (addr as usize & 0x3FFF);
+            if offset >= rom.len() {
+                continue;
+            }
+            // Instructions don't cross banks in practice, so stick to this one
+            let end = ((offset >> 14) + 1) << 14;
+            let length = Instruction::decode(&rom[offset..end.min(rom.len())], addr)
+                .map_or(1, |instruction| instruction.length as usize);
+            map[offset] |= EXECUTED;
+            for byte in &mut map[offset..(offset + length).min(rom.len())] {
+                *byte |= COVERED;
+            }
+        }
+        map
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 * 100.0 / total as f64
+    }
+}
@@ -0,0 +1,168 @@
+//! Rewinding: a ring buffer of save states taken every few frames.
+//!
+//! Only the newest snapshot is kept in full. Every older one is stored as
+//! the XOR with its successor, run-length encoded. Most of a state stays
+//! the same from one snapshot to the next, so the deltas are mostly zeros
+//! and compress to a fraction of a full state. Rewinding undoes the deltas
+//! from the newest end; dropping the oldest snapshot needs no work at all.
+
+use std::collections::VecDeque;
+
+use gameboy::GameBoy;
+use error::Result;
+
+struct Delta {
+    frame: u64,
+    data: Vec<u8>,
+}
+
+pub struct Rewind {
+    interval: u64,
+    capacity: usize,
+    /// Frame and state of the newest snapshot.
+    newest: Option<(u64, Vec<u8>)>,
+    /// Older snapshots, oldest first.
+    deltas: VecDeque<Delta>,
+}
+
+impl Rewind {
+    /// Keeps up to `capacity` snapshots, one every `interval` frames.
+    pub fn new(interval: u64, capacity: usize) -> Rewind {
+        Rewind {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            newest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// Call after every frame; takes a snapshot when one is due.
+    pub fn record(&mut self, gameboy: &GameBoy) {
+        let frame = gameboy.frames();
+        let due = match self.newest {
+            Some((last, _)) => frame >= last + self.interval || frame < last,
+            None => true,
+        };
+        if due {
+            self.push(frame, gameboy.save_state());
+        }
+    }
+
+    fn push(&mut self, frame: u64, state: Vec<u8>) {
+        if let Some((last, previous)) = self.newest.take() {
+            if last < frame && previous.len() == state.len() {
+                self.deltas.push_back(Delta {
+                    frame: last,
+                    data: encode(&previous, &state),
+                });
+            } else {
+                // After a reset or a state from elsewhere the history no longer applies
+                self.deltas.clear();
+            }
+        }
+        self.newest = Some((frame, state));
+        while self.len() > self.capacity {
+            self.deltas.pop_front();
+        }
+    }
+
+    /// Goes back at least `frames` frames, or as far as the buffer reaches,
+    /// and returns the frame number restored. Snapshots newer than the one
+    /// restored are discarded. Nothing happens while the buffer is empty.
+    pub fn rewind(&mut self, gameboy: &mut GameBoy, frames: u64) -> Result<u64> {
+        let target = gameboy.frames().saturating_sub(frames);
+        let (mut frame, mut state) = match self.newest.take() {
+            Some(newest) => newest,
+            None => return Ok(gameboy.frames()),
+        };
+        while frame > target {
+            match self.deltas.pop_back() {
+                Some(delta) => {
+                    decode(&mut state, &delta.data);
+                    frame = delta.frame;
+                },
+                None => break,
+            }
+        }
+        let result = gameboy.load_state(&state);
+        self.newest = Some((frame, state));
+        result.map(|_| frame)
+    }
+
+    /// Number of snapshots held.
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.newest.iter().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    /// Bytes used by the snapshots.
+    pub fn size(&self) -> usize {
+        let newest = self.newest.as_ref().map_or(0, |(_, state)| state.len());
+        newest + self.deltas.iter().map(|delta| delta.data.len()).sum::<usize>()
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+    }
+}
+
+/// XORs `old` with `new` and run-length encodes the result as pairs of
+/// runs: a count of unchanged bytes, then a count of changed bytes followed
+/// by those bytes. Counts are LEB128.
+fn encode(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut out = vec!();
+    let mut i = 0;
+    while i < old.len() {
+        let start = i;
+        while i < old.len() && old[i] == new[i] {
+            i += 1;
+        }
+        write_count(&mut out, i - start);
+        let start = i;
+        while i < old.len() && old[i] != new[i] {
+            i += 1;
+        }
+        write_count(&mut out, i - start);
+        out.extend(old[start..i].iter().zip(&new[start..i]).map(|(a, b)| a ^ b));
+    }
+    out
+}
+
+/// Applies a delta from `encode` to `state`, in either direction.
+fn decode(state: &mut [u8], delta: &[u8]) {
+    let mut delta = delta.iter();
+    let mut i = 0;
+    while let Some(unchanged) = read_count(&mut delta) {
+        i += unchanged;
+        let changed = read_count(&mut delta).unwrap_or(0);
+        for (byte, diff) in state[i..i + changed].iter_mut().zip(&mut delta) {
+            *byte ^= diff;
+        }
+        i += changed;
+    }
+}
+
+fn write_count(out: &mut Vec<u8>, mut count: usize) {
+    while count >= 0x80 {
+        out.push(0x80 | (count & 0x7F) as u8);
+        count >>= 7;
+    }
+    out.push(count as u8);
+}
+
+fn read_count<'a, I: Iterator<Item = &'a u8>>(input: &mut I) -> Option<usize> {
+    let mut count = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *input.next()?;
+        count |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Some(count);
+        }
+        shift += 7;
+    }
+}
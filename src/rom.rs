@@ -1,21 +1,26 @@
 use mmu::Bus;
+use error::Result;
 
 pub struct Rom {
     rom: Vec<u8>
 }
 
 impl Rom {
-    pub fn new(filename : &String) -> Result<Rom,String> {
+    pub fn new(filename : &str) -> Result<Rom> {
         use std::fs::File;
         use std::io::prelude::*;
 
-        let mut file = try!(File::open(filename).map_err(|e| { format!("{}", e)}) );
+        let mut file = File::open(filename)?;
         let mut buffer = vec!();
-        try!(file.read_to_end(&mut buffer).map_err(|e| { format!("{}", e)}) );
+        file.read_to_end(&mut buffer)?;
 
-        Ok(Rom {
-            rom: buffer
-        })
+        Ok(Rom::from_bytes(buffer))
+    }
+
+    pub fn from_bytes(rom: Vec<u8>) -> Rom {
+        Rom {
+            rom
+        }
     }
 
     pub fn as_slice(&self) -> &[u8] {
@@ -25,10 +30,10 @@ impl Rom {
 
 impl Bus for Rom {
     fn read(&self, addr: u16) -> u8 {
-        self.rom[addr as usize]
+        self.rom.get(addr as usize).cloned().unwrap_or(0xFF)
     }
 
-    fn write(&mut self, addr: u16, value: u8) {
-        panic!("Write not supported: 0x{:x} = 0x{:x}", addr, value);
+    fn write(&mut self, _addr: u16, _value: u8) {
+        // ROM is read-only
     }
 }
@@ -0,0 +1,180 @@
+//! Save states: a snapshot of the whole machine in a small binary format.
+//!
+//! A state starts with a header: the magic bytes `GBMS`, the format
+//! `VERSION` and the CRC-32 of the ROM it was taken from. The rest is every
+//! component's state in a fixed order, little-endian, without padding. The
+//! order is defined by the `State` impls, so any change to them has to bump
+//! `VERSION`.
+
+use error::{Error, Result};
+
+pub const MAGIC: &[u8; 4] = b"GBMS";
+pub const VERSION: u16 = 1;
+
+/// Size of the header in front of every state.
+pub const HEADER_SIZE: usize = 10;
+
+/// Something that can be written to and restored from a save state.
+pub trait State {
+    fn save(&self, out: &mut Writer);
+    fn load(&mut self, input: &mut Reader) -> Result<()>;
+}
+
+pub struct Writer {
+    data: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        Writer {
+            data: Vec::with_capacity(0x8000),
+        }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.data.extend_from_slice(value);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Default for Writer {
+    fn default() -> Writer {
+        Writer::new()
+    }
+}
+
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> {
+        Reader { data }
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8]> {
+        if self.data.len() < count {
+            return Err(bad("truncated"));
+        }
+        let (head, tail) = self.data.split_at(count);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            value => Err(bad(&format!("invalid boolean {}", value))),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Number of bytes left.
+    pub fn remaining(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Fills `value` completely.
+    pub fn bytes(&mut self, value: &mut [u8]) -> Result<()> {
+        value.copy_from_slice(self.take(value.len())?);
+        Ok(())
+    }
+
+    /// Fails unless everything has been read.
+    pub fn finish(self) -> Result<()> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(bad("trailing data"))
+        }
+    }
+}
+
+/// Starts a state for the ROM with the given checksum.
+pub fn write_header(out: &mut Writer, checksum: u32) {
+    out.bytes(MAGIC);
+    out.u16(VERSION);
+    out.u32(checksum);
+}
+
+/// Checks the header of a state that should belong to the ROM with the
+/// given checksum.
+pub fn read_header(input: &mut Reader, checksum: u32) -> Result<()> {
+    let mut magic = [0; 4];
+    input.bytes(&mut magic).map_err(|_| bad("not a save state"))?;
+    if &magic != MAGIC {
+        return Err(bad("not a save state"));
+    }
+    let version = input.u16()?;
+    if version != VERSION {
+        return Err(bad(&format!("unsupported version {}", version)));
+    }
+    let found = input.u32()?;
+    if found != checksum {
+        return Err(Error::RomMismatch { expected: checksum, found });
+    }
+    Ok(())
+}
+
+/// Error for a state that is corrupt or from an incompatible version.
+pub fn bad(reason: &str) -> Error {
+    Error::BadSaveState(reason.to_string())
+}
+
+/// CRC-32 (IEEE) of `data`, used to tie a state to its ROM.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+    !crc
+}
@@ -0,0 +1,102 @@
+//! Event-driven timing for the peripherals.
+//!
+//! Rather than being ticked on every M-cycle, each peripheral keeps track of
+//! the time it last caught up to and tells when something it does becomes
+//! visible without anybody touching it: a mode change, a timer overflow, the
+//! end of a serial transfer, a frame sequencer step. The MMU only runs it at
+//! those times, and right before the CPU writes one of its registers. Reads
+//! work out whatever moves steadily in between, like DIV, from the `Clock`.
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use irq::Irq;
+
+/// M-cycles elapsed since power-on, shared by the MMU and its peripherals.
+#[derive(Debug, Clone, Default)]
+pub struct Clock(Rc<Cell<u64>>);
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock::default()
+    }
+
+    pub fn now(&self) -> u64 {
+        self.0.get()
+    }
+
+    /// Moves on by one M-cycle, returning the new time.
+    pub(crate) fn tick(&self) -> u64 {
+        let now = self.0.get() + 1;
+        self.0.set(now);
+        now
+    }
+
+    pub(crate) fn set(&self, now: u64) {
+        self.0.set(now);
+    }
+}
+
+/// A peripheral run by the scheduler.
+pub trait Scheduled {
+    /// Runs everything up to the current time.
+    fn catch_up(&mut self, irq: &mut Irq);
+
+    /// The time it has to be caught up at next, if anything is coming.
+    fn next_event(&self) -> Option<u64>;
+
+    /// Takes its state as the one at the current time, after a load.
+    fn restart(&mut self);
+}
+
+/// Peripherals with events, in the order they run when due together.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Source {
+    Gpu,
+    Timer,
+    Serial,
+    Apu,
+}
+
+impl Source {
+    pub const ALL: [Source; 4] = [Source::Gpu, Source::Timer, Source::Serial, Source::Apu];
+}
+
+const NEVER: u64 = u64::MAX;
+
+/// Next event time of every source.
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    events: [u64; 4],
+    next: u64,
+}
+
+impl Scheduler {
+    pub fn new() -> Scheduler {
+        Scheduler {
+            events: [NEVER; 4],
+            next: NEVER,
+        }
+    }
+
+    pub fn schedule(&mut self, source: Source, at: Option<u64>) {
+        self.events[source as usize] = at.unwrap_or(NEVER);
+        self.next = self.events.iter().copied().min().unwrap_or(NEVER);
+    }
+
+    /// Whether `source` has an event at or before `now`.
+    pub fn is_due(&self, source: Source, now: u64) -> bool {
+        self.events[source as usize] <= now
+    }
+
+    /// Time of the earliest event, `u64::MAX` if there is none.
+    pub fn next(&self) -> u64 {
+        self.next
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        Scheduler::new()
+    }
+}
@@ -0,0 +1,39 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use png;
+use gpu::{Color, SCREEN_WIDTH, SCREEN_HEIGHT};
+
+/// Writes the framebuffer as a binary PGM image.
+pub fn write_pgm(filename: &str, framebuffer: &[Color]) -> io::Result<()> {
+    let mut file = File::create(filename)?;
+    write!(file, "P5\n{} {}\n255\n", SCREEN_WIDTH, SCREEN_HEIGHT)?;
+    let pixels: Vec<u8> = framebuffer.iter().map(|c| c.to_gray()).collect();
+    file.write_all(&pixels)
+}
+
+/// Writes the framebuffer as an 8-bit grayscale PNG image.
+pub fn write_png(filename: &str, framebuffer: &[Color]) -> io::Result<()> {
+    let file = File::create(filename)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(png_error)?;
+    let pixels: Vec<u8> = framebuffer.iter().map(|c| c.to_gray()).collect();
+    writer.write_image_data(&pixels).map_err(png_error)
+}
+
+/// Writes the framebuffer as PNG or PGM depending on the file extension.
+pub fn write(filename: &str, framebuffer: &[Color]) -> io::Result<()> {
+    if filename.to_lowercase().ends_with(".png") {
+        write_png(filename, framebuffer)
+    } else {
+        write_pgm(filename, framebuffer)
+    }
+}
+
+fn png_error(e: png::EncodingError) -> io::Error {
+    match e {
+        png::EncodingError::IoError(e) => e,
+        e => io::Error::other(e),
+    }
+}
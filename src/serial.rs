@@ -0,0 +1,117 @@
+use mmu::Bus;
+use irq::{Irq, Interrupt};
+use scheduler::{Clock, Scheduled};
+use savestate::{State, Writer, Reader};
+use error::Result;
+
+/// M-cycles to shift out one byte using the internal 8192 Hz clock.
+const TRANSFER_CYCLES: usize = 1024;
+
+/// Serial port without a link partner; everything sent is kept in `output`.
+pub struct Serial {
+    clock: Clock,
+    last: u64,
+    data: u8,
+    control: u8,
+    remaining: usize,
+    output: Vec<u8>,
+}
+
+impl Serial {
+    pub fn new(clock: Clock) -> Serial {
+        Serial {
+            last: clock.now(),
+            clock,
+            data: 0,
+            control: 0,
+            remaining: 0,
+            output: vec!(),
+        }
+    }
+
+    /// Every byte transferred so far.
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    fn set_control(&mut self, value: u8) {
+        self.control = value & 0x81;
+        // Only transfers using the internal clock complete without a partner
+        if value & 0x81 == 0x81 {
+            self.remaining = TRANSFER_CYCLES;
+        }
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Serial {
+        Serial::new(Clock::new())
+    }
+}
+
+impl Bus for Serial {
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF01 => self.data,
+            0xFF02 => 0x7E | self.control,
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        match addr {
+            0xFF01 => self.data = value,
+            0xFF02 => self.set_control(value),
+            _ => (),
+        }
+    }
+}
+
+impl Scheduled for Serial {
+    fn catch_up(&mut self, irq: &mut Irq) {
+        let elapsed = (self.clock.now() - self.last) as usize;
+        self.last = self.clock.now();
+        if self.remaining == 0 {
+            return;
+        }
+        self.remaining = self.remaining.saturating_sub(elapsed);
+        if self.remaining == 0 {
+            self.output.push(self.data);
+            // Nobody is connected, so all ones are shifted in
+            self.data = 0xFF;
+            self.control &= 0x7F;
+            irq.request_interrupt(Interrupt::Serial);
+        }
+    }
+
+    /// When the transfer completes.
+    fn next_event(&self) -> Option<u64> {
+        if self.remaining == 0 {
+            None
+        } else {
+            Some(self.last + self.remaining as u64)
+        }
+    }
+
+    fn restart(&mut self) {
+        self.last = self.clock.now();
+    }
+}
+
+/// `output` is a log for the host rather than machine state, so it is left
+/// out and keeps growing across loads.
+impl State for Serial {
+    fn save(&self, out: &mut Writer) {
+        out.u8(self.data);
+        out.u8(self.control);
+        let elapsed = (self.clock.now() - self.last) as usize;
+        out.u16(self.remaining.saturating_sub(elapsed) as u16);
+    }
+
+    fn load(&mut self, input: &mut Reader) -> Result<()> {
+        self.data = input.u8()?;
+        self.control = input.u8()? & 0x81;
+        self.remaining = (input.u16()? as usize).min(TRANSFER_CYCLES);
+        Ok(())
+    }
+}
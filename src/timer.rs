@@ -1,44 +1,184 @@
 use mmu::Bus;
+use irq::{Irq, Interrupt};
+use scheduler::{Clock, Scheduled};
+use savestate::{State, Writer, Reader};
+use error::Result;
 
-//TODO DO proper implementation
-
+/// Runs lazily: `counter` and `counter_value` are as of `last`, and reads
+/// add whatever ticked since.
 pub struct Timer {
+    clock: Clock,
+    last: u64,
+    counter: u16, // DIV is the upper byte
+    counter_value: u8,
     enabled: bool,
     input_clock: u8, //TODO use enum
     modulo: u8,
 }
 
 impl Timer {
-    pub fn new() -> Timer {
+    pub fn new(clock: Clock) -> Timer {
         Timer {
+            last: clock.now(),
+            clock,
+            counter: 0xABCC, // Value left behind by the boot ROM
+            counter_value: 0,
             enabled: false,
             input_clock: 0,
             modulo: 0,
         }
     }
 
+    /// Bit of the internal counter whose falling edge increments TIMA.
+    fn clock_mask(&self) -> u16 {
+        match self.input_clock {
+            0 => 1 << 9, // 4096 Hz
+            1 => 1 << 3, // 262144 Hz
+            2 => 1 << 5, // 65536 Hz
+            _ => 1 << 7, // 16384 Hz
+        }
+    }
+
+    /// Distance between two increments of TIMA, in counter steps.
+    fn period(&self) -> u64 {
+        (self.clock_mask() as u64) << 1
+    }
+
+    fn elapsed(&self) -> u64 {
+        self.clock.now() - self.last
+    }
+
+    fn counter_now(&self) -> u16 {
+        self.counter.wrapping_add((self.elapsed() * 4) as u16)
+    }
+
+    /// Falling edges of the selected bit over the next `elapsed` M-cycles.
+    fn edges(&self, elapsed: u64) -> u64 {
+        if !self.enabled {
+            return 0;
+        }
+        let start = self.counter as u64;
+        (start + elapsed * 4) / self.period() - start / self.period()
+    }
+
+    /// Never overflows, as overflows are events.
+    fn counter_value_now(&self) -> u8 {
+        self.counter_value.wrapping_add(self.edges(self.elapsed()) as u8)
+    }
+
+    fn set_counter(&mut self, counter: u16, irq: &mut Irq) {
+        let mask = self.clock_mask();
+        let falling_edge = self.counter & mask != 0 && counter & mask == 0;
+        self.counter = counter;
+        if self.enabled && falling_edge {
+            self.increment(irq);
+        }
+    }
+
+    fn increment(&mut self, irq: &mut Irq) {
+        let (value, overflow) = self.counter_value.overflowing_add(1);
+        if overflow {
+            self.counter_value = self.modulo;
+            irq.request_interrupt(Interrupt::Timer);
+        } else {
+            self.counter_value = value;
+        }
+    }
+
     fn set_control(&mut self, value: u8) {
         self.enabled = (value & 0x04) != 0;
         self.input_clock = value & 0x03;
     }
 
+    fn get_control(&self) -> u8 {
+        let enabled = if self.enabled { 0x04 } else { 0x00 };
+        0xF8 | enabled | self.input_clock
+    }
+
     fn set_modulo(&mut self, value: u8) {
         self.modulo = value;
     }
+
+    /// Writing DIV resets the whole internal counter, which can itself tick TIMA.
+    pub fn reset_divider(&mut self, irq: &mut Irq) {
+        self.set_counter(0, irq);
+    }
+}
+
+impl Default for Timer {
+    fn default() -> Timer {
+        Timer::new(Clock::new())
+    }
 }
 
 impl Bus for Timer {
     fn write(&mut self, addr: u16, value: u8) {
         match addr {
+            0xFF05 => self.counter_value = value,
             0xFF06 => self.set_modulo(value),
             0xFF07 => self.set_control(value),
-           _ => panic!("Unsupported write 0x{:04x} = 0x{:02x}", addr, value)
+            _ => (), // DIV is reset through reset_divider
         }
     }
 
     fn read(&self, addr: u16) -> u8 {
         match addr {
-            _ => panic!("Unsupported read 0x{:04x}", addr)
+            0xFF04 => (self.counter_now() >> 8) as u8,
+            0xFF05 => self.counter_value_now(),
+            0xFF06 => self.modulo,
+            0xFF07 => self.get_control(),
+            _ => 0xFF,
         }
     }
-}
\ No newline at end of file
+}
+
+impl Scheduled for Timer {
+    fn catch_up(&mut self, irq: &mut Irq) {
+        let elapsed = self.elapsed();
+        let mut edges = self.edges(elapsed);
+        self.counter = self.counter_now();
+        self.last = self.clock.now();
+        while edges > 0 {
+            let to_overflow = 0x100 - self.counter_value as u64;
+            if edges < to_overflow {
+                self.counter_value += edges as u8;
+                break;
+            }
+            edges -= to_overflow;
+            self.counter_value = self.modulo;
+            irq.request_interrupt(Interrupt::Timer);
+        }
+    }
+
+    /// When TIMA overflows.
+    fn next_event(&self) -> Option<u64> {
+        if !self.enabled {
+            return None;
+        }
+        let start = self.counter as u64;
+        let overflow = (start / self.period() + 0x100 - self.counter_value as u64) * self.period();
+        Some(self.last + (overflow - start).div_ceil(4))
+    }
+
+    fn restart(&mut self) {
+        self.last = self.clock.now();
+    }
+}
+
+impl State for Timer {
+    fn save(&self, out: &mut Writer) {
+        out.u16(self.counter_now());
+        out.u8(self.counter_value_now());
+        out.u8(self.modulo);
+        out.u8(self.get_control());
+    }
+
+    fn load(&mut self, input: &mut Reader) -> Result<()> {
+        self.counter = input.u16()?;
+        self.counter_value = input.u8()?;
+        self.modulo = input.u8()?;
+        let control = input.u8()?;
+        self.set_control(control);
+        Ok(())
+    }
+}
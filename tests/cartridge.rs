@@ -0,0 +1,127 @@
+//! MBC1 banking and OAM DMA.
+
+extern crate gbm_rust;
+
+mod common;
+
+use gbm_rust::{Cartridge, GameBoy};
+use gbm_rust::mmu::Bus;
+use gbm_rust::rom::Rom;
+
+/// An MBC1 cartridge with `banks` ROM banks, each starting with its own
+/// number, and 32 KiB of RAM.
+fn mbc1(banks: usize) -> Cartridge {
+    let mut rom = vec!(0; banks * 0x4000);
+    for bank in 0..banks {
+        rom[bank * 0x4000] = bank as u8;
+        rom[bank * 0x4000 + 1] = 0xA5;
+    }
+    rom[0x134..0x138].copy_from_slice(b"MBC1");
+    rom[0x147] = 0x03;
+    rom[0x148] = (banks / 2).trailing_zeros() as u8;
+    rom[0x149] = 0x03;
+    Cartridge::from_rom(Rom::from_bytes(rom)).unwrap()
+}
+
+fn banks(cart: &Cartridge) -> (u8, u8) {
+    (cart.read(0x0000), cart.read(0x4000))
+}
+
+#[test]
+fn switches_rom_banks() {
+    let mut cart = mbc1(64);
+    assert_eq!(banks(&cart), (0, 1));
+    cart.write(0x2000, 0x05);
+    assert_eq!(banks(&cart), (0, 5));
+    assert_eq!(cart.read(0x4001), 0xA5);
+
+    // Bank 0 can't be mapped high, and only five bits count
+    cart.write(0x3FFF, 0x00);
+    assert_eq!(banks(&cart), (0, 1));
+    cart.write(0x2000, 0xE3);
+    assert_eq!(banks(&cart), (0, 3));
+
+    // The RAM bank register adds bits 5 and 6
+    cart.write(0x4000, 0x01);
+    assert_eq!(banks(&cart), (0, 0x23));
+    cart.write(0x2000, 0x20);
+    assert_eq!(banks(&cart), (0, 0x21), "0x20 reads as 0x21");
+    assert_eq!(cart.rom_bank(0x4000), 0x21);
+
+    // Mode 1 maps them over the low area too
+    cart.write(0x6000, 0x01);
+    assert_eq!(banks(&cart), (0x20, 0x21));
+    assert_eq!(cart.rom_bank(0x0000), 0x20);
+
+    cart.reset();
+    assert_eq!(banks(&cart), (0, 1));
+}
+
+#[test]
+fn wraps_banks_to_the_rom_size() {
+    let mut cart = mbc1(8);
+    cart.write(0x2000, 0x0B);
+    assert_eq!(banks(&cart), (0, 3));
+    cart.write(0x4000, 0x03);
+    cart.write(0x6000, 0x01);
+    assert_eq!(banks(&cart), (0, 3));
+}
+
+#[test]
+fn enables_and_banks_ram() {
+    let mut cart = mbc1(64);
+    cart.write(0xA000, 0x11);
+    assert_eq!(cart.read(0xA000), 0xFF, "disabled at power-on");
+
+    cart.write(0x0000, 0x0A);
+    cart.write(0xA000, 0x11);
+    assert_eq!(cart.read(0xA000), 0x11);
+
+    // Mode 0 always uses the first bank
+    cart.write(0x4000, 0x02);
+    assert_eq!(cart.read(0xA000), 0x11);
+    cart.write(0x6000, 0x01);
+    assert_eq!(cart.read(0xA000), 0x00);
+    cart.write(0xBFFF, 0x22);
+    cart.write(0x4000, 0x00);
+    assert_eq!(cart.read(0xA000), 0x11);
+    assert_eq!(cart.read(0xBFFF), 0x00);
+    cart.write(0x4000, 0x02);
+    assert_eq!(cart.read(0xBFFF), 0x22);
+
+    // Anything but 0x0A in the low nibble disables it again
+    cart.write(0x1FFF, 0x1B);
+    assert_eq!(cart.read(0xBFFF), 0xFF);
+    cart.write(0x1FFF, 0xFA);
+    assert_eq!(cart.read(0xBFFF), 0x22);
+}
+
+#[test]
+fn rom_only_ignores_bank_writes() {
+    let gameboy = common::gameboy(b"ROM ONLY");
+    let mut cart = Cartridge::from_rom(Rom::from_bytes(gameboy.mmu().cartridge().rom().to_vec())).unwrap();
+    let (low, high) = banks(&cart);
+    for &addr in &[0x0000, 0x2000, 0x4000, 0x6000] {
+        cart.write(addr, 0x03);
+    }
+    assert_eq!(banks(&cart), (low, high));
+    assert_eq!(cart.rom_bank(0x7FFF), 1);
+}
+
+#[test]
+fn dma_copies_to_oam() {
+    let mut gameboy: GameBoy = common::gameboy(b"DMA");
+    let mmu = gameboy.mmu_mut();
+    for i in 0..0xA0 {
+        mmu.write(0xC100 + i, (i as u8).wrapping_mul(7));
+    }
+    mmu.write(0xFF46, 0xC1);
+    for i in 0..0xA0 {
+        assert_eq!(mmu.peek(0xFE00 + i), (i as u8).wrapping_mul(7), "OAM 0x{:02x}", i);
+    }
+
+    // From ROM as well
+    mmu.write(0xFF46, 0x01);
+    assert_eq!(mmu.peek(0xFE50), mmu.peek(0x0150));
+    assert_eq!(mmu.peek(0xFE9F), mmu.peek(0x019F));
+}
@@ -0,0 +1,107 @@
+//! Game Genie and GameShark codes.
+
+extern crate gbm_rust;
+
+mod common;
+
+use gbm_rust::Error;
+use gbm_rust::cheats::{Cheat, Cheats, Code, GameGenie, GameShark};
+use gbm_rust::mmu::Bus;
+use common::gameboy;
+
+// 0x0151 holds the operand of LD A,0x05
+const PATCH: &str = "071-51F";
+const PATCH_OVER_05: &str = "071-51F-FAE";
+const PATCH_OVER_06: &str = "071-51F-FA2";
+const STORE: &str = "014200C1";
+
+#[test]
+fn decodes_codes() {
+    assert_eq!(Code::parse(PATCH).unwrap(), Code::GameGenie(GameGenie { addr: 0x0151, value: 0x07, compare: None }));
+    assert_eq!(Code::parse(PATCH_OVER_05).unwrap(),
+               Code::GameGenie(GameGenie { addr: 0x0151, value: 0x07, compare: Some(0x05) }));
+    assert_eq!(Code::parse("00A-17B-C49").unwrap(),
+               Code::GameGenie(GameGenie { addr: 0x4A17, value: 0x00, compare: Some(0xC8) }));
+    assert_eq!(Code::parse("071-51f-fae").unwrap(), Code::parse(PATCH_OVER_05).unwrap());
+    assert_eq!(Code::parse(STORE).unwrap(), Code::GameShark(GameShark { bank: 0x01, value: 0x42, addr: 0xC100 }));
+}
+
+#[test]
+fn refuses_bad_codes() {
+    for code in &["0123", "01FF3CC", "XYZ-DEF", "000-127", "01420001", "0142-00C1"] {
+        match Code::parse(code) {
+            Err(Error::BadCheat(_)) => (),
+            other => panic!("{} gave {:?}", code, other),
+        }
+    }
+}
+
+#[test]
+fn game_genie_patches_rom_reads() {
+    let mut gameboy = gameboy(b"CHEATS");
+    let mut cheats = Cheats::new();
+    cheats.add(Cheat::new(PATCH_OVER_06).unwrap());
+    cheats.add(Cheat::new(PATCH_OVER_05).unwrap());
+    gameboy.set_cheats(cheats);
+    assert_eq!(gameboy.mmu().peek(0x0151), 0x07);
+    assert_eq!(gameboy.mmu().cartridge().rom()[0x0151], 0x05, "the ROM itself stays as it is");
+
+    // NOP, JP 0x0150, LD A,0x07 then LDH (TAC),A
+    for _ in 0..4 {
+        gameboy.step().unwrap();
+    }
+    assert_eq!(gameboy.cpu().registers().a, 0x07);
+    assert_eq!(gameboy.mmu().peek(0xFF07) & 0x07, 0x07);
+
+    assert!(gameboy.set_cheat_enabled(1, false));
+    assert_eq!(gameboy.mmu().peek(0x0151), 0x05, "the other code doesn't match");
+    assert!(!gameboy.set_cheat_enabled(2, false));
+}
+
+#[test]
+fn gameshark_writes_every_frame() {
+    let mut gameboy = gameboy(b"CHEATS");
+    let mut cheats = Cheats::new();
+    cheats.add(Cheat::new(STORE).unwrap());
+    gameboy.set_cheats(cheats);
+    assert_eq!(gameboy.mmu().peek(0xC100), 0x00);
+
+    gameboy.run_frame().unwrap();
+    assert_eq!(gameboy.mmu().peek(0xC100), 0x42);
+    gameboy.mmu_mut().write(0xC100, 0x00);
+    gameboy.run_frame().unwrap();
+    assert_eq!(gameboy.mmu().peek(0xC100), 0x42);
+
+    gameboy.set_cheat_enabled(0, false);
+    gameboy.mmu_mut().write(0xC100, 0x00);
+    gameboy.run_frame().unwrap();
+    assert_eq!(gameboy.mmu().peek(0xC100), 0x00);
+}
+
+#[test]
+fn reads_cheats_files_by_rom() {
+    let gameboy = gameboy(b"CHEATS");
+    let cart = gameboy.mmu().cartridge();
+    let text = format!("\
+; Cheats for a few games
+[OTHER GAME]
+000-000 Not for us
+[CHEATS]
+071-51F-FAE More timer ; faster
+!014200C1 Store 42
+[{:08X}]
+014300C2
+", cart.checksum());
+    let cheats = Cheats::parse(&text, cart).unwrap();
+    assert_eq!(cheats.len(), 3);
+    let names: Vec<(&str, &str, bool)> = cheats.iter()
+        .map(|cheat| (cheat.text.as_str(), cheat.name.as_str(), cheat.enabled))
+        .collect();
+    assert_eq!(names, [("071-51F-FAE", "More timer", true), ("014200C1", "Store 42", false), ("014300C2", "", true)]);
+
+    match Cheats::parse("[CHEATS]\n\n071-51F-FAE\n8000 Broken\n", cart) {
+        Err(Error::BadCheat(reason)) => assert!(reason.starts_with("line 4:"), "{}", reason),
+        other => panic!("{:?}", other),
+    }
+    assert!(Cheats::parse("[OTHER GAME]\n8000 Broken\n", cart).unwrap().is_empty());
+}
@@ -0,0 +1,49 @@
+//! Small generated ROMs. The default program keeps the CPU, timer, APU
+//! and PPU busy.
+
+// Not every test uses every helper
+#![allow(dead_code)]
+
+use gbm_rust::{Cartridge, GameBoy};
+use gbm_rust::rom::Rom;
+
+const PROGRAM: &[u8] = &[
+    0x3E, 0x05,       // LD A,0x05
+    0xE0, 0x07,       // LDH (TAC),A
+    0x3E, 0x80,       // LD A,0x80
+    0xE0, 0x26,       // LDH (NR52),A
+    0x3E, 0x87,       // LD A,0x87
+    0xE0, 0x14,       // LDH (NR14),A
+    0x21, 0x00, 0x80, // LD HL,0x8000
+    // loop:
+    0xF0, 0x04,       // LDH A,(DIV)
+    0x22,             // LD (HL+),A
+    0xEA, 0x00, 0xC0, // LD (0xC000),A
+    0x7C,             // LD A,H
+    0xE6, 0x17,       // AND 0x17
+    0xF6, 0x80,       // OR 0x80
+    0x67,             // LD H,A
+    0x18, 0xF1,       // JR loop
+];
+
+pub fn gameboy(title: &[u8]) -> GameBoy {
+    with_program(title, PROGRAM)
+}
+
+/// A ROM that jumps straight to `program` at 0x0150.
+pub fn with_program(title: &[u8], program: &[u8]) -> GameBoy {
+    let mut rom = vec!(0; 0x8000);
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    rom[0x134..0x134 + title.len()].copy_from_slice(title);
+    rom[0x150..0x150 + program.len()].copy_from_slice(program);
+    GameBoy::new(Cartridge::from_rom(Rom::from_bytes(rom)).unwrap())
+}
+
+pub fn run(gameboy: &mut GameBoy, frames: usize) -> Vec<i16> {
+    let mut audio = vec!();
+    for _ in 0..frames {
+        gameboy.run_frame().unwrap();
+        audio.extend(gameboy.drain_audio());
+    }
+    audio
+}
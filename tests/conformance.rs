@@ -0,0 +1,162 @@
+//! Runs Blargg's and Mooneye's test ROMs through the headless runner.
+//!
+//! The ROMs aren't part of the repository, so these tests are ignored by
+//! default and show up as such. Point `GBM_TEST_ROMS` at a directory laid
+//! out as below (defaults to `tests/roms`) and run them with
+//!
+//! ```text
+//! GBM_TEST_ROMS=~/gb-test-roms cargo test --release --test conformance -- --ignored --nocapture
+//! ```
+//!
+//! ```text
+//! blargg/cpu_instrs/individual/*.gb
+//! blargg/instr_timing/*.gb
+//! blargg/mem_timing/individual/*.gb
+//! mooneye/acceptance/**/*.gb
+//! ```
+//!
+//! A suite that is missing fails, as does an empty one. ROMs listed in
+//! `tests/conformance_expected_failures.txt` are reported but don't fail
+//! the run, so only regressions do; one that starts passing fails it too,
+//! so the list can't go stale. Setting `GBM_RECORD_FAILURES=1` rewrites
+//! the list from the run instead of checking it.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+/// Suites run in parallel, and each rewrites its part of the list.
+static EXPECTED_FAILURES: Mutex<()> = Mutex::new(());
+
+fn expected_failures_path() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("conformance_expected_failures.txt")
+}
+
+fn rom_root() -> PathBuf {
+    match env::var_os("GBM_TEST_ROMS") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms"),
+    }
+}
+
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            find_roms(&path, roms);
+        } else if path.extension().is_some_and(|ext| ext == "gb") {
+            roms.push(path);
+        }
+    }
+}
+
+fn expected_failures() -> Vec<String> {
+    fs::read_to_string(expected_failures_path()).unwrap_or_default()
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect()
+}
+
+/// Replaces the entries for `suite` in the list with `failures`, keeping
+/// the comments and every other suite's entries.
+fn record_failures(suite: &str, failures: &[String]) {
+    let path = expected_failures_path();
+    let prefix = format!("{}/", suite);
+    let mut lines: Vec<String> = fs::read_to_string(&path).unwrap_or_default()
+        .lines()
+        .filter(|line| !line.trim().starts_with(&prefix))
+        .map(|line| line.to_string())
+        .collect();
+    lines.extend(failures.iter().cloned());
+    let (comments, mut entries): (Vec<String>, Vec<String>) = lines.into_iter()
+        .filter(|line| !line.trim().is_empty())
+        .partition(|line| line.starts_with('#'));
+    entries.sort();
+    let text: Vec<String> = comments.into_iter().chain(entries).collect();
+    fs::write(&path, text.join("\n") + "\n").unwrap();
+}
+
+fn run_suite(suite: &str, protocol: &str, frames: u64) {
+    let root = rom_root();
+    let dir = root.join(suite);
+    assert!(dir.is_dir(), "{} not found, set GBM_TEST_ROMS to where the test ROMs are", dir.display());
+
+    let expected_failures = expected_failures();
+    let mut roms = vec!();
+    find_roms(&dir, &mut roms);
+    assert!(!roms.is_empty(), "No ROMs in {}", dir.display());
+
+    let mut failures = vec!();
+    let mut regressions = vec!();
+    let mut fixed = vec!();
+    let mut passed = 0;
+    for rom in &roms {
+        let name = rom.strip_prefix(&root).unwrap().to_string_lossy().replace('\\', "/");
+        let output = Command::new(env!("CARGO_BIN_EXE_gbm-rust"))
+            .arg("headless")
+            .arg("--check").arg(protocol)
+            .arg("--frames").arg(frames.to_string())
+            .arg(rom)
+            .output()
+            .unwrap();
+        let expected_failure = expected_failures.contains(&name);
+        let status = match (output.status.success(), expected_failure) {
+            (true, false) => "PASS",
+            (true, true) => "XPASS",
+            (false, true) => "XFAIL",
+            (false, false) => "FAIL",
+        };
+        println!("{:5} {}", status, name);
+        if output.status.success() {
+            passed += 1;
+            if expected_failure {
+                fixed.push(name);
+            }
+        } else {
+            if !expected_failure {
+                regressions.push(name.clone());
+            }
+            failures.push(name);
+        }
+    }
+
+    println!("{}: {} of {} passed", suite, passed, roms.len());
+    if env::var_os("GBM_RECORD_FAILURES").is_some() {
+        let _lock = EXPECTED_FAILURES.lock().unwrap_or_else(|e| e.into_inner());
+        record_failures(suite, &failures);
+        return;
+    }
+    assert!(regressions.is_empty(), "Unexpected failures:\n{}", regressions.join("\n"));
+    assert!(fixed.is_empty(), "Passing now, take them off the expected failures:\n{}", fixed.join("\n"));
+}
+
+#[test]
+#[ignore = "needs the test ROMs, see the module docs"]
+fn blargg_cpu_instrs() {
+    run_suite("blargg/cpu_instrs", "blargg", 4000);
+}
+
+#[test]
+#[ignore = "needs the test ROMs, see the module docs"]
+fn blargg_instr_timing() {
+    run_suite("blargg/instr_timing", "blargg", 600);
+}
+
+#[test]
+#[ignore = "needs the test ROMs, see the module docs"]
+fn blargg_mem_timing() {
+    run_suite("blargg/mem_timing", "blargg", 600);
+}
+
+#[test]
+#[ignore = "needs the test ROMs, see the module docs"]
+fn mooneye_acceptance() {
+    run_suite("mooneye/acceptance", "mooneye", 600);
+}
@@ -0,0 +1,154 @@
+//! Debugger commands against a small program with nested calls.
+
+extern crate gbm_rust;
+
+mod common;
+
+use gbm_rust::GameBoy;
+use gbm_rust::debugger::{Debugger, Output};
+use gbm_rust::mmu::Bus;
+
+const PROGRAM: &[u8] = &[
+    0x31, 0xFE, 0xFF, // 0150 LD SP,0xFFFE
+    0xCD, 0x60, 0x01, // 0153 CALL 0x0160
+    0xEA, 0x00, 0xC0, // 0156 LD (0xC000),A
+    0x18, 0xF8,       // 0159 JR 0x0153
+    0x00, 0x00, 0x00, 0x00, 0x00,
+    0x3C,             // 0160 INC A
+    0xCD, 0x70, 0x01, // 0161 CALL 0x0170
+    0xC9,             // 0164 RET
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x04,             // 0170 INC B
+    0xC9,             // 0171 RET
+];
+
+fn execute(debugger: &mut Debugger, gameboy: &mut GameBoy, line: &str) -> String {
+    match debugger.execute(gameboy, line) {
+        Output::Text(text) => text,
+        Output::Quit => panic!("{} quit the debugger", line),
+    }
+}
+
+fn pc(gameboy: &GameBoy) -> u16 {
+    gameboy.cpu().registers().pc
+}
+
+#[test]
+fn breaks_and_steps() {
+    let mut gameboy = common::with_program(b"DEBUG", PROGRAM);
+    let mut debugger = Debugger::new();
+
+    execute(&mut debugger, &mut gameboy, "break 0153");
+    let text = execute(&mut debugger, &mut gameboy, "continue");
+    assert!(text.starts_with("Breakpoint hit at 0x0153"), "{}", text);
+
+    // Stepping over the call runs both functions
+    execute(&mut debugger, &mut gameboy, "next");
+    assert_eq!(pc(&gameboy), 0x0156);
+    assert_eq!(gameboy.cpu().registers().b, 0x01);
+
+    // An empty line repeats the last command
+    execute(&mut debugger, &mut gameboy, "");
+    assert_eq!(pc(&gameboy), 0x0159);
+
+    execute(&mut debugger, &mut gameboy, "delete");
+    execute(&mut debugger, &mut gameboy, "step 3");
+    assert_eq!(pc(&gameboy), 0x0161);
+    execute(&mut debugger, &mut gameboy, "finish");
+    assert_eq!(pc(&gameboy), 0x0156);
+}
+
+#[test]
+fn breakpoints_respect_banks() {
+    let mut gameboy = common::with_program(b"DEBUG", PROGRAM);
+    let mut debugger = Debugger::new();
+    execute(&mut debugger, &mut gameboy, "break 01:0160");
+    execute(&mut debugger, &mut gameboy, "break 00:0170");
+    execute(&mut debugger, &mut gameboy, "continue");
+    assert_eq!(pc(&gameboy), 0x0170);
+}
+
+#[test]
+fn watches_memory() {
+    let mut gameboy = common::with_program(b"DEBUG", PROGRAM);
+    let mut debugger = Debugger::new();
+    execute(&mut debugger, &mut gameboy, "watch c000");
+    let text = execute(&mut debugger, &mut gameboy, "continue");
+    assert!(text.starts_with("Write of 0x02 at 0xc000"), "{}", text);
+    assert_eq!(pc(&gameboy), 0x0159);
+
+    execute(&mut debugger, &mut gameboy, "delete");
+    execute(&mut debugger, &mut gameboy, "rwatch fffd");
+    let text = execute(&mut debugger, &mut gameboy, "continue");
+    assert!(text.starts_with("Read of 0x01 at 0xfffd"), "{}", text);
+
+    // Fetching an instruction reads it too
+    execute(&mut debugger, &mut gameboy, "delete");
+    execute(&mut debugger, &mut gameboy, "rwatch 0171");
+    let text = execute(&mut debugger, &mut gameboy, "continue");
+    assert!(text.starts_with("Read of 0xc9 at 0x0171"), "{}", text);
+}
+
+#[test]
+fn edits_registers_and_memory() {
+    let mut gameboy = common::with_program(b"DEBUG", PROGRAM);
+    let mut debugger = Debugger::new();
+    execute(&mut debugger, &mut gameboy, "set hl c123");
+    execute(&mut debugger, &mut gameboy, "set a 7f");
+    assert_eq!(gameboy.cpu().registers().h, 0xC1);
+    assert_eq!(gameboy.cpu().registers().l, 0x23);
+    assert_eq!(gameboy.cpu().registers().a, 0x7F);
+
+    let text = execute(&mut debugger, &mut gameboy, "write c000 12 34");
+    assert_eq!(text, "c000: 12 34");
+    assert_eq!(execute(&mut debugger, &mut gameboy, "x $c000 3"), "c000: 12 34 00");
+    assert!(execute(&mut debugger, &mut gameboy, "set a 100").starts_with("Can't set"));
+}
+
+#[test]
+fn writes_dont_trip_watchpoints() {
+    let mut gameboy = common::with_program(b"DEBUG", PROGRAM);
+    let mut debugger = Debugger::new();
+    execute(&mut debugger, &mut gameboy, "break 0150");
+    execute(&mut debugger, &mut gameboy, "continue");
+    execute(&mut debugger, &mut gameboy, "awatch c000");
+    execute(&mut debugger, &mut gameboy, "write c000 55");
+    let text = execute(&mut debugger, &mut gameboy, "step");
+    assert!(text.starts_with("> 00:0153"), "{}", text);
+    let text = execute(&mut debugger, &mut gameboy, "continue");
+    assert!(text.starts_with("Write of 0x02 at 0xc000"), "{}", text);
+}
+
+#[test]
+fn wraps_around_the_address_space() {
+    let mut gameboy = common::with_program(b"DEBUG", PROGRAM);
+    let mut debugger = Debugger::new();
+    let text = execute(&mut debugger, &mut gameboy, "x fff8 20");
+    let rows: Vec<&str> = text.lines().collect();
+    assert_eq!(rows.len(), 2, "{}", text);
+    assert!(rows[0].starts_with("fff8: "), "{}", text);
+    assert!(rows[1].starts_with("0008: "), "{}", text);
+
+    // Anything past the whole space is cut short rather than overflowing
+    let text = execute(&mut debugger, &mut gameboy, "x c000 70000");
+    assert_eq!(text.lines().count(), 0x1000);
+    assert!(text.lines().last().unwrap().starts_with("bff0: "));
+
+    execute(&mut debugger, &mut gameboy, "set pc ffff");
+    let text = execute(&mut debugger, &mut gameboy, "list");
+    assert!(text.lines().any(|line| line.starts_with("> --:ffff")), "{}", text);
+}
+
+#[test]
+fn adds_and_toggles_cheats() {
+    let mut gameboy = common::with_program(b"DEBUG", PROGRAM);
+    let mut debugger = Debugger::new();
+    assert_eq!(execute(&mut debugger, &mut gameboy, "cheat"), "No cheats");
+    assert_eq!(execute(&mut debugger, &mut gameboy, "cheat 041-60F"), "Cheat 1: Game Genie: 0x04 at 0x0160");
+    assert_eq!(gameboy.mmu().peek(0x0160), 0x04);
+    assert_eq!(execute(&mut debugger, &mut gameboy, "cheat off 1"), "Cheat 1 off");
+    assert_eq!(gameboy.mmu().peek(0x0160), 0x3C);
+    assert_eq!(execute(&mut debugger, &mut gameboy, "cheat"), "1: 041-60F off Game Genie: 0x04 at 0x0160");
+    assert_eq!(execute(&mut debugger, &mut gameboy, "cheat on 2"), "No cheat 2");
+    assert!(execute(&mut debugger, &mut gameboy, "cheat 12345").starts_with("Bad cheat"));
+}
@@ -0,0 +1,174 @@
+//! Static disassembly of whole ROMs.
+//!
+//! Assembling the output back needs RGBDS, so that test is ignored by
+//! default. Point `GBM_RGBDS` at the directory holding `rgbasm` and
+//! `rgblink` and run it with
+//!
+//! ```text
+//! GBM_RGBDS=/usr/local/bin cargo test --test disasm -- --ignored
+//! ```
+
+extern crate gbm_rust;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use gbm_rust::Error;
+use gbm_rust::disasm::{Disassembler, Symbols};
+
+const PROGRAM: &[u8] = &[
+    0xCD, 0x70, 0x01, // 0150: CALL 0x0170
+    0xFA, 0xA0, 0xC0, // 0153: LD A,(0xC0A0)
+    0xE0, 0x44,       // 0156: LDH (LY),A
+    0x28, 0xFE,       // 0158: JR Z,0x0158
+    0xCD, 0x00, 0x40, // 015A: CALL 0x4000
+    0xC3, 0x50, 0x01, // 015D: JP 0x0150
+];
+
+const MAIN: &[u8] = &[
+    0x10, 0x01,       // 0170: STOP, padded with 0x01
+    0xFF,             // 0172: RST 0x38
+    0xC9,             // 0173: RET
+];
+
+const SYMBOLS: &str = "\
+; File generated by rgblink
+00:0170 Main
+00:c0a0 wScore
+00:ff44 rLY
+";
+
+fn rom() -> Vec<u8> {
+    let mut rom = vec!(0; 0x8000);
+    rom[0x38] = 0xC9;
+    rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    rom[0x150..0x150 + PROGRAM.len()].copy_from_slice(PROGRAM);
+    rom[0x170..0x170 + MAIN.len()].copy_from_slice(MAIN);
+    // Not reachable, as the JP before them never falls through
+    rom[0x160..0x162].copy_from_slice(&[0xDE, 0xAD]);
+    rom[0x4000] = 0xC9;
+    rom
+}
+
+/// Reads and writes of 0xFFxx through full 16-bit addresses.
+const HIGH_MEMORY: &[u8] = &[
+    0xFA, 0x44, 0xFF, // 0150: LD A,(0xFF44)
+    0xEA, 0x80, 0xFF, // 0153: LD (0xFF80),A
+    0xEA, 0x00, 0xFE, // 0156: LD (0xFE00),A
+    0xF0, 0x44,       // 0159: LDH A,(LY)
+    0xC3, 0x50, 0x01, // 015B: JP 0x0150
+];
+
+fn high_memory_rom() -> Vec<u8> {
+    let mut rom = rom();
+    rom[0x150..0x150 + HIGH_MEMORY.len()].copy_from_slice(HIGH_MEMORY);
+    rom
+}
+
+/// Assembles and links `source` with the rgbasm and rgblink in `rgbds`.
+fn assemble(rgbds: &Path, name: &str, source: &str) -> Vec<u8> {
+    let dir = env::temp_dir().join(format!("gbm-rust-disasm-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let asm = dir.join("rom.asm");
+    let object = dir.join("rom.o");
+    let linked = dir.join("rom.gb");
+    fs::write(&asm, source).unwrap();
+    let status = Command::new(rgbds.join("rgbasm")).arg("-o").arg(&object).arg(&asm).status()
+        .unwrap_or_else(|e| panic!("rgbasm in {}: {}", rgbds.display(), e));
+    assert!(status.success(), "rgbasm failed on\n{}", source);
+    let status = Command::new(rgbds.join("rgblink")).arg("-o").arg(&linked).arg(&object).status()
+        .unwrap_or_else(|e| panic!("rgblink in {}: {}", rgbds.display(), e));
+    assert!(status.success(), "rgblink failed on\n{}", source);
+    let rom = fs::read(&linked).unwrap();
+    let _ = fs::remove_dir_all(dir);
+    rom
+}
+
+fn lines(source: &str) -> Vec<&str> {
+    source.lines().collect()
+}
+
+/// Whether `expected` shows up in `source` as consecutive lines.
+fn contains(source: &str, expected: &[&str]) -> bool {
+    lines(source).windows(expected.len()).any(|window| window == expected)
+}
+
+#[test]
+fn follows_code_from_entry_point() {
+    let rom = rom();
+    let symbols = Symbols::new();
+    let disassembler = Disassembler::new(&rom, &symbols);
+    assert!(disassembler.is_code(0x0150));
+    assert!(disassembler.is_code(0x0170));
+    assert!(!disassembler.is_code(0x0160));
+    assert!(!disassembler.is_code(0x0170 + MAIN.len()));
+    assert!(!disassembler.is_code(0x0040), "padding vectors are not code");
+
+    let source = disassembler.source();
+    assert!(contains(&source, &["SECTION \"ROM Bank $000\", ROM0[$0000]", "    ds 56, $00", "L00_0038:", "    ret"]), "{}", source);
+    assert!(contains(&source, &["    nop", "    jp L00_0150"]), "{}", source);
+    assert!(contains(&source, &["L00_0150:", "    call L00_0170", "    ld a, [$c0a0]", "    ldh [$ff44], a"]), "{}", source);
+    assert!(contains(&source, &["L00_0158:", "    jr z, L00_0158", "    call L01_4000", "    jp L00_0150", "    db $de, $ad, $00, $00, $00, $00, $00, $00"]), "{}", source);
+    // rgbasm would pad STOP with a zero
+    assert!(contains(&source, &["L00_0170:", "    db $10, $01", "    rst $38", "    ret"]), "{}", source);
+    assert!(contains(&source, &["SECTION \"ROM Bank $001\", ROMX[$4000], BANK[$1]", "L01_4000:", "    ret", "    ds 16383, $00"]), "{}", source);
+}
+
+#[test]
+fn names_addresses_after_symbols() {
+    let rom = rom();
+    let symbols = Symbols::parse(SYMBOLS).unwrap();
+    let source = Disassembler::new(&rom, &symbols).source();
+    assert!(contains(&source, &["DEF wScore EQU $c0a0", "DEF rLY EQU $ff44"]), "{}", source);
+    assert!(contains(&source, &["    call Main", "    ld a, [wScore]", "    ldh [rLY], a"]), "{}", source);
+    assert!(contains(&source, &["Main:", "    db $10, $01"]), "{}", source);
+}
+
+#[test]
+fn leaves_banked_code_alone() {
+    // With more than two banks, bank 0 can't tell which one a call lands in
+    let mut rom = rom();
+    rom.resize(0x10000, 0);
+    let symbols = Symbols::new();
+    let source = Disassembler::new(&rom, &symbols).source();
+    assert!(contains(&source, &["    call $4000"]), "{}", source);
+    assert!(!source.contains("L01_4000"), "{}", source);
+    assert!(source.contains("SECTION \"ROM Bank $003\", ROMX[$4000], BANK[$3]"), "{}", source);
+}
+
+#[test]
+fn rejects_bad_symbol_files() {
+    match Symbols::parse("00:0150 Start\nnonsense\n") {
+        Err(Error::BadSymbols(reason)) => assert_eq!(reason, "line 2: nonsense"),
+        other => panic!("expected a bad symbol file, got {:?}", other),
+    }
+}
+
+#[test]
+fn keeps_the_long_form_of_high_memory_loads() {
+    let rom = high_memory_rom();
+    let symbols = Symbols::parse(SYMBOLS).unwrap();
+    let source = Disassembler::new(&rom, &symbols).source();
+    assert!(contains(&source, &[
+        "L00_0150:",
+        "    db $fa, $44, $ff ; ld a, [rLY]",
+        "    db $ea, $80, $ff ; ld [$ff80], a",
+        "    ld [$fe00], a",
+        "    ldh a, [rLY]",
+        "    jp L00_0150",
+    ]), "{}", source);
+}
+
+#[test]
+#[ignore = "needs RGBDS, see the module docs"]
+fn assembles_back_into_the_same_rom() {
+    let rgbds = env::var_os("GBM_RGBDS").expect("Point GBM_RGBDS at the directory with rgbasm and rgblink");
+    let symbols = Symbols::parse(SYMBOLS).unwrap();
+    for &(name, ref rom) in &[("program", rom()), ("high-memory", high_memory_rom())] {
+        let source = Disassembler::new(rom, &symbols).source();
+        let assembled = assemble(Path::new(&rgbds), name, &source);
+        assert!(assembled == *rom, "{} doesn't assemble back:\n{}", name, source);
+    }
+}
@@ -0,0 +1,137 @@
+//! The GDB remote serial protocol stub, driven over a real socket.
+
+extern crate gbm_rust;
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+use gbm_rust::{Cartridge, GameBoy};
+use gbm_rust::gdb::Stub;
+use gbm_rust::rom::Rom;
+
+struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    /// Starts a stub on the test program and connects to it.
+    fn connect() -> (Client, thread::JoinHandle<()>) {
+        Client::connect_to(|| common::gameboy(b"GDB"))
+    }
+
+    /// Starts a stub on the machine `gameboy` builds and connects to it.
+    fn connect_to(gameboy: fn() -> GameBoy) -> (Client, thread::JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut gameboy = gameboy();
+            let (stream, _) = listener.accept().unwrap();
+            Stub::new().serve(&mut gameboy, stream).unwrap();
+        });
+        (Client { stream: TcpStream::connect(addr).unwrap() }, server)
+    }
+
+    fn byte(&mut self) -> u8 {
+        let mut byte = [0];
+        self.stream.read_exact(&mut byte).unwrap();
+        byte[0]
+    }
+
+    /// Sends a packet and returns the reply.
+    fn request(&mut self, data: &str) -> String {
+        let sum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        write!(self.stream, "${}#{:02x}", data, sum).unwrap();
+        assert_eq!(self.byte(), b'+');
+        assert_eq!(self.byte(), b'$');
+        let mut reply = vec!();
+        loop {
+            match self.byte() {
+                b'#' => break,
+                byte => reply.push(byte),
+            }
+        }
+        let checksum = [self.byte(), self.byte()];
+        let sum = reply.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        assert_eq!(std::str::from_utf8(&checksum).unwrap(), format!("{:02x}", sum));
+        self.stream.write_all(b"+").unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+}
+
+#[test]
+fn reads_and_writes_registers_and_memory() {
+    let (mut client, server) = Client::connect();
+    assert!(client.request("qSupported:swbreak+").contains("qXfer:features:read+"));
+    assert!(client.request("qXfer:features:read:target.xml:0,1000").starts_with("l<?xml"));
+    assert_eq!(client.request("?"), "S05");
+    // AF, BC, DE, HL, SP and PC, little-endian
+    assert_eq!(client.request("g"), "b0011300d8004d01feff0001");
+    assert_eq!(client.request("p5"), "0001");
+    assert_eq!(client.request("P3=00c1"), "OK");
+    assert_eq!(client.request("p3"), "00c1");
+    assert_eq!(client.request("m150,2"), "3e05");
+    assert_eq!(client.request("Mc000,2:1234"), "OK");
+    assert_eq!(client.request("mc000,3"), "123400");
+    assert_eq!(client.request("mffff,2"), "E01");
+    assert_eq!(client.request("D"), "OK");
+    server.join().unwrap();
+}
+
+/// An MBC1 cartridge with four ROM banks, each starting with its own number.
+fn mbc1() -> GameBoy {
+    let mut rom = vec!(0; 4 * 0x4000);
+    for bank in 0..4 {
+        rom[bank * 0x4000] = bank as u8;
+    }
+    rom[0x147] = 0x01;
+    rom[0x148] = 0x01;
+    GameBoy::new(Cartridge::from_rom(Rom::from_bytes(rom)).unwrap())
+}
+
+#[test]
+fn refuses_to_write_rom() {
+    let (mut client, server) = Client::connect_to(mbc1);
+    assert_eq!(client.request("m4000,1"), "01");
+    // On the MBC this would select bank 3
+    assert_eq!(client.request("M2000,1:03"), "E02");
+    assert_eq!(client.request("M7fff,2:0303"), "E02");
+    assert_eq!(client.request("m4000,1"), "01");
+    assert_eq!(client.request("D"), "OK");
+    server.join().unwrap();
+}
+
+#[test]
+fn stops_at_breakpoints_and_watchpoints() {
+    let (mut client, server) = Client::connect();
+    assert_eq!(client.request("s"), "S05");
+    assert_eq!(client.request("p5"), "0101");
+
+    // LD (0xC000),A in the loop
+    assert_eq!(client.request("Z0,162,1"), "OK");
+    assert_eq!(client.request("c"), "T05swbreak:;");
+    assert_eq!(client.request("p5"), "6201");
+    assert_eq!(client.request("z0,162,1"), "OK");
+
+    assert_eq!(client.request("Z2,c000,1"), "OK");
+    assert_eq!(client.request("vCont;c"), "T05watch:c000;");
+    assert_eq!(client.request("p5"), "6501");
+    assert_eq!(client.request("z2,c000,1"), "OK");
+
+    // With nothing left to stop it, only an interrupt does
+    write!(client.stream, "$c#63").unwrap();
+    assert_eq!(client.byte(), b'+');
+    client.stream.write_all(&[0x03]).unwrap();
+    assert_eq!(client.byte(), b'$');
+    assert_eq!(client.byte(), b'S');
+    assert_eq!([client.byte(), client.byte(), client.byte()], *b"02#");
+    client.byte();
+    client.byte();
+    client.stream.write_all(b"+").unwrap();
+    // Kill gets no reply, the stub just hangs up
+    write!(client.stream, "$k#6b").unwrap();
+    assert_eq!(client.byte(), b'+');
+    server.join().unwrap();
+}
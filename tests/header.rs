@@ -0,0 +1,133 @@
+//! Cartridge header parsing.
+
+extern crate gbm_rust;
+
+use gbm_rust::{Cartridge, Error};
+use gbm_rust::header::{CgbSupport, Destination, Header, MemoryBankController};
+use gbm_rust::rom::Rom;
+
+/// A 32 KiB ROM with `title` at 0x134 and the given header bytes, checksums
+/// filled in.
+fn rom(title: &[u8], fields: &[(usize, u8)]) -> Vec<u8> {
+    let mut rom = vec!(0; 0x8000);
+    rom[0x134..0x134 + title.len()].copy_from_slice(title);
+    for &(addr, value) in fields {
+        rom[addr] = value;
+    }
+    fix_checksums(&mut rom);
+    rom
+}
+
+fn fix_checksums(rom: &mut [u8]) {
+    rom[0x14D] = rom[0x134..0x14D].iter().fold(0u8, |x, &b| x.wrapping_sub(b).wrapping_sub(1));
+    let global = rom.iter().enumerate()
+        .filter(|&(i, _)| i != 0x14E && i != 0x14F)
+        .fold(0u16, |x, (_, &b)| x.wrapping_add(b as u16));
+    rom[0x14E] = (global >> 8) as u8;
+    rom[0x14F] = global as u8;
+}
+
+fn bad_header(rom: &[u8]) -> String {
+    match Header::parse(rom) {
+        Err(Error::BadRomHeader(reason)) => reason,
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn splits_the_title_area() {
+    // Old cartridges use all 16 bytes for the title
+    let header = Header::parse(&rom(b"SIXTEEN CHAR NAM", &[])).unwrap();
+    assert_eq!(header.title, "SIXTEEN CHAR NAM");
+    assert_eq!(header.manufacturer_code, None);
+    assert_eq!(header.cgb, CgbSupport::None);
+
+    // Newer ones end it with a manufacturer code and the CGB flag
+    let header = Header::parse(&rom(b"POKEMON YELAPSE", &[(0x143, 0x80)])).unwrap();
+    assert_eq!(header.title, "POKEMON YEL");
+    assert_eq!(header.manufacturer_code.as_deref(), Some("APSE"));
+    assert_eq!(header.cgb, CgbSupport::Enhanced);
+
+    // Without a plausible code the title runs up to the flag
+    let header = Header::parse(&rom(b"LONG CGB title", &[(0x143, 0xC0)])).unwrap();
+    assert_eq!(header.title, "LONG CGB title");
+    assert_eq!(header.manufacturer_code, None);
+    assert_eq!(header.cgb, CgbSupport::Only);
+
+    // Padding is cut off and anything unprintable replaced
+    let header = Header::parse(&rom(b"NUL\0JUNK", &[])).unwrap();
+    assert_eq!(header.title, "NUL");
+    let header = Header::parse(&rom(b"TAB\tEND", &[])).unwrap();
+    assert_eq!(header.title, "TAB?END");
+}
+
+#[test]
+fn reads_licensees_and_flags() {
+    let header = Header::parse(&rom(b"OLD", &[(0x14B, 0x01), (0x146, 0x03), (0x14A, 0x01), (0x14C, 0x02)])).unwrap();
+    assert_eq!(header.old_licensee_code, 0x01);
+    assert_eq!(header.new_licensee_code, None);
+    assert!(header.sgb);
+    assert_eq!(header.destination, Destination::Overseas);
+    assert_eq!(header.version, 2);
+
+    // 0x33 defers to the two ASCII characters of the new code
+    let header = Header::parse(&rom(b"NEW", &[(0x14B, 0x33), (0x144, b'0'), (0x145, b'1'), (0x14A, 0x07)])).unwrap();
+    assert_eq!(header.new_licensee_code.as_deref(), Some("01"));
+    assert!(!header.sgb);
+    assert_eq!(header.destination, Destination::Unknown(0x07));
+}
+
+#[test]
+fn decodes_sizes_and_features() {
+    let header = Header::parse(&rom(b"SIZES", &[(0x147, 0x13), (0x148, 0x05), (0x149, 0x03)])).unwrap();
+    assert_eq!(header.rom_size, 1024 * 1024);
+    assert_eq!(header.rom_banks(), 64);
+    assert_eq!(header.ram_size, 32 * 1024);
+    assert_eq!(header.ram_banks(), 4);
+    let cart = header.cartridge_type;
+    assert_eq!(cart.mbc, Some(MemoryBankController::Mbc3));
+    assert!(cart.ram && cart.battery && !cart.timer && !cart.rumble);
+    assert_eq!(cart.to_string(), "Mbc3+RAM+BATTERY");
+
+    let header = Header::parse(&rom(b"ODD", &[(0x148, 0x52), (0x149, 0x01)])).unwrap();
+    assert_eq!(header.rom_banks(), 72);
+    assert_eq!(header.ram_size, 0x800);
+    assert_eq!(header.ram_banks(), 1, "2 KiB still takes a bank");
+    assert_eq!(Header::parse(&rom(b"NONE", &[])).unwrap().ram_banks(), 0);
+}
+
+#[test]
+fn verifies_checksums() {
+    let mut data = rom(b"CHECKED", &[]);
+    let header = Header::parse(&data).unwrap();
+    assert!(header.header_checksum.is_valid());
+    assert!(header.global_checksum.is_valid());
+
+    data[0x4000] = 0x42;
+    let header = Header::parse(&data).unwrap();
+    assert!(header.header_checksum.is_valid(), "only covers 0x134-0x14C");
+    assert!(!header.global_checksum.is_valid());
+    assert_eq!(header.global_checksum.computed, header.global_checksum.expected.wrapping_add(0x42));
+
+    data[0x134] = b'X';
+    let header = Header::parse(&data).unwrap();
+    assert!(!header.header_checksum.is_valid());
+    assert_eq!(header.header_checksum.computed, header.header_checksum.expected.wrapping_sub(b'X' - b'C'));
+}
+
+#[test]
+fn refuses_truncated_and_unknown_headers() {
+    assert!(bad_header(&[0; 0x14F]).contains("only 335 bytes"));
+    assert!(bad_header(&rom(b"ROM SIZE", &[(0x148, 0x09)])).contains("ROM size 0x09"));
+    assert!(bad_header(&rom(b"RAM SIZE", &[(0x149, 0x06)])).contains("RAM size 0x06"));
+
+    // An unknown cartridge type parses, but can't be run
+    let data = rom(b"TYPE", &[(0x147, 0x42)]);
+    let header = Header::parse(&data).unwrap();
+    assert_eq!(header.cartridge_type.mbc, None);
+    assert_eq!(header.cartridge_type.to_string(), "Unknown(0x42)");
+    match Cartridge::from_rom(Rom::from_bytes(data)) {
+        Err(Error::UnsupportedCartridgeType(0x42)) => (),
+        other => panic!("{:?}", other.map(|cart| cart.title().to_string())),
+    }
+}
@@ -0,0 +1,82 @@
+//! Hooks on bus traffic.
+
+extern crate gbm_rust;
+
+mod common;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use gbm_rust::hooks::{Trigger, BusAccess};
+use gbm_rust::mmu::Bus;
+use common::gameboy;
+
+fn record(log: &Rc<RefCell<Vec<BusAccess>>>) -> impl FnMut(BusAccess) -> Option<u8> {
+    let log = log.clone();
+    move |access| {
+        log.borrow_mut().push(access);
+        None
+    }
+}
+
+#[test]
+fn sees_reads_writes_and_executes() {
+    let mut gameboy = gameboy(b"HOOKS");
+    let log = Rc::new(RefCell::new(vec!()));
+    gameboy.mmu_mut().add_hook(Trigger::all(), 0x0150 ..= 0x0151, record(&log));
+    gameboy.mmu_mut().add_hook(Trigger::WRITE, 0xFF07 ..= 0xFF07, record(&log));
+    for _ in 0..4 {
+        gameboy.step().unwrap();
+    }
+    // LD A,0x05 then LDH (TAC),A
+    assert_eq!(*log.borrow(), vec!(
+        BusAccess { trigger: Trigger::READ, addr: 0x0150, value: 0x3E },
+        BusAccess { trigger: Trigger::EXECUTE, addr: 0x0150, value: 0x3E },
+        BusAccess { trigger: Trigger::READ, addr: 0x0151, value: 0x05 },
+        BusAccess { trigger: Trigger::WRITE, addr: 0xFF07, value: 0x05 },
+    ));
+
+    // Looking doesn't count
+    log.borrow_mut().clear();
+    assert_eq!(gameboy.mmu().peek(0x0150), 0x3E);
+    assert!(log.borrow().is_empty());
+}
+
+#[test]
+fn overrides_values() {
+    let mut gameboy = gameboy(b"HOOKS");
+    // LD A,0x05 loads 0x42 instead
+    gameboy.mmu_mut().add_hook(Trigger::READ, 0x0151 ..= 0x0151, |_| Some(0x42));
+    // Hooks apply in order: 0x42 + 1 = 0x43, then 0x43 ^ 1 = 0x42 rather
+    // than 0x42 ^ 1 + 1 = 0x44
+    gameboy.mmu_mut().add_hook(Trigger::WRITE, 0xFF07 ..= 0xFF07, |access| Some(access.value + 1));
+    gameboy.mmu_mut().add_hook(Trigger::WRITE, 0xFF07 ..= 0xFF07, |access| Some(access.value ^ 0x01));
+    for _ in 0..4 {
+        gameboy.step().unwrap();
+    }
+    assert_eq!(gameboy.cpu().registers().a, 0x42);
+    assert_eq!(gameboy.mmu().peek(0xFF07) & 0x07, 0x42 & 0x07);
+}
+
+#[test]
+fn replaces_opcodes() {
+    let mut gameboy = gameboy(b"HOOKS");
+    // Turn LD A,0x05 into LD B,0x05
+    gameboy.mmu_mut().add_hook(Trigger::EXECUTE, 0x0150 ..= 0x0150, |_| Some(0x06));
+    for _ in 0..3 {
+        gameboy.step().unwrap();
+    }
+    assert_eq!(gameboy.cpu().registers().b, 0x05);
+    assert_eq!(gameboy.cpu().registers().a, 0x01);
+}
+
+#[test]
+fn removes_hooks() {
+    let mut gameboy = gameboy(b"HOOKS");
+    let log = Rc::new(RefCell::new(vec!()));
+    let id = gameboy.mmu_mut().add_hook(Trigger::READ, 0x0000 ..= 0xFFFF, record(&log));
+    assert!(gameboy.mmu_mut().remove_hook(id));
+    assert!(!gameboy.mmu_mut().remove_hook(id));
+    gameboy.run_frame().unwrap();
+    assert!(log.borrow().is_empty());
+}
@@ -0,0 +1,81 @@
+//! JSON output of the info command.
+
+extern crate serde_json;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+use serde_json::Value;
+
+/// A fresh directory for this test's ROMs.
+fn scratch(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("gbm-rust-info-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn write_rom(path: &PathBuf, title: &[u8]) {
+    let mut rom = vec!(0; 0x8000);
+    rom[0x134..0x134 + title.len()].copy_from_slice(title);
+    fs::write(path, rom).unwrap();
+}
+
+/// Runs `info --json` and returns its exit code and parsed output.
+fn info(files: &[PathBuf]) -> (i32, Vec<Value>) {
+    let output = Command::new(env!("CARGO_BIN_EXE_gbm-rust"))
+        .arg("info")
+        .arg("--json")
+        .args(files)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let entries = match serde_json::from_str(&stdout) {
+        Ok(Value::Array(entries)) => entries,
+        other => panic!("{:?} from\n{}", other, stdout),
+    };
+    (output.status.code().unwrap(), entries)
+}
+
+#[test]
+fn escapes_titles_and_file_names() {
+    let dir = scratch("escapes");
+    let path = dir.join("quote\"back\\slash\ttab\nline\u{1}.gb");
+    write_rom(&path, b"SAY \"HI\" C:\\");
+    let (status, entries) = info(std::slice::from_ref(&path));
+
+    assert_eq!(status, 0);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["file"], path.to_str().unwrap());
+    assert_eq!(entries[0]["title"], "SAY \"HI\" C:\\");
+    assert_eq!(entries[0]["cartridge_type"]["mbc"], "RomOnly");
+    assert_eq!(entries[0]["header_checksum"]["valid"], false);
+    assert_eq!(entries[0]["manufacturer_code"], Value::Null);
+    let _ = fs::remove_dir_all(dir);
+}
+
+#[test]
+fn reports_errors_as_objects() {
+    let dir = scratch("errors");
+    let good = dir.join("good.gb");
+    write_rom(&good, b"GOOD");
+    let short = dir.join("short.gb");
+    fs::write(&short, [0u8; 0x100]).unwrap();
+    let missing = dir.join("missing.gb");
+    let (status, entries) = info(&[good.clone(), short.clone(), missing.clone()]);
+
+    assert_eq!(status, 1, "any error fails the run");
+    assert_eq!(entries.len(), 3, "but every file gets an entry");
+    assert_eq!(entries[0]["title"], "GOOD");
+    for (entry, path) in entries[1..].iter().zip(&[short, missing]) {
+        let object = entry.as_object().unwrap();
+        let keys: Vec<&str> = object.keys().map(|key| key.as_str()).collect();
+        assert_eq!(keys, ["error", "file"]);
+        assert_eq!(entry["file"], path.to_str().unwrap());
+        assert!(entry["error"].is_string());
+    }
+    assert!(entries[1]["error"].as_str().unwrap().starts_with("Bad ROM header"), "{}", entries[1]);
+    let _ = fs::remove_dir_all(dir);
+}
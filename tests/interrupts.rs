@@ -0,0 +1,65 @@
+//! Interrupt dispatch through the whole machine.
+
+extern crate gbm_rust;
+
+mod common;
+
+use gbm_rust::GameBoy;
+use gbm_rust::mmu::Bus;
+
+/// Requests a timer interrupt with SP at `sp` and enables it. The
+/// interrupt is dispatched after the NOP at 0x015A.
+fn program(sp: u16) -> Vec<u8> {
+    vec!(
+        0x31, sp as u8, (sp >> 8) as u8, // 0150 LD SP,sp
+        0x3E, 0x04,                      // 0153 LD A,0x04
+        0xE0, 0xFF,                      // 0155 LDH (IE),A
+        0xE0, 0x0F,                      // 0157 LDH (IF),A
+        0xFB,                            // 0159 EI
+        0x00,                            // 015A NOP
+        0x00,                            // 015B NOP
+    )
+}
+
+/// Runs up to the dispatch, then the dispatch itself, returning how many
+/// M-cycles the latter took.
+fn dispatch(gameboy: &mut GameBoy) -> u64 {
+    while gameboy.cpu().registers().pc != 0x015B {
+        gameboy.step().unwrap();
+    }
+    let start = gameboy.mmu().cycles();
+    gameboy.step().unwrap();
+    gameboy.mmu().cycles() - start
+}
+
+#[test]
+fn pushes_pc_and_jumps_to_the_vector() {
+    let mut gameboy = common::with_program(b"INTERRUPTS", &program(0xD000));
+    assert_eq!(dispatch(&mut gameboy), 5);
+    assert_eq!(gameboy.cpu().registers().pc, 0x0050);
+    assert_eq!(gameboy.cpu().registers().sp, 0xCFFE);
+    assert_eq!(gameboy.mmu().peek(0xCFFF), 0x01);
+    assert_eq!(gameboy.mmu().peek(0xCFFE), 0x5B);
+    assert_eq!(gameboy.mmu().peek(0xFF0F) & 0x1F, 0x00);
+}
+
+#[test]
+fn pushing_onto_ie_cancels_the_interrupt() {
+    // The high byte of PC lands on IE, which then only enables VBlank
+    let mut gameboy = common::with_program(b"INTERRUPTS", &program(0x0000));
+    assert_eq!(dispatch(&mut gameboy), 5);
+    assert_eq!(gameboy.cpu().registers().pc, 0x0000);
+    assert_eq!(gameboy.mmu().peek(0xFFFF), 0x01);
+    assert_eq!(gameboy.mmu().peek(0xFFFE), 0x5B);
+    assert_eq!(gameboy.mmu().peek(0xFF0F) & 0x1F, 0x04, "the timer interrupt is still pending");
+}
+
+#[test]
+fn interrupt_is_picked_before_the_low_byte_lands() {
+    // 0x5B disables the timer interrupt, but too late to cancel it
+    let mut gameboy = common::with_program(b"INTERRUPTS", &program(0x0001));
+    assert_eq!(dispatch(&mut gameboy), 5);
+    assert_eq!(gameboy.cpu().registers().pc, 0x0050);
+    assert_eq!(gameboy.mmu().peek(0xFFFF) & 0x1F, 0x1B);
+    assert_eq!(gameboy.mmu().peek(0xFF0F) & 0x1F, 0x00);
+}
@@ -0,0 +1,116 @@
+//! Movie recording and replay.
+
+extern crate gbm_rust;
+
+mod common;
+
+use gbm_rust::{Buttons, Error};
+use gbm_rust::movie::{Movie, Recorder, Desync, Script};
+use common::{gameboy, run};
+
+fn input(frame: usize) -> Buttons {
+    Buttons::from_bits_truncate((frame * 37) as u8)
+}
+
+#[test]
+fn replays_from_power_on() {
+    let mut recording = gameboy(b"MOVIE");
+    // Whatever ran before recording started doesn't matter
+    run(&mut recording, 7);
+    let mut recorder = Recorder::power_on(&mut recording);
+    for frame in 0..60 {
+        recorder.frame(&mut recording, input(frame)).unwrap();
+    }
+    let movie = Movie::from_bytes(&recorder.finish().to_bytes()).unwrap();
+    assert!(!movie.has_start_state());
+
+    let mut replay = gameboy(b"MOVIE");
+    assert_eq!(movie.play(&mut replay).unwrap(), None);
+    assert_eq!(replay.save_state(), recording.save_state());
+}
+
+#[test]
+fn replays_from_save_state() {
+    let mut recording = gameboy(b"MOVIE");
+    run(&mut recording, 13);
+    let mut recorder = Recorder::from_here(&recording);
+    for frame in 0..30 {
+        recorder.frame(&mut recording, input(frame)).unwrap();
+    }
+    let movie = recorder.finish();
+    assert!(movie.has_start_state());
+
+    let mut replay = gameboy(b"MOVIE");
+    assert_eq!(movie.play(&mut replay).unwrap(), None);
+    assert_eq!(replay.save_state(), recording.save_state());
+}
+
+#[test]
+fn reports_first_desync() {
+    let mut gameboy = gameboy(b"MOVIE");
+    let mut recorder = Recorder::power_on(&mut gameboy);
+    for frame in 0..20 {
+        recorder.frame(&mut gameboy, input(frame)).unwrap();
+    }
+    let mut data = recorder.finish().to_bytes();
+    // Flip a bit in the hash of frame 12: header, no state, count, then 9 bytes a frame
+    let offset = 4 + 2 + 4 + 4 + 4 + 12 * 9 + 1;
+    data[offset] ^= 0x01;
+    let movie = Movie::from_bytes(&data).unwrap();
+    match movie.play(&mut gameboy).unwrap() {
+        Some(Desync { frame: 12, .. }) => (),
+        other => panic!("expected a desync at frame 12, got {:?}", other),
+    }
+}
+
+#[test]
+fn refuses_other_rom() {
+    let mut gameboy = gameboy(b"MOVIE");
+    let movie = Recorder::power_on(&mut gameboy).finish();
+    let mut other = common::gameboy(b"OTHER");
+    match movie.play(&mut other) {
+        Err(Error::RomMismatch { .. }) => (),
+        other => panic!("expected a ROM mismatch, got {:?}", other.err()),
+    }
+}
+
+#[test]
+fn records_input_scripts() {
+    let script = Script::parse("\
+# Title screen
+10 start
+12       # let go
+20 A right
+21 b Select up down left
+").unwrap();
+    assert_eq!(script.input(0), Buttons::empty());
+    assert_eq!(script.input(10), Buttons::START);
+    assert_eq!(script.input(11), Buttons::START);
+    assert_eq!(script.input(12), Buttons::empty());
+    assert_eq!(script.input(20), Buttons::A | Buttons::RIGHT);
+    assert_eq!(script.input(1000), Buttons::all() - Buttons::A - Buttons::RIGHT - Buttons::START);
+
+    let mut gameboy = gameboy(b"MOVIE");
+    let mut recorder = Recorder::power_on(&mut gameboy);
+    for frame in 0..25 {
+        recorder.frame(&mut gameboy, script.input(frame)).unwrap();
+    }
+    let movie = recorder.finish();
+    let inputs: Vec<Buttons> = movie.frames().iter().map(|frame| frame.input).collect();
+    let expected: Vec<Buttons> = (0..25).map(|frame| script.input(frame)).collect();
+    assert_eq!(inputs, expected);
+}
+
+#[test]
+fn refuses_bad_input_scripts() {
+    for &(text, reason) in &[
+        ("10 start\nten a", "line 2: ten isn't a frame number"),
+        ("10 jump", "line 1: unknown button jump"),
+        ("10 a\n\n10 b", "line 3: frame 10 doesn't come after the line before"),
+    ] {
+        match Script::parse(text) {
+            Err(Error::BadMovie(message)) => assert_eq!(message, format!("input script {}", reason)),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! Frame pacing, speed and frame skipping.
+
+extern crate gbm_rust;
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use gbm_rust::pacing::{Pacer, Speed, FRAME_RATE};
+use common::{gameboy, run};
+
+/// Host time `frames` real-time frames after `start`.
+fn after(start: Instant, frames: f64) -> Instant {
+    start + Duration::from_secs_f64(frames / FRAME_RATE)
+}
+
+fn close(wait: Duration, frames: f64) -> bool {
+    (wait.as_secs_f64() * FRAME_RATE - frames).abs() < 1e-3
+}
+
+#[test]
+fn waits_for_real_time() {
+    let start = Instant::now();
+    let mut pacer = Pacer::new(Speed::Times(1.0), 8, start);
+    // Emulating a frame took a quarter of its time
+    let wait = pacer.frame_done(after(start, 0.25));
+    assert!(close(wait, 0.75), "{:?}", wait);
+    assert!(pacer.render());
+    let wait = pacer.frame_done(after(start, 1.5));
+    assert!(close(wait, 0.5), "{:?}", wait);
+    assert!(pacer.render());
+
+    let mut slow = Pacer::new(Speed::Times(0.5), 8, start);
+    assert!(close(slow.frame_done(after(start, 0.25)), 1.75));
+}
+
+#[test]
+fn fast_forward_skips_frames_the_display_cant_show() {
+    let start = Instant::now();
+    let mut pacer = Pacer::new(Speed::Times(4.0), 8, start);
+    let mut rendered = vec!();
+    for frame in 1..=16 {
+        rendered.push(pacer.render());
+        // Each takes a fifth of its real-time frame to emulate
+        let wait = pacer.frame_done(after(start, frame as f64 * 0.25 - 0.05));
+        assert!(close(wait, 0.05), "{:?}", wait);
+    }
+    // One frame for each display refresh they go out on
+    let drawn: Vec<usize> = (1..=16).filter(|&frame| rendered[frame - 1]).collect();
+    assert_eq!(drawn, [1, 4, 8, 12, 16]);
+
+    // No more in a row than allowed
+    let mut pacer = Pacer::new(Speed::Times(4.0), 1, start);
+    for frame in 1..=16 {
+        let render = pacer.render();
+        pacer.frame_done(after(start, frame as f64 * 0.25 - 0.05));
+        assert!(render || pacer.render());
+    }
+}
+
+#[test]
+fn falling_behind_skips_then_gives_up() {
+    let start = Instant::now();
+    let mut pacer = Pacer::new(Speed::Times(1.0), 8, start);
+    assert_eq!(pacer.frame_done(after(start, 2.5)), Duration::from_secs(0));
+    assert!(!pacer.render(), "behind, so the next frame isn't drawn");
+    pacer.frame_done(after(start, 2.8));
+    assert!(pacer.render(), "on to a new refresh");
+
+    // Way too far behind to catch up: starts over from the last frame
+    pacer.frame_done(after(start, 40.0));
+    assert!(pacer.render());
+    assert!(close(pacer.frame_done(after(start, 40.5)), 0.5));
+}
+
+#[test]
+fn uncapped_never_waits_and_reports_speed() {
+    let start = Instant::now();
+    let mut pacer = Pacer::new(Speed::Uncapped, 0, start);
+    for frame in 1..=120 {
+        assert!(pacer.render());
+        assert_eq!(pacer.frame_done(after(start, frame as f64 * 0.5)), Duration::from_secs(0));
+    }
+    assert!(pacer.take_report(after(start, 30.0)).is_none(), "less than a second in");
+    let report = pacer.take_report(after(start, 60.0)).unwrap();
+    assert_eq!((report.frames, report.skipped), (120, 0));
+    assert!((report.speed() - 2.0).abs() < 1e-3, "{}", report);
+    assert_eq!(pacer.take_report(after(start, 120.0)).unwrap().frames, 0);
+    assert_eq!(pacer.total(after(start, 120.0)).frames, 120);
+
+    pacer.set_speed(Speed::Times(1.0), after(start, 120.0));
+    assert!(close(pacer.frame_done(after(start, 120.0)), 1.0));
+}
+
+#[test]
+fn skipped_frames_change_nothing_but_the_picture() {
+    let mut drawn = gameboy(b"PACING");
+    let mut skipped = gameboy(b"PACING");
+    run(&mut drawn, 3);
+    run(&mut skipped, 3);
+    let before = skipped.framebuffer().to_vec();
+
+    skipped.set_rendering(false);
+    run(&mut drawn, 5);
+    run(&mut skipped, 5);
+    assert_eq!(skipped.framebuffer(), &before[..]);
+    assert_eq!(skipped.cpu().registers().pc, drawn.cpu().registers().pc);
+    assert_eq!(skipped.mmu().cycles(), drawn.mmu().cycles());
+
+    skipped.set_rendering(true);
+    run(&mut drawn, 1);
+    run(&mut skipped, 1);
+    assert_eq!(skipped.save_state(), drawn.save_state());
+}
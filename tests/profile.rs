@@ -0,0 +1,88 @@
+//! Execution profiles and coverage maps.
+
+extern crate gbm_rust;
+
+mod common;
+
+use gbm_rust::disasm::Symbols;
+use gbm_rust::profile::{Profile, EXECUTED, COVERED};
+use common::{gameboy, run};
+
+#[test]
+fn counts_executions_and_cycles() {
+    let mut gameboy = gameboy(b"PROFILE");
+    gameboy.set_profile(Profile::new());
+    run(&mut gameboy, 5);
+    let profile = gameboy.take_profile().unwrap();
+    let entries = profile.entries();
+
+    assert_eq!(entries[&(0, 0x0150)].executions, 1);
+    let loops = entries[&(0, 0x015F)].executions;
+    assert!(loops > 100);
+    for (&addr, &cycles) in [0x15F, 0x161, 0x162, 0x165, 0x166, 0x168, 0x16A, 0x16B].iter()
+        .zip([3, 2, 4, 1, 2, 2, 1, 3].iter()) {
+        let entry = entries[&(0, addr)];
+        // The run may stop anywhere in the loop
+        assert!(entry.executions == loops || entry.executions == loops - 1, "{:04x}", addr);
+        assert_eq!(entry.cycles, entry.executions * cycles, "{:04x}", addr);
+    }
+    assert_eq!(profile.halted_cycles(), 0);
+    assert_eq!(profile.total_cycles(), entries.values().map(|entry| entry.cycles).sum::<u64>());
+}
+
+#[test]
+fn coverage_marks_instruction_bytes() {
+    let mut gameboy = gameboy(b"PROFILE");
+    gameboy.set_profile(Profile::new());
+    run(&mut gameboy, 1);
+    let profile = gameboy.take_profile().unwrap();
+    let map = profile.coverage(gameboy.mmu().cartridge().rom());
+
+    assert_eq!(map.len(), 0x8000);
+    assert_eq!(map[0x0150], EXECUTED | COVERED);
+    assert_eq!(map[0x0151], COVERED);
+    assert_eq!(map[0x0162], EXECUTED | COVERED);
+    assert_eq!(map[0x0164], COVERED);
+    assert_eq!(map[0x016D], 0);
+    assert_eq!(map[0x0200], 0);
+}
+
+#[test]
+fn flat_profile_adds_up_by_symbol() {
+    let mut gameboy = gameboy(b"PROFILE");
+    gameboy.set_profile(Profile::new());
+    run(&mut gameboy, 2);
+    let profile = gameboy.take_profile().unwrap();
+    let symbols = Symbols::parse("00:0150 Main\n00:015f Loop\n").unwrap();
+
+    let flat = profile.flat(&symbols);
+    let lines: Vec<&str> = flat.lines().collect();
+    assert!(lines[1].ends_with("  Loop"), "{}", flat);
+    assert!(lines[2].ends_with("  Main") || lines[2].ends_with("  bank 00"), "{}", flat);
+    let loop_cycles: u64 = profile.entries().iter()
+        .filter(|&(&(_, addr), _)| addr >= 0x015F)
+        .map(|(_, entry)| entry.cycles)
+        .sum();
+    assert!(lines[1].contains(&format!(" {} ", loop_cycles)), "{}", flat);
+
+    let report = profile.report(&symbols);
+    assert!(report.contains("00:0150 "));
+    assert!(report.lines().any(|line| line.starts_with("00:0161") && line.ends_with("  Loop+2")), "{}", report);
+}
+
+#[test]
+fn taking_the_profile_stops_it() {
+    let mut gameboy = gameboy(b"PROFILE");
+    gameboy.set_profile(Profile::new());
+    run(&mut gameboy, 1);
+    let first = gameboy.take_profile().unwrap();
+    assert!(gameboy.take_profile().is_none());
+    run(&mut gameboy, 1);
+
+    gameboy.set_profile(Profile::new());
+    run(&mut gameboy, 1);
+    let second = gameboy.take_profile().unwrap();
+    assert!(!second.entries().contains_key(&(0, 0x0150)));
+    assert!(second.total_cycles() > 0);
+    assert!(first.entries().contains_key(&(0, 0x0150)));
+}
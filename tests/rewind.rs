@@ -0,0 +1,50 @@
+//! Rewind buffer behaviour.
+
+extern crate gbm_rust;
+
+mod common;
+
+use gbm_rust::rewind::Rewind;
+use common::gameboy;
+
+#[test]
+fn restores_earlier_snapshots() {
+    let mut gameboy = gameboy(b"REWIND");
+    let mut rewind = Rewind::new(5, 100);
+    let mut states = vec!();
+    for _ in 0..100 {
+        gameboy.run_frame().unwrap();
+        rewind.record(&gameboy);
+        states.push((gameboy.frames(), gameboy.save_state()));
+    }
+    let full: usize = states.iter().step_by(5).map(|(_, state)| state.len()).sum();
+    assert!(rewind.size() < full / 2, "{} bytes for {} bytes of snapshots", rewind.size(), full);
+
+    let now = gameboy.frames();
+    let frame = rewind.rewind(&mut gameboy, 23).unwrap();
+    assert!(frame <= now - 23 && frame > now - 23 - 5);
+    assert_eq!(gameboy.frames(), frame);
+    let expected = &states.iter().find(|&&(f, _)| f == frame).unwrap().1;
+    assert_eq!(&gameboy.save_state(), expected);
+
+    // Going further back picks up from the restored snapshot
+    let frame = rewind.rewind(&mut gameboy, 40).unwrap();
+    let expected = &states.iter().find(|&&(f, _)| f == frame).unwrap().1;
+    assert_eq!(&gameboy.save_state(), expected);
+}
+
+#[test]
+fn stays_within_capacity() {
+    let mut gameboy = gameboy(b"REWIND");
+    let mut rewind = Rewind::new(1, 10);
+    for _ in 0..50 {
+        gameboy.run_frame().unwrap();
+        rewind.record(&gameboy);
+    }
+    assert_eq!(rewind.len(), 10);
+
+    // Asking for more than is kept stops at the oldest snapshot
+    let now = gameboy.frames();
+    assert_eq!(rewind.rewind(&mut gameboy, 1000).unwrap(), now - 9);
+    assert_eq!(rewind.len(), 1);
+}
@@ -0,0 +1,86 @@
+//! Save state round trips.
+
+extern crate gbm_rust;
+
+mod common;
+
+use gbm_rust::Error;
+use common::{gameboy, run, with_program};
+
+#[test]
+fn resumes_identically() {
+    let mut gameboy = gameboy(b"STATE");
+    run(&mut gameboy, 10);
+    let state = gameboy.save_state();
+
+    let audio = run(&mut gameboy, 20);
+    let framebuffer = gameboy.framebuffer().to_vec();
+    let after = gameboy.save_state();
+
+    gameboy.load_state(&state).unwrap();
+    assert_eq!(gameboy.save_state(), state);
+    assert_eq!(run(&mut gameboy, 20), audio);
+    assert_eq!(gameboy.framebuffer(), &framebuffer[..]);
+    assert_eq!(gameboy.save_state(), after);
+}
+
+#[test]
+fn refuses_other_rom() {
+    let state = gameboy(b"STATE").save_state();
+    match gameboy(b"OTHER").load_state(&state) {
+        Err(Error::RomMismatch { .. }) => (),
+        other => panic!("expected a ROM mismatch, got {:?}", other),
+    }
+}
+
+#[test]
+fn bad_state_leaves_machine_alone() {
+    let mut gameboy = gameboy(b"STATE");
+    run(&mut gameboy, 5);
+    let mut state = gameboy.save_state();
+    run(&mut gameboy, 5);
+    let before = gameboy.save_state();
+
+    state.truncate(state.len() - 1);
+    match gameboy.load_state(&state) {
+        Err(Error::BadSaveState(_)) => (),
+        other => panic!("expected a bad state, got {:?}", other),
+    }
+    assert_eq!(gameboy.save_state(), before);
+}
+
+/// Sets SCY, SCX and LYC to values that mark where the LCD state starts.
+const MARKED: &[u8] = &[
+    0x3E, 0xA5,       // LD A,0xA5
+    0xE0, 0x42,       // LDH (SCY),A
+    0x3E, 0x5A,       // LD A,0x5A
+    0xE0, 0x43,       // LDH (SCX),A
+    0x3E, 0xC3,       // LD A,0xC3
+    0xE0, 0x45,       // LDH (LYC),A
+    0x18, 0xFE,       // JR -2
+];
+
+#[test]
+fn refuses_lcd_state_out_of_range() {
+    let mut gameboy = with_program(b"STATE", MARKED);
+    run(&mut gameboy, 1);
+    let state = gameboy.save_state();
+    // SCY, SCX, LY, LYC, LCDC, STAT, the STAT line, the mode and the cycles
+    let lcd = state.windows(4).position(|w| w[0] == 0xA5 && w[1] == 0x5A && w[3] == 0xC3).unwrap();
+
+    let mut past_last_line = state.clone();
+    past_last_line[lcd + 2] = 0xFF;
+    let mut off_screen = state.clone();
+    off_screen[lcd + 2] = 150;
+    off_screen[lcd + 7] = 3;
+    let mut overlong_mode = state.clone();
+    overlong_mode[lcd + 8..lcd + 12].copy_from_slice(&[0xFF; 4]);
+
+    for bad in &[past_last_line, off_screen, overlong_mode] {
+        match gameboy.load_state(bad) {
+            Err(Error::BadSaveState(_)) => (),
+            other => panic!("expected a bad state, got {:?}", other),
+        }
+    }
+    gameboy.load_state(&state).unwrap();
+}
@@ -0,0 +1,121 @@
+//! Peripherals run by events must look like they tick on every cycle.
+
+extern crate gbm_rust;
+
+mod common;
+
+use gbm_rust::gpu::FRAME_CYCLES;
+use gbm_rust::mmu::{Bus, Master};
+use common::{gameboy, run};
+
+#[test]
+fn timer_reads_follow_every_cycle() {
+    let mut gameboy = gameboy(b"SCHEDULER");
+    let mmu = gameboy.mmu_mut();
+    mmu.write(0xFF04, 0x00);
+    mmu.write(0xFF06, 0xF0);
+    mmu.write(0xFF05, 0xFC);
+    mmu.write(0xFF07, 0x05);
+    mmu.write(0xFF0F, 0x00);
+
+    // TIMA ticks on falling edges of bit 3 of the counter
+    let (mut counter, mut tima) = (0u16, 0xFCu8);
+    for cycle in 0..3000 {
+        mmu.cycle();
+        let next = counter.wrapping_add(4);
+        let overflow = counter & 0x08 != 0 && next & 0x08 == 0 && tima == 0xFF;
+        if counter & 0x08 != 0 && next & 0x08 == 0 {
+            tima = if overflow { 0xF0 } else { tima + 1 };
+        }
+        counter = next;
+
+        assert_eq!(mmu.peek(0xFF04), (counter >> 8) as u8, "DIV at {}", cycle);
+        assert_eq!(mmu.peek(0xFF05), tima, "TIMA at {}", cycle);
+        assert_eq!(mmu.peek(0xFF0F) & 0x04 != 0, overflow, "interrupt at {}", cycle);
+        mmu.write(0xFF0F, 0x00);
+        if cycle == 1000 {
+            // Slower, from the next falling edge of bit 9
+            mmu.write(0xFF07, 0x04);
+            let edges = (counter as u32 + 4 * 2000) / 0x400 - counter as u32 / 0x400;
+            for _ in 0..2000 {
+                mmu.cycle();
+            }
+            counter = counter.wrapping_add(4 * 2000);
+            tima = tima.wrapping_add(edges as u8);
+            assert_eq!(mmu.peek(0xFF05), tima);
+            mmu.write(0xFF07, 0x05);
+        }
+    }
+}
+
+#[test]
+fn serial_transfer_takes_1024_cycles() {
+    let mut gameboy = gameboy(b"SCHEDULER");
+    let mmu = gameboy.mmu_mut();
+    mmu.write(0xFF01, 0x5A);
+    mmu.write(0xFF02, 0x81);
+    mmu.write(0xFF0F, 0x00);
+    for _ in 0..1023 {
+        mmu.cycle();
+    }
+    assert_eq!(mmu.peek(0xFF0F) & 0x08, 0x00);
+    assert_eq!(mmu.peek(0xFF02), 0xFF);
+
+    mmu.cycle();
+    assert_eq!(mmu.peek(0xFF0F) & 0x08, 0x08);
+    assert_eq!(mmu.peek(0xFF01), 0xFF);
+    assert_eq!(mmu.peek(0xFF02), 0x7F);
+    assert_eq!(mmu.serial().output(), &[0x5A]);
+}
+
+#[test]
+fn lcd_modes_follow_every_cycle() {
+    let mut gameboy = gameboy(b"SCHEDULER");
+    let mmu = gameboy.mmu_mut();
+    // Starts over from line 0 when turned back on
+    mmu.write(0xFF40, 0x11);
+    mmu.write(0xFF40, 0x91);
+    mmu.write(0xFF45, 5);
+    mmu.write(0xFF41, 0x40);
+    mmu.write(0xFF0F, 0x00);
+
+    let mut lyc_interrupts = vec!();
+    for cycle in 1..2 * FRAME_CYCLES {
+        mmu.cycle();
+        let (line, dot) = ((cycle % FRAME_CYCLES) / 114, cycle % 114);
+        let mode = match dot {
+            _ if line >= 144 => 1,
+            0 ..= 19 => 2,
+            20 ..= 62 => 3,
+            _ => 0,
+        };
+        assert_eq!(mmu.peek(0xFF44) as usize, line, "LY at {}", cycle);
+        assert_eq!(mmu.peek(0xFF41) & 0x03, mode, "mode at {}", cycle);
+        if mmu.peek(0xFF0F) & 0x02 != 0 {
+            lyc_interrupts.push(cycle);
+            mmu.write(0xFF0F, 0x00);
+        }
+    }
+    assert_eq!(lyc_interrupts, [5 * 114, FRAME_CYCLES + 5 * 114]);
+}
+
+#[test]
+fn states_saved_between_events_resume_identically() {
+    let mut gameboy = gameboy(b"SCHEDULER");
+    run(&mut gameboy, 3);
+    gameboy.mmu_mut().write(0xFF02, 0x81);
+    for _ in 0..77 {
+        gameboy.step().unwrap();
+    }
+    gameboy.drain_audio();
+    let state = gameboy.save_state();
+
+    let audio = run(&mut gameboy, 2);
+    let after = gameboy.save_state();
+    assert_eq!(gameboy.mmu().serial().output().len(), 1);
+
+    gameboy.load_state(&state).unwrap();
+    assert_eq!(gameboy.save_state(), state);
+    assert_eq!(run(&mut gameboy, 2), audio);
+    assert_eq!(gameboy.save_state(), after);
+}
@@ -0,0 +1,186 @@
+//! Screenshot regression tests.
+//!
+//! Every ROM in `$GBM_TEST_ROMS/screenshots` (defaults to
+//! `tests/roms/screenshots`) with a reference image next to it, e.g.
+//! `dmg-acid2.gb` and `dmg-acid2.png`, is run headlessly for a fixed
+//! number of frames. The final frame is compared pixel by pixel with the
+//! reference. On a mismatch the actual frame and a diff image, with
+//! differing pixels in red, are written to the test output directory.
+//!
+//! The ROMs and references aren't part of the repository, so the test is
+//! ignored by default. Run it with
+//!
+//! ```text
+//! GBM_TEST_ROMS=~/gb-test-roms cargo test --test screenshots -- --ignored --nocapture
+//! ```
+//!
+//! It fails when the directory is missing or has no ROM with a reference.
+//! A small ROM built here, with its reference in `tests/references`, runs
+//! every time so the pipeline itself stays covered.
+
+extern crate png;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const FRAMES: u64 = 120;
+
+struct Image {
+    width: usize,
+    height: usize,
+    gray: Vec<u8>,
+}
+
+fn load(path: &Path) -> Image {
+    let mut decoder = png::Decoder::new(File::open(path).unwrap());
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().unwrap();
+    let mut buffer = vec!(0; reader.output_buffer_size());
+    let info = reader.next_frame(&mut buffer).unwrap();
+    let channels = info.color_type.samples();
+    let gray = buffer[..info.buffer_size()]
+        .chunks(channels)
+        .map(|pixel| pixel[0])
+        .collect();
+    Image {
+        width: info.width as usize,
+        height: info.height as usize,
+        gray,
+    }
+}
+
+fn write_diff(path: &Path, actual: &Image, expected: &Image) {
+    let mut rgb = vec!();
+    for (&a, &e) in actual.gray.iter().zip(expected.gray.iter()) {
+        if a == e {
+            // Faded so the differences stand out
+            let faded = 0x80 + e / 2;
+            rgb.extend_from_slice(&[faded, faded, faded]);
+        } else {
+            rgb.extend_from_slice(&[0xFF, 0x00, 0x00]);
+        }
+    }
+    let file = BufWriter::new(File::create(path).unwrap());
+    let mut encoder = png::Encoder::new(file, actual.width as u32, actual.height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header().unwrap().write_image_data(&rgb).unwrap();
+}
+
+fn rom_dir() -> PathBuf {
+    let root = match env::var_os("GBM_TEST_ROMS") {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms"),
+    };
+    root.join("screenshots")
+}
+
+/// Runs `rom` and compares its last frame with `reference`, reporting the
+/// result. Output images go to `out`.
+fn matches(rom: &Path, reference: &Path, out: &Path) -> bool {
+    let name = rom.file_stem().unwrap().to_string_lossy().into_owned();
+    let actual_path = out.join(format!("{}.png", name));
+    let status = Command::new(env!("CARGO_BIN_EXE_gbm-rust"))
+        .arg("headless")
+        .arg("--frames").arg(FRAMES.to_string())
+        .arg("--dump").arg(&actual_path)
+        .arg(rom)
+        .output()
+        .unwrap()
+        .status;
+    if !status.success() {
+        println!("FAIL {} (exit status {})", name, status);
+        return false;
+    }
+
+    let actual = load(&actual_path);
+    let expected = load(reference);
+    assert_eq!((actual.width, actual.height), (expected.width, expected.height),
+               "{}: reference image has the wrong size", name);
+    let differences = actual.gray.iter().zip(expected.gray.iter()).filter(|&(a, e)| a != e).count();
+    if differences == 0 {
+        println!("PASS {}", name);
+        true
+    } else {
+        let diff_path = out.join(format!("{}-diff.png", name));
+        write_diff(&diff_path, &actual, &expected);
+        println!("FAIL {} ({} pixels differ, see {})", name, differences, diff_path.display());
+        false
+    }
+}
+
+fn out_dir() -> PathBuf {
+    let out = Path::new(env!("CARGO_TARGET_TMPDIR")).join("screenshots");
+    fs::create_dir_all(&out).unwrap();
+    out
+}
+
+#[test]
+#[ignore = "needs the test ROMs, see the module docs"]
+fn screenshots() {
+    let dir = rom_dir();
+    assert!(dir.is_dir(), "{} not found, set GBM_TEST_ROMS to where the test ROMs are", dir.display());
+    let out = out_dir();
+
+    let mut roms: Vec<PathBuf> = fs::read_dir(&dir).unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "gb"))
+        .filter(|path| path.with_extension("png").is_file())
+        .collect();
+    roms.sort();
+    assert!(!roms.is_empty(), "No ROMs with a reference image in {}", dir.display());
+
+    let failures: Vec<String> = roms.iter()
+        .filter(|rom| !matches(rom, &rom.with_extension("png"), &out))
+        .map(|rom| rom.file_stem().unwrap().to_string_lossy().into_owned())
+        .collect();
+    assert!(failures.is_empty(), "Screenshot mismatches: {}", failures.join(", "));
+}
+
+/// Turns the LCD off in VBlank, draws a checkerboard of a patterned tile
+/// and turns it back on.
+const CHECKERBOARD: &[u8] = &[
+    0xF0, 0x44,       // 0150 LDH A,(LY)
+    0xFE, 0x90,       // 0152 CP 0x90
+    0x20, 0xFA,       // 0154 JR NZ,0x0150
+    0xAF,             // 0156 XOR A
+    0xE0, 0x40,       // 0157 LDH (LCDC),A
+    0x21, 0x10, 0x80, // 0159 LD HL,0x8010
+    0x0E, 0x10,       // 015C LD C,0x10
+    0x7D,             // 015E LD A,L
+    0x22,             // 015F LD (HL+),A
+    0x0D,             // 0160 DEC C
+    0x20, 0xFB,       // 0161 JR NZ,0x015E
+    0x21, 0x00, 0x98, // 0163 LD HL,0x9800
+    0x7D,             // 0166 LD A,L
+    0xE6, 0x21,       // 0167 AND 0x21
+    0x28, 0x07,       // 0169 JR Z,0x0172
+    0xFE, 0x21,       // 016B CP 0x21
+    0x3E, 0x01,       // 016D LD A,0x01
+    0x20, 0x01,       // 016F JR NZ,0x0172
+    0xAF,             // 0171 XOR A
+    0x22,             // 0172 LD (HL+),A
+    0x7C,             // 0173 LD A,H
+    0xFE, 0x9C,       // 0174 CP 0x9C
+    0x20, 0xEE,       // 0176 JR NZ,0x0166
+    0x3E, 0xE4,       // 0178 LD A,0xE4
+    0xE0, 0x47,       // 017A LDH (BGP),A
+    0x3E, 0x91,       // 017C LD A,0x91
+    0xE0, 0x40,       // 017E LDH (LCDC),A
+    0x18, 0xFE,       // 0180 JR 0x0180
+];
+
+#[test]
+fn draws_the_checked_in_reference() {
+    let out = out_dir();
+    let rom = out.join("checkerboard.gb");
+    let mut data = vec!(0; 0x8000);
+    data[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+    data[0x150..0x150 + CHECKERBOARD.len()].copy_from_slice(CHECKERBOARD);
+    fs::write(&rom, data).unwrap();
+    let reference = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("references").join("checkerboard.png");
+    assert!(matches(&rom, &reference, &out));
+}
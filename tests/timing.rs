@@ -0,0 +1,169 @@
+//! Instruction and memory access timing through the whole machine, measured
+//! with the timer the way Blargg's instr_timing and mem_timing do.
+//!
+//! Each measurement resets DIV with TIMA counting every 4 M-cycles, runs
+//! some code that ends up with a TIMA value in A, and does so four times
+//! with zero to three NOPs in front. A value sampled x M-cycles after the
+//! reset is floor((x + k) / 4) for some fixed k, so the four add up to
+//! x + k: exact M-cycles, with k cancelling out between measurements.
+
+extern crate gbm_rust;
+
+mod common;
+
+/// Where the measured code starts, without any NOPs in front.
+const START: u16 = 0x0150 + 18;
+
+/// Code to measure, given the address it starts at.
+type Code<'a> = &'a dyn Fn(u16) -> Vec<u8>;
+
+/// LDH A,(TIMA): reads TIMA on its third M-cycle.
+const READ_TIMA: [u8; 2] = [0xF0, 0x05];
+
+/// Runs `code` after the reset with HL and C pointing at `hl`, Z set and
+/// SP at 0xFFFE, returning A once it's done.
+fn run(hl: u16, nops: usize, code: Code) -> u8 {
+    let mut program = vec!(
+        0x3E, 0x05,                      // LD A,0x05
+        0xE0, 0x07,                      // LDH (TAC),A
+        0x21, hl as u8, (hl >> 8) as u8, // LD HL,hl
+        0x0E, hl as u8,                  // LD C,hl
+        0x31, 0xFE, 0xFF,                // LD SP,0xFFFE
+        0xAF,                            // XOR A
+        0xE0, 0x05,                      // LDH (TIMA),A
+        0xE0, 0x04,                      // LDH (DIV),A
+    );
+    program.resize(START as usize - 0x0150 + nops, 0x00);
+    let start = 0x0150 + program.len() as u16;
+    program.extend(code(start));
+    let end = 0x0150 + program.len() as u16;
+    program.extend(&[0x18, 0xFE]);
+
+    let mut gameboy = common::with_program(b"TIMING", &program);
+    for _ in 0..1000 {
+        if gameboy.cpu().registers().pc == end {
+            return gameboy.cpu().registers().a;
+        }
+        gameboy.step().unwrap();
+    }
+    panic!("{:02x?} never finished", code(start));
+}
+
+/// M-cycles from the reset to when the TIMA value in A was sampled, plus
+/// a constant.
+fn measure(hl: u16, code: Code) -> i64 {
+    // Each NOP pushes the sample back by one
+    (0..4).map(|nops| run(hl, nops, code) as i64 - nops as i64).sum()
+}
+
+/// M-cycles from the start of `code` to when the TIMA value in A was
+/// sampled, taking LDH A,(TIMA) right at the start as 3.
+fn sampled_at(hl: u16, code: Code) -> i64 {
+    measure(hl, code) - measure(hl, &|_| READ_TIMA.to_vec()) + 3
+}
+
+fn check(failures: &mut Vec<String>, what: &str, bytes: &[u8], actual: i64, expected: i64) {
+    if actual != expected {
+        failures.push(format!("{} ({:02x?}): {} instead of {}", what, bytes, actual, expected));
+    }
+}
+
+#[test]
+fn instructions_take_their_time() {
+    let lo = |addr: u16| addr as u8;
+    let hi = |addr: u16| (addr >> 8) as u8;
+    // Code, with branches going on to the next instruction, and M-cycles
+    let cases: &[(&str, Code, i64)] = &[
+        ("NOP", &|_| vec!(0x00), 1),
+        ("LD BC,d16", &|_| vec!(0x01, 0x34, 0x12), 3),
+        ("INC BC", &|_| vec!(0x03), 2),
+        ("INC (HL)", &|_| vec!(0x34), 3),
+        ("LD (HL),d8", &|_| vec!(0x36, 0x00), 3),
+        ("LD (a16),SP", &|_| vec!(0x08, 0x00, 0xC0), 5),
+        ("ADD HL,BC", &|_| vec!(0x09), 2),
+        ("PUSH BC", &|_| vec!(0xC5), 4),
+        ("POP BC", &|_| vec!(0xC1), 3),
+        ("ADD SP,e", &|_| vec!(0xE8, 0x00), 4),
+        ("LD HL,SP+e", &|_| vec!(0xF8, 0x00), 3),
+        ("LD SP,HL", &|_| vec!(0xF9), 2),
+        ("LD A,(a16)", &|_| vec!(0xFA, 0x00, 0xC0), 4),
+        ("JR e", &|_| vec!(0x18, 0x00), 3),
+        ("JR Z,e taken", &|_| vec!(0x28, 0x00), 3),
+        ("JR NZ,e not taken", &|_| vec!(0x20, 0x00), 2),
+        ("JP a16", &|at| vec!(0xC3, lo(at + 3), hi(at + 3)), 4),
+        ("JP Z,a16 taken", &|at| vec!(0xCA, lo(at + 3), hi(at + 3)), 4),
+        ("JP NZ,a16 not taken", &|at| vec!(0xC2, lo(at + 3), hi(at + 3)), 3),
+        ("JP HL", &|at| vec!(0x21, lo(at + 4), hi(at + 4), 0xE9), 3 + 1),
+        ("CALL a16", &|at| vec!(0xCD, lo(at + 3), hi(at + 3)), 6),
+        ("CALL Z,a16 taken", &|at| vec!(0xCC, lo(at + 3), hi(at + 3)), 6),
+        ("CALL NZ,a16 not taken", &|at| vec!(0xC4, lo(at + 3), hi(at + 3)), 3),
+        // Calls a RET that comes back to a JR over it
+        ("RET", &|at| vec!(0xCD, lo(at + 5), hi(at + 5), 0x18, 0x01, 0xC9), 6 + 4 + 3),
+        ("RETI", &|at| vec!(0xCD, lo(at + 5), hi(at + 5), 0x18, 0x01, 0xD9), 6 + 4 + 3),
+        ("RET Z taken", &|at| vec!(0xCD, lo(at + 5), hi(at + 5), 0x18, 0x01, 0xC8), 6 + 5 + 3),
+        ("RET NZ not taken", &|_| vec!(0xC0), 2),
+        ("DI", &|_| vec!(0xF3), 1),
+        ("RLC B", &|_| vec!(0xCB, 0x00), 2),
+        ("BIT 0,(HL)", &|_| vec!(0xCB, 0x46), 3),
+        ("SET 0,(HL)", &|_| vec!(0xCB, 0xC6), 4),
+    ];
+    let mut failures = vec!();
+    for &(name, code, cycles) in cases {
+        let timed = |at: u16| {
+            let mut bytes = code(at);
+            bytes.extend(&READ_TIMA);
+            bytes
+        };
+        // Without the instruction, TIMA is sampled 3 M-cycles in
+        check(&mut failures, name, &code(START), sampled_at(0xC000, &timed) - 3, cycles);
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn reads_happen_on_their_cycle() {
+    let cases: &[(&str, &[u8], i64)] = &[
+        ("LD A,(HL)", &[0x7E], 2),
+        ("LD A,(HL+)", &[0x2A], 2),
+        ("LD A,(HL-)", &[0x3A], 2),
+        ("LD A,(C)", &[0xF2], 2),
+        ("LDH A,(a8)", &[0xF0, 0x05], 3),
+        ("LD A,(a16)", &[0xFA, 0x05, 0xFF], 4),
+        // Read-modify-write: A gets the written value plus what TIMA
+        // counted after the write. For a read on r, a write on w and a
+        // length of n that adds up to r - w + n + 3, plus four times
+        // what the instruction adds
+        ("INC (HL)", &[0x34, 0xF0, 0x05], 2 - 3 + 3 + 3 + 4),
+        ("DEC (HL)", &[0x35, 0xF0, 0x05], 2 - 3 + 3 + 3 - 4),
+        ("RES 7,(HL)", &[0xCB, 0xBE, 0xF0, 0x05], 3 - 4 + 4 + 3),
+    ];
+    let mut failures = vec!();
+    for &(name, code, cycle) in cases {
+        check(&mut failures, name, code, sampled_at(0xFF05, &|_| code.to_vec()), cycle);
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
+
+#[test]
+fn writes_happen_on_their_cycle() {
+    // A write to DIV resets the count, so everything TIMA counted before
+    // it shows when it happened
+    let reference = measure(0xFF04, &|_| vec!(0x77, 0xF0, 0x05));
+    let cases: &[(&str, &[u8], i64)] = &[
+        ("LD (HL),A", &[0x77], 2),
+        ("LD (HL+),A", &[0x22], 2),
+        ("LD (C),A", &[0xE2], 2),
+        ("LDH (a8),A", &[0xE0, 0x04], 3),
+        ("LD (a16),A", &[0xEA, 0x04, 0xFF], 4),
+        ("INC (HL)", &[0x34], 3),
+        ("SET 0,(HL)", &[0xCB, 0xC6], 4),
+    ];
+    let mut failures = vec!();
+    for &(name, code, cycle) in cases {
+        let mut timed = code.to_vec();
+        timed.extend(&READ_TIMA);
+        let written = measure(0xFF04, &|_| timed.clone()) - reference + 2;
+        check(&mut failures, name, code, written, cycle);
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
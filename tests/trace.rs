@@ -0,0 +1,113 @@
+//! Execution traces in the Gameboy Doctor format.
+
+extern crate gbm_rust;
+
+mod common;
+
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use gbm_rust::GameBoy;
+use gbm_rust::cpu::trace::{self, Trace, Filter};
+use gbm_rust::mmu::Bus;
+use common::{gameboy, with_program};
+
+/// Collects the trace where the test can get at it.
+#[derive(Clone, Default)]
+struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Buffer {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn trace(gameboy: &mut GameBoy, filter: Filter, steps: usize) -> Vec<String> {
+    let buffer = Buffer::default();
+    gameboy.cpu_mut().set_trace(Trace::new(buffer.clone(), filter));
+    for _ in 0..steps {
+        gameboy.step().unwrap();
+    }
+    gameboy.cpu_mut().take_trace().unwrap().finish().unwrap();
+    let text = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+    text.lines().map(|line| line.to_string()).collect()
+}
+
+#[test]
+fn logs_state_before_each_instruction() {
+    let lines = trace(&mut gameboy(b"TRACE"), Filter::default(), 4);
+    assert_eq!(lines, vec!(
+        "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,50,01",
+        "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:C3,50,01,00",
+        "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0150 PCMEM:3E,05,E0,07",
+        "A:05 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0152 PCMEM:E0,07,3E,80",
+    ));
+}
+
+#[test]
+fn filters_by_pc_and_bank() {
+    let filter = Filter { pc: Some((0x0150, 0x0153)), bank: None };
+    let lines = trace(&mut gameboy(b"TRACE"), filter, 10);
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains("PC:0150") && lines[1].contains("PC:0152"), "{:?}", lines);
+
+    // Nothing runs from bank 1 in the test program
+    let filter = Filter { pc: None, bank: Some(1) };
+    assert!(trace(&mut gameboy(b"TRACE"), filter, 10).is_empty());
+    let filter = Filter { pc: None, bank: Some(0) };
+    assert_eq!(trace(&mut gameboy(b"TRACE"), filter, 10).len(), 10);
+}
+
+#[test]
+fn does_not_change_execution() {
+    let mut traced = gameboy(b"TRACE");
+    trace(&mut traced, Filter::default(), 5000);
+    let mut plain = gameboy(b"TRACE");
+    for _ in 0..5000 {
+        plain.step().unwrap();
+    }
+    assert_eq!(traced.save_state(), plain.save_state());
+}
+
+/// Waits for LY to reach 0x90, then spins.
+const WAIT_FOR_VBLANK: &[u8] = &[
+    0xF0, 0x44, // 0150 LDH A,(0x44)
+    0xFE, 0x90, // 0152 CP 0x90
+    0x20, 0xFA, // 0154 JR NZ,0x0150
+    0x3C,       // 0156 INC A
+    0x18, 0xFE, // 0157 JR 0x0157
+];
+
+#[test]
+fn reads_ly_like_gameboy_doctor() {
+    let mut gameboy = with_program(b"DOCTOR", WAIT_FOR_VBLANK);
+    trace::stub_ly(gameboy.mmu_mut());
+    let lines = trace(&mut gameboy, Filter::default(), 8);
+    assert_eq!(lines, vec!(
+        "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,50,01",
+        "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:C3,50,01,00",
+        "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0150 PCMEM:F0,44,FE,90",
+        "A:90 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0152 PCMEM:FE,90,20,FA",
+        "A:90 F:C0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0154 PCMEM:20,FA,3C,18",
+        "A:90 F:C0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0156 PCMEM:3C,18,FE,00",
+        "A:91 F:00 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0157 PCMEM:18,FE,00,00",
+        "A:91 F:00 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0157 PCMEM:18,FE,00,00",
+    ));
+
+    // The display itself keeps going
+    let line = gameboy.mmu().peek(0xFF44);
+    common::run(&mut gameboy, 1);
+    assert_eq!(gameboy.frames(), 1);
+    assert_ne!(gameboy.mmu().peek(0xFF44), line);
+    assert_eq!(gameboy.mmu().read(0xFF44), trace::DOCTOR_LY);
+
+    // Without the stub the first read sees the real line
+    let lines = trace(&mut with_program(b"DOCTOR", WAIT_FOR_VBLANK), Filter::default(), 5);
+    assert!(lines[3].starts_with("A:00 "), "{:?}", lines);
+    assert!(lines[4].contains("PC:0154"), "{:?}", lines);
+}